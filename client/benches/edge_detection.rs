@@ -0,0 +1,67 @@
+//! Per-frame cost of edge detection at common camera resolutions, measured
+//! through the `EdgeDetector` pipeline: submitting a frame and waiting for
+//! its edges.
+//!
+//! Run with `cargo bench -p client`.
+
+use client::edge_detector::{EdgeDetector, EdgeSettings};
+use client::image_frame::ImageFrame;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use std::time::Duration;
+
+/// Resolutions cameras are commonly run at
+const RESOLUTIONS: [(usize, usize); 4] = [(320, 240), (640, 480), (1280, 720), (1920, 1080)];
+
+/// Generous, so a slow machine measures its speed instead of timeouts
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Image with edges in every direction: a bright disc on a diagonal
+/// gradient, crossed by stripes
+fn test_image(w: usize, h: usize) -> ImageFrame {
+    let mut frame = ImageFrame::new(w, h, 3).unwrap();
+    let (cx, cy, r) = (w as f32 / 2.0, h as f32 / 2.0, h as f32 / 3.0);
+
+    for y in 0..h {
+        for x in 0..w {
+            let mut v = ((x + y) * 255 / (w + h)) as u8;
+            if (x as f32 - cx).hypot(y as f32 - cy) < r {
+                v = 240;
+            }
+            if (x / 40) % 4 == 0 {
+                v /= 3;
+            }
+
+            let i = (y * w + x) * 3;
+            frame.buffer_mut()[i..i + 3].copy_from_slice(&[v, v / 2, 255 - v]);
+        }
+    }
+
+    frame
+}
+
+fn edge_detection(c: &mut Criterion) {
+    let mut group = c.benchmark_group("edge_detection");
+
+    for (w, h) in RESOLUTIONS {
+        let frame = test_image(w, h);
+        let mut detector = EdgeDetector::new(w, h, EdgeSettings::default());
+        detector.start().unwrap();
+
+        group.throughput(Throughput::Elements((w * h) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{}x{}", w, h)),
+            &frame,
+            |b, frame| {
+                b.iter(|| {
+                    let seq = detector.submit_frame(frame).unwrap().unwrap();
+                    detector.wait_for_edges(seq, TIMEOUT).unwrap().unwrap()
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, edge_detection);
+criterion_main!(benches);
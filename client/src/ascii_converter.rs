@@ -1,16 +1,28 @@
-use crate::edge_detector::EdgeDetector;
+use crate::charset::Charset;
+use crate::dither::Dithering;
+use crate::edge_detector::{EdgeDetector, EdgeDirection, EdgeInfo, EdgeSettings};
+use crate::exposure::{AutoExposure, Equalization, ToneMap};
 use crate::image_frame::ImageFrame;
-use common::ascii_frame::AsciiFrame;
+use crate::luminance::LuminanceModel;
+pub use crate::luminance::{B_LUMINANCE, G_LUMINANCE, R_LUMINANCE};
+use crate::resample::{Framing, Placement, Region, Resampling};
+use crate::temporal::TemporalFilter;
+use crate::video_config::VideoConfig;
+use common::ascii_frame::{AsciiFrame, CellColor, RenderMode, Rgb};
 use std::error::Error;
+use std::time::{Duration, Instant};
 
-/// The coefficients below are derived from Rec. ITU-R BT.601-7.
-/// In the specification, these luminance coefficients represent
-/// how much they influence / contribute to the human eye's
-/// perception of brightness.
+/// Longest wait for the edges of a frame, past which it is drawn without
+/// edges
+const EDGE_TIMEOUT: Duration = Duration::from_millis(100);
 
-pub const R_LUMINANCE: f32 = 0.2989;
-pub const G_LUMINANCE: f32 = 0.5870;
-pub const B_LUMINANCE: f32 = 0.1140;
+/// Auto-exposure measures every `EXPOSURE_SAMPLE_STEP`th pixel across and
+/// down a frame
+const EXPOSURE_SAMPLE_STEP: usize = 4;
+
+/// Share of a character's shorter side an edge has to run across before the
+/// character is drawn at the edge's full strength
+const MIN_EDGE_SPAN: f32 = 0.5;
 
 /// Intermediary translator to transform an `ImageFrame` into an `AsciiFrame`
 pub struct AsciiConverter {
@@ -34,7 +46,8 @@ pub struct AsciiConverter {
     ascii_forward: Vec<char>,
     /// Characters for back edges in `AsciiFrame` representation
     ascii_back: Vec<char>,
-    /// Minimum gradient magnitude for edge detection
+    /// Minimum edge strength behind a character for it to be drawn as an
+    /// edge, the edge detector's low threshold (see `sample_edge`)
     edge_threshold: f32,
     /// Adjustment factor for contrast.
     /// Values < 1.0 reduce contrast, values > 1.0 increase contrast
@@ -42,6 +55,28 @@ pub struct AsciiConverter {
     /// Adjustment factor for brightness.
     /// values > 0 increase brightness, values < 0 brightness
     brightness: f32,
+    /// How the intensity of a color is computed
+    luminance: LuminanceModel,
+    /// How intensities are spread over the range before anything else
+    equalization: Equalization,
+    /// Equalized intensities of the frame being converted
+    tone_map: ToneMap,
+    /// Adapts `contrast` and `brightness` to the scene, if enabled
+    auto_exposure: Option<AutoExposure>,
+    /// Whether characters are colored. Frames without colors carry no
+    /// color plane, so are smaller to send
+    colors: bool,
+    /// How intensities are spread over `ascii_intensity`, and over the dots
+    /// of braille patterns
+    dithering: Dithering,
+    /// How the pixels (and edges) behind a character are combined
+    resampling: Resampling,
+    /// Keeps characters from flickering between frames
+    temporal: TemporalFilter,
+    /// Height of a character cell divided by its width
+    cell_aspect: f32,
+    /// How the image is fit onto the frame when their aspect ratios differ
+    framing: Framing,
 }
 
 impl AsciiConverter {
@@ -52,108 +87,386 @@ impl AsciiConverter {
     pub const DEFAULT_ASCII_BACK: &'static str = "\\╲⟍";
     pub const DEFAULT_CONTRAST: f32 = 1.5;
     pub const DEFAULT_BRIGHTNESS: f32 = 0.0;
+    /// Intensity above which a braille dot is set
+    pub const BRAILLE_THRESHOLD: f32 = 127.5;
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        ascii_intensity: Vec<char>,
-        ascii_horizontal: Vec<char>,
-        ascii_vertical: Vec<char>,
-        ascii_forward: Vec<char>,
-        ascii_back: Vec<char>,
+        charset: Charset,
         w: usize,
         h: usize,
-        edge_threshold: f32,
+        edge_settings: EdgeSettings,
         contrast: f32,
         brightness: f32,
+        luminance: LuminanceModel,
+        equalization: Equalization,
+        exposure_target: Option<f32>,
+        colors: bool,
+        dithering: Dithering,
+        resampling: Resampling,
+        temporal_strength: f32,
+        cell_aspect: f32,
+        framing: Framing,
     ) -> Result<Self, Box<dyn Error>> {
-        let edge_detector = EdgeDetector::new(w, h, edge_threshold);
+        charset.validate()?;
+        let mut edge_detector = EdgeDetector::new(w, h, edge_settings);
 
-        edge_detector.start(w, h)?;
+        edge_detector.start()?;
 
         Ok(Self {
             edge_detector,
-            ascii_intensity,
-            ascii_horizontal,
-            ascii_vertical,
-            ascii_forward,
-            ascii_back,
-            edge_threshold,
+            ascii_intensity: charset.intensity,
+            ascii_horizontal: charset.horizontal,
+            ascii_vertical: charset.vertical,
+            ascii_forward: charset.forward,
+            ascii_back: charset.back,
+            edge_threshold: edge_settings
+                .low_threshold
+                .min(edge_settings.high_threshold),
             contrast,
             brightness,
+            luminance,
+            equalization,
+            tone_map: ToneMap::default(),
+            auto_exposure: exposure_target
+                .map(|target| AutoExposure::new(target, contrast, brightness)),
+            colors,
+            dithering,
+            resampling,
+            temporal: TemporalFilter::new(temporal_strength),
+            cell_aspect,
+            framing,
         })
     }
 
+    /// Converter for 640x480 images with the default characters and settings
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Result<Self, Box<dyn Error>> {
         Self::new(
-            Self::DEFAULT_ASCII_INTENSITY.chars().collect(),
-            Self::DEFAULT_ASCII_HORIZONTAL.chars().collect(),
-            Self::DEFAULT_ASCII_VERTICAL.chars().collect(),
-            Self::DEFAULT_ASCII_FORWARD.chars().collect(),
-            Self::DEFAULT_ASCII_BACK.chars().collect(),
+            Charset::default(),
             640,
             480,
-            EdgeDetector::DEFAULT_EDGE_THRESHOLD,
+            EdgeSettings::default(),
             Self::DEFAULT_CONTRAST,
             Self::DEFAULT_BRIGHTNESS,
+            LuminanceModel::default(),
+            Equalization::default(),
+            None,
+            true,
+            Dithering::default(),
+            Resampling::default(),
+            TemporalFilter::DEFAULT_STRENGTH,
+            VideoConfig::DEFAULT_CELL_ASPECT,
+            Framing::default(),
         )
     }
 
+    /// Convert an `ImageFrame` into `a_frame`, the way its `RenderMode` asks
+    /// for. See `convert_ascii`, `convert_half_block` and `convert_braille`
+    ///
+    /// Colors are equalized as `equalization` says, then adjusted with the
+    /// contrast and brightness, which auto-exposure first moves toward the
+    /// values this frame asks for
+    pub fn convert(
+        &mut self,
+        i_frame: &ImageFrame,
+        a_frame: &mut AsciiFrame,
+    ) -> Result<(), Box<dyn Error>> {
+        self.tone_map
+            .update(self.equalization, self.luminance, i_frame);
+        if let Some(auto_exposure) = &mut self.auto_exposure {
+            let statistics = self.tone_map.statistics(i_frame, EXPOSURE_SAMPLE_STEP);
+            auto_exposure.update(statistics, Instant::now());
+            self.contrast = auto_exposure.contrast;
+            self.brightness = auto_exposure.brightness;
+        }
+
+        match a_frame.mode {
+            RenderMode::Ascii => self.convert_ascii(i_frame, a_frame),
+            RenderMode::HalfBlock => {
+                self.convert_half_block(i_frame, a_frame);
+                Ok(())
+            }
+            RenderMode::Braille => {
+                self.convert_braille(i_frame, a_frame);
+                Ok(())
+            }
+        }
+    }
+
     /// Convert an `ImageFrame` to an ASCII art representation with edges
-    /// - Strong edges (based on `edge_threshold`) are represented with
-    ///   separate characters to reflect the angle of an edge
+    /// - Edges kept by the edge detector that cross enough of a character
+    ///   (see `sample_edge`) are represented with separate characters to
+    ///   reflect the angle of an edge
     /// - All other regions are represented with intensity-based (grayscale)
     ///   ASCII characters
     ///
+    /// Every character is colored with the color of the source pixels it
+    /// covers.
+    ///
     /// The function also handles scaling from the original `ImageFrame`'s
-    /// dimensions to the target `AsciiFrame`'s dimensions
-    pub fn convert(
-        &self,
+    /// dimensions to the target `AsciiFrame`'s dimensions, combining the
+    /// pixels and edges behind every character as `resampling` says.
+    /// Cells outside of the picture (see `framing`) are left blank.
+    ///
+    /// Colors are sampled while the edge detector works on the frame. If
+    /// its edges take longer than `EDGE_TIMEOUT`, or the edge detector is
+    /// still busy with an earlier frame, the frame is drawn without edges.
+    ///
+    /// Intensities and edge magnitudes go through the `temporal` filter,
+    /// which also keeps cells on their last ramp character while it fits
+    fn convert_ascii(
+        &mut self,
         i_frame: &ImageFrame,
         a_frame: &mut AsciiFrame,
     ) -> Result<(), Box<dyn Error>> {
-        // submit the original image to the edge detector
-        self.edge_detector.submit_frame(i_frame)?;
+        // submit the original image to the edge detector, which skips it
+        // while it's still busy with earlier frames
+        let seq = self.edge_detector.submit_frame(i_frame)?;
 
-        // scaling factors to map the ASCII frame's dimension
-        // to the original image's dimension
-        let scale_x = i_frame.w as f32 / a_frame.w as f32;
-        let scale_y = i_frame.h as f32 / a_frame.h as f32;
+        // the edge detector works on the image as it is, so regions of the
+        // image are regions of its edges too
+        let placement = self.placement(i_frame, a_frame);
+        let mut cells = Vec::with_capacity(a_frame.w * a_frame.h);
+        for y in 0..a_frame.h {
+            for x in 0..a_frame.w {
+                let cell = placement.region(x, y).map(|region| {
+                    // modify RGB w/ given brightness & contrast values
+                    let rgb = self.sample_rgb(i_frame, region);
+                    (region, rgb)
+                });
+                if self.colors {
+                    let color = CellColor {
+                        fg: cell.map(|(_, rgb)| Rgb::from(rgb)),
+                        bg: None,
+                    };
+                    a_frame.set_color(x, y, color);
+                }
+                cells.push(cell);
+            }
+        }
 
         // retrieve processed edge info
-        let edge_info = self.edge_detector.get_edge_info()?;
+        let edge_info = match seq {
+            Some(seq) => self.edge_detector.wait_for_edges(seq, EDGE_TIMEOUT)?,
+            None => None,
+        }
+        .unwrap_or_else(|| EdgeInfo::none(i_frame.w, i_frame.h));
+
+        // edge character or intensity of every cell, the intensities are
+        // dithered onto `ascii_intensity` once all of them are known
+        let filtering = self.temporal.is_enabled();
+        self.temporal.begin(a_frame.w, a_frame.h, RenderMode::Ascii);
+        let mut edges = vec![None; cells.len()];
+        let mut intensities = vec![0.0; cells.len()];
+        for (i, cell) in cells.into_iter().enumerate() {
+            let Some((region, rgb)) = cell else {
+                // keep dithering from scattering dots into the bars
+                edges[i] = Some(' ');
+                continue;
+            };
+
+            let (mut magnitude, angle) = self.sample_edge(&edge_info, region);
+            let mut intensity = self.luminance.intensity(rgb);
+            if filtering {
+                magnitude = self.temporal.magnitude(i, magnitude);
+                intensity = self.temporal.intensity(i, intensity);
+            }
+
+            // if an edge is at least as strong as the threshold, assign
+            // edge character instead of regular character
+            if magnitude > 0.0 && magnitude >= self.edge_threshold {
+                edges[i] = Some(self.angle_to_edge(angle, magnitude));
+            } else {
+                // no significant edge, map by intensity
+                intensities[i] = intensity;
+            }
+        }
+
+        let ramp_len = self.ascii_intensity.len();
+        let mut levels: Vec<Option<usize>> = self
+            .dithering
+            .quantize(&intensities, a_frame.w, ramp_len)
+            .into_iter()
+            .zip(&edges)
+            .map(|(level, edge)| edge.is_none().then_some(level))
+            .collect();
+        if filtering {
+            self.temporal
+                .hold_levels(&intensities, &mut levels, |level| {
+                    self.dithering.level_range(level, ramp_len)
+                });
+        }
+        for (i, (edge, level)) in edges.into_iter().zip(levels).enumerate() {
+            let c = level.map_or(edge.unwrap_or(' '), |level| self.ascii_intensity[level]);
+            a_frame.set_char(i % a_frame.w, i / a_frame.w, c);
+        }
+
+        Ok(())
+    }
+
+    /// Strength and angle of the edges behind `region`, from the pixels the
+    /// edge detector kept (`EdgeInfo::linked`) only. The strength is their
+    /// mean magnitude, scaled down where they cover less than a line across
+    /// `MIN_EDGE_SPAN` of the region, so it reaches `edge_threshold` for
+    /// weak edges hysteresis kept but not for edges that merely clip the
+    /// region. Angles are combined as doubled-angle vectors weighted by
+    /// magnitude, so gradients pointing in opposite directions (the same
+    /// edge) reinforce rather than cancel
+    fn sample_edge(&self, edge_info: &EdgeInfo, region: Region) -> (f32, f32) {
+        let [coverage, magnitude, cos, sin] =
+            self.resampling
+                .sample(edge_info.w, edge_info.h, region, |x, y| {
+                    let i = y * edge_info.w + x;
+                    if !edge_info.linked.get(i).copied().unwrap_or(false) {
+                        return [0.0; 4];
+                    }
+                    let m = edge_info.magnitude.get(i).copied().unwrap_or(0.0);
+                    let a = edge_info.angle.get(i).copied().unwrap_or(0.0) * 2.0;
+                    [1.0, m, m * a.cos(), m * a.sin()]
+                });
+        if coverage <= 0.0 {
+            return (0.0, 0.0);
+        }
+
+        // a line across the shorter side covers 1 / longer side of the region
+        let span = MIN_EDGE_SPAN / region.w.max(region.h).max(1.0);
+        let strength = magnitude / coverage * (coverage / span).min(1.0);
+        (strength, sin.atan2(cos) / 2.0)
+    }
+
+    /// Convert an `ImageFrame` to half blocks, splitting every character
+    /// cell into an upper and lower "pixel". Terminal cells are about twice
+    /// as tall as they are wide, so this gives square pixels at twice the
+    /// vertical resolution of `convert_ascii`, at the cost of showing no
+    /// edges and needing a terminal with colors. Every channel of every
+    /// pixel goes through the `temporal` filter
+    fn convert_half_block(&mut self, i_frame: &ImageFrame, a_frame: &mut AsciiFrame) {
+        let placement = self.placement(i_frame, a_frame);
+        // filtered as a grid of 3 channels per pixel, 2 pixels per cell
+        let filtering = self.temporal.is_enabled();
+        if filtering {
+            self.temporal
+                .begin(a_frame.w * 3, a_frame.h * 2, RenderMode::HalfBlock);
+        }
+
+        for y in 0..a_frame.h {
+            for x in 0..a_frame.w {
+                let Some(region) = placement.region(x, y) else {
+                    a_frame.set_char(x, y, ' ');
+                    a_frame.set_color(x, y, CellColor::default());
+                    continue;
+                };
+                let mut half = |row: usize| {
+                    let region = region.cell(0, row, 1, 2);
+                    let (r, g, b) = self.sample_rgb(i_frame, region);
+                    if !filtering {
+                        return Rgb::from((r, g, b));
+                    }
+                    let first = ((y * 2 + row) * a_frame.w + x) * 3;
+                    let [r, g, b] = [r, g, b].map(|c| c as f32);
+                    let [r, g, b] = [(0, r), (1, g), (2, b)]
+                        .map(|(i, c)| self.temporal.intensity(first + i, c).round() as u8);
+                    Rgb::from((r, g, b))
+                };
+                let color = CellColor {
+                    fg: Some(half(0)),
+                    bg: Some(half(1)),
+                };
+
+                a_frame.set_char(x, y, RenderMode::HALF_BLOCK);
+                a_frame.set_color(x, y, color);
+            }
+        }
+    }
+
+    /// Convert an `ImageFrame` to braille patterns, splitting every
+    /// character cell into a 2x4 grid of dots. A dot is set if its region
+    /// of the image is brighter than `BRAILLE_THRESHOLD`, or as the
+    /// `dithering` decides, giving 8 times the detail of `convert_ascii` on
+    /// terminals without colors. Characters are colored with the color of
+    /// the cell. Dots go through the `temporal` filter like the cells of
+    /// `convert_ascii` do
+    fn convert_braille(&mut self, i_frame: &ImageFrame, a_frame: &mut AsciiFrame) {
+        // intensity of every dot, as a grid twice as wide and four times
+        // as tall as the frame
+        let (dots_w, dots_h) = (a_frame.w * 2, a_frame.h * 4);
+        let placement = self.placement(i_frame, a_frame);
+        let mut intensities: Vec<f32> = (0..dots_w * dots_h)
+            .map(|i| {
+                let (dot_x, dot_y) = (i % dots_w, i / dots_w);
+                placement
+                    .region(dot_x / 2, dot_y / 4)
+                    .map_or(0.0, |region| {
+                        let region = region.cell(dot_x % 2, dot_y % 4, 2, 4);
+                        self.luminance.intensity(self.sample_rgb(i_frame, region))
+                    })
+            })
+            .collect();
+
+        let filtering = self.temporal.is_enabled();
+        if filtering {
+            self.temporal.begin(dots_w, dots_h, RenderMode::Braille);
+            for (i, intensity) in intensities.iter_mut().enumerate() {
+                *intensity = self.temporal.intensity(i, *intensity);
+            }
+        }
+        let mut set: Vec<Option<usize>> = self
+            .dithering
+            .quantize(&intensities, dots_w, 2)
+            .into_iter()
+            .map(Some)
+            .collect();
+        if filtering {
+            self.temporal.hold_levels(&intensities, &mut set, |level| {
+                self.dithering.level_range(level, 2)
+            });
+        }
 
         for y in 0..a_frame.h {
             for x in 0..a_frame.w {
-                let i_x = (x as f32 * scale_x) as usize;
-                let i_y = (y as f32 * scale_y) as usize;
-                let e_i = i_y.min(edge_info.h - 1) * edge_info.w + i_x.min(edge_info.w - 1);
-
-                // if an edge's magnitude is greater than the threshold,
-                // assign edge character instead of regular character
-                if e_i < edge_info.magnitude.len() && edge_info.magnitude[e_i] > self.edge_threshold
-                {
-                    let c = self.angle_to_edge(edge_info.angle[e_i], edge_info.magnitude[e_i]);
-                    a_frame.set_char(x, y, c);
-                } else {
-                    // no significant edge, retrieve RGB values from
-                    // scaled pixel destination in image frame and
-                    // map by intensity
-                    if let Some(rgb) = i_frame.get_pixel(i_x, i_y) {
-                        // modify RGB w/ given brightness & contrast values
-                        let rgb_adj = self.adjust_pixel(rgb);
-                        let intensity = ImageFrame::calculate_intensity_u8(rgb_adj);
-
-                        let char_i =
-                            (intensity as f32 / 255.0 * self.ascii_intensity.len() as f32) as usize;
-                        // bounds check (e.g. floating point rounding error)
-                        let char_i = char_i.min(self.ascii_intensity.len() - 1);
-
-                        a_frame.set_char(x, y, self.ascii_intensity[char_i]);
+                let Some(region) = placement.region(x, y) else {
+                    a_frame.set_char(x, y, ' ');
+                    if self.colors {
+                        a_frame.set_color(x, y, CellColor::default());
                     }
+                    continue;
+                };
+                let mut dots = 0u8;
+                for dot in 0..8 {
+                    if set[(y * 4 + dot / 2) * dots_w + x * 2 + dot % 2] == Some(1) {
+                        dots |= 1 << dot;
+                    }
+                }
+                a_frame.set_char(x, y, RenderMode::braille(dots));
+
+                if self.colors {
+                    let color = CellColor {
+                        fg: Some(Rgb::from(self.sample_rgb(i_frame, region))),
+                        bg: None,
+                    };
+                    a_frame.set_color(x, y, color);
                 }
             }
         }
+    }
 
-        Ok(())
+    /// Where `i_frame` ends up on `a_frame`, keeping its aspect ratio as
+    /// `framing` says. Half blocks and braille dots split their cell evenly,
+    /// so only the shape of the cells matters
+    fn placement(&self, i_frame: &ImageFrame, a_frame: &AsciiFrame) -> Placement {
+        self.framing
+            .place(i_frame.w, i_frame.h, a_frame.w, a_frame.h, self.cell_aspect)
+    }
+
+    /// Color behind `region`, equalized by `tone_map` and adjusted by
+    /// `adjust_pixel`
+    fn sample_rgb(&self, i_frame: &ImageFrame, region: Region) -> (u8, u8, u8) {
+        let rgb = self.resampling.sample_rgb(i_frame, region);
+        let rgb = self
+            .tone_map
+            .apply(rgb, region.x + region.w / 2.0, region.y + region.h / 2.0);
+        self.adjust_pixel(rgb)
     }
 
     /// Alter the color channels of an RGB pixel according to the specified
@@ -166,30 +479,25 @@ impl AsciiConverter {
             v = (v - 0.5) * self.contrast + 0.5;
             v += self.brightness;
             // floor of 0.0 and ceiling of 1.0 (prevent overflow)
-            v = v.max(0.0).min(1.0);
+            v = v.clamp(0.0, 1.0);
             (v * 255.0) as u8
         };
 
         (apply(r), apply(g), apply(b))
     }
 
-    /// Normalizes an angle to 0-180 degrees, then maps the angle to an
-    /// angle character based on magnitude and angle degree
+    /// Maps an edge to an angle character, picking the family of characters
+    /// by the edge's `EdgeDirection` and the character by its magnitude
     fn angle_to_edge(&self, angle: f32, magnitude: f32) -> char {
-        // normalizing to 0-180
-        let angle_d = ((angle.to_degrees() % 180.0) + 180.0) % 180.0;
-
-        let char_i = ((magnitude / 255.0) * (self.ascii_horizontal.len() as f32))
-            .min((self.ascii_horizontal.len() - 1) as f32) as usize;
-
-        if (angle_d >= 0.0 && angle_d < 22.5) || (angle_d >= 157.5 && angle_d < 180.0) {
-            self.ascii_horizontal[char_i.min(self.ascii_horizontal.len() - 1)]
-        } else if (angle_d >= 22.5) && (angle_d < 67.5) {
-            self.ascii_forward[char_i.min(self.ascii_forward.len() - 1)]
-        } else if (angle_d >= 67.5) && (angle_d < 112.5) {
-            self.ascii_vertical[char_i.min(self.ascii_vertical.len() - 1)]
-        } else {
-            self.ascii_back[char_i.min(self.ascii_back.len() - 1)]
-        }
+        let family = match EdgeDirection::of(angle) {
+            EdgeDirection::Horizontal => &self.ascii_horizontal,
+            EdgeDirection::Forward => &self.ascii_forward,
+            EdgeDirection::Vertical => &self.ascii_vertical,
+            EdgeDirection::Back => &self.ascii_back,
+        };
+
+        let char_i =
+            ((magnitude / 255.0) * (family.len() as f32)).min((family.len() - 1) as f32) as usize;
+        family[char_i]
     }
 }
@@ -1,27 +1,72 @@
-use common::ascii_frame::AsciiFrame;
+use crate::color_depth::{ColorDepth, Pen};
+use crate::luminance::LuminanceModel;
+use common::ascii_frame::{AsciiFrame, CellColor, RenderMode};
+use common::datagram::{FrameHeader, MAX_REORDER};
+use common::delta::DeltaDecoder;
+use common::fragment::Reassembler;
+use common::protocol::{ParticipantId, decode_relayed};
+use std::collections::HashMap;
 use std::error::Error;
 use std::io;
 use std::io::Write;
+use std::time::{Duration, Instant};
+use terminal_size::{Height, Width, terminal_size};
+
+/// Keyframes are asked for at most this often from the same sender, since
+/// several deltas usually fail before the requested keyframe arrives
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(250);
 
 /// Outputs ASCII frame data to `stdout`
 pub struct AsciiRenderer {
     /// used to reduce terminal flickering and
     /// (to later be used) for changing window sizes
     prev_frame: Vec<char>,
+    /// colors of the characters in `prev_frame`, as written to the terminal
+    prev_colors: Vec<Pen>,
+    /// how many colors the terminal can show
+    color_depth: ColorDepth,
+    /// how intensities are computed for terminals without colors
+    luminance: LuminanceModel,
+    /// intensity ramp of the charset in use, from dark to bright, that
+    /// half blocks are swapped for on terminals without colors
+    ramp: Vec<char>,
     /// width of previous `AsciiFrame`
     prev_w: usize,
     /// height of previous `AsciiFrame`
     prev_h: usize,
+    /// header of the newest frame received from each sender
+    newest: HashMap<ParticipantId, FrameHeader>,
+    /// puts frames split across several datagrams back together
+    reassembler: Reassembler,
+    /// applies delta frames to their keyframes
+    decoder: DeltaDecoder,
+    /// when a keyframe was last asked for from each sender
+    keyframe_requested_at: HashMap<ParticipantId, Instant>,
+    /// senders that should be asked for a keyframe
+    pending_keyframe_requests: Vec<ParticipantId>,
 }
 
 impl AsciiRenderer {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
+    pub fn new(
+        color_depth: ColorDepth,
+        luminance: LuminanceModel,
+        ramp: Vec<char>,
+    ) -> Result<Self, Box<dyn Error>> {
         Self::clear_screen()?;
 
         Ok(AsciiRenderer {
             prev_frame: Vec::new(),
+            prev_colors: Vec::new(),
+            color_depth,
+            luminance,
+            ramp,
             prev_w: 0,
             prev_h: 0,
+            newest: HashMap::new(),
+            reassembler: Reassembler::default(),
+            decoder: DeltaDecoder::default(),
+            keyframe_requested_at: HashMap::new(),
+            pending_keyframe_requests: Vec::new(),
         })
     }
 
@@ -34,9 +79,74 @@ impl AsciiRenderer {
         Ok(())
     }
 
+    /// Forget what is on the screen, so the next `render` clears it and
+    /// draws the whole frame again (e.g. after the terminal echoed input)
+    pub fn invalidate(&mut self) {
+        self.prev_frame.clear();
+    }
+
+    /// Size of the terminal in characters, or `fallback` if `stdout` is not
+    /// a terminal. The last row is left free, so printing to it never
+    /// scrolls the screen.
+    pub fn screen_size(fallback: (usize, usize)) -> (usize, usize) {
+        match terminal_size() {
+            Some((Width(w), Height(h))) if w > 0 && h > 1 => (w as usize, h as usize - 1),
+            _ => fallback,
+        }
+    }
+
+    /// Height of a character cell divided by its width, worked out from the
+    /// terminal's size in pixels. `None` if the terminal doesn't report one
+    pub fn cell_aspect() -> Option<f32> {
+        #[cfg(unix)]
+        if let Ok(size) = rustix::termios::tcgetwinsize(std::io::stdout())
+            && size.ws_col > 0
+            && size.ws_row > 0
+            && size.ws_xpixel > 0
+            && size.ws_ypixel > 0
+        {
+            let cell_w = size.ws_xpixel as f32 / size.ws_col as f32;
+            let cell_h = size.ws_ypixel as f32 / size.ws_row as f32;
+            return Some(cell_h / cell_w);
+        }
+
+        None
+    }
+
+    /// Make `frame` showable on this terminal. Without colors every half
+    /// block looks the same, so they are swapped for characters of `ramp`
+    /// picked by the intensity of both halves
+    pub fn adapt(&self, frame: AsciiFrame) -> AsciiFrame {
+        if self.color_depth != ColorDepth::Monochrome
+            || frame.mode != RenderMode::HalfBlock
+            || self.ramp.is_empty()
+        {
+            return frame;
+        }
+        let Ok(mut out) = AsciiFrame::new(frame.w, frame.h, ' ') else {
+            return frame;
+        };
+
+        let ramp = &self.ramp;
+        let colors = frame.colors().unwrap_or_default();
+        for (i, color) in colors.iter().enumerate() {
+            // halves without a color count as black
+            let intensity = [color.fg, color.bg]
+                .into_iter()
+                .flatten()
+                .map(|rgb| self.luminance.intensity((rgb.r, rgb.g, rgb.b)))
+                .sum::<f32>()
+                / 2.0;
+            let char_i = ((intensity / 255.0 * ramp.len() as f32) as usize).min(ramp.len() - 1);
+            out.set_char(i % frame.w, i / frame.w, ramp[char_i]);
+        }
+
+        out
+    }
+
     /// With an `AsciiFrame`, output any ASCII characters that changed from
-    /// `prev_frame` to the screen, and record these changes into
-    /// `prev_frame`
+    /// `prev_frame` to the screen, in the closest colors the terminal can
+    /// show, and record these changes into `prev_frame`
     pub fn render(&mut self, frame: &AsciiFrame) -> Result<(), Box<dyn Error>> {
         // did frame size change?
         if frame.w != self.prev_w
@@ -44,57 +154,114 @@ impl AsciiRenderer {
             || self.prev_frame.len() != frame.w * frame.h
         {
             self.prev_frame = vec![' '; frame.w * frame.h];
+            self.prev_colors = vec![Pen::default(); frame.w * frame.h];
             self.prev_w = frame.w;
             self.prev_h = frame.h;
 
             Self::clear_screen()?;
         }
 
+        // colors the terminal currently prints with
+        let mut pen = Pen::default();
+
         for y in 0..frame.h {
             for x in 0..frame.w {
                 let i = y * frame.w + x;
+                if i >= frame.chars().len() || i >= self.prev_frame.len() {
+                    continue;
+                }
+
+                let c = frame.chars()[i];
+                let color = self
+                    .color_depth
+                    .pen(frame.colors().map_or_else(CellColor::default, |c| c[i]));
+                if c != self.prev_frame[i] || color != self.prev_colors[i] {
+                    if color != pen {
+                        print!("{}", color.sgr());
+                        pen = color;
+                    }
 
-                if i < frame.chars().len()
-                    && i < self.prev_frame.len()
-                    && frame.chars()[i] != self.prev_frame[i]
-                {
                     // ANSI escape code sequence, move cursor to specified
                     // row & column & change character
-                    print!("\x1B[{};{}H{}", y + 1, x + 1, frame.chars()[i]);
-                    self.prev_frame[i] = frame.chars()[i];
+                    print!("\x1B[{};{}H{}", y + 1, x + 1, c);
+                    self.prev_frame[i] = c;
+                    self.prev_colors[i] = color;
                 }
             }
         }
 
+        // leave the terminal in its default colors
+        if pen != Pen::default() {
+            print!("\x1B[0m");
+        }
         io::stdout().flush()?;
 
         Ok(())
     }
 
-    /// Deserializes an array of bytes into an `AsciiFrame`, if it is valid
-    pub fn process_datagram(&mut self, datagram: &[u8]) -> Result<AsciiFrame, Box<dyn Error>> {
-        if datagram.len() < 16 {
-            return Err("frame too small (size header too small)".into());
+    /// Deserializes a fragment forwarded by the server. Once all fragments
+    /// of a frame have arrived, returns the sending participant's ID and
+    /// its `AsciiFrame`, if it is valid.
+    /// Frames that are older than the newest one seen from the same sender
+    /// (reordered or duplicated on the way) are rejected.
+    /// Frames that can't be decoded, e.g. deltas whose keyframe was lost,
+    /// queue up a keyframe request for the sender, see
+    /// `take_keyframe_requests`.
+    pub fn process_datagram(
+        &mut self,
+        datagram: &[u8],
+    ) -> Result<Option<(ParticipantId, AsciiFrame)>, Box<dyn Error>> {
+        let (sender, fragment) = decode_relayed(datagram).ok_or("datagram missing relay header")?;
+        let Some(datagram) = self.reassembler.push(sender, fragment, Instant::now())? else {
+            return Ok(None);
+        };
+        let (header, _) = FrameHeader::decode(&datagram)?;
+
+        if let Some(newest) = self.newest.get(&sender)
+            && !header.is_newer_than(newest)
+            && newest.seq.wrapping_sub(header.seq) <= MAX_REORDER
+        {
+            return Err(format!("stale frame {} from {}", header.seq, sender).into());
         }
+        // anything further behind means the sender restarted its stream
 
-        let mut w_bytes = [0u8; 8];
-        w_bytes.copy_from_slice(&datagram[0..8]);
-        let w = usize::from_be_bytes(w_bytes);
+        let frame = match self.decoder.decode(sender, &datagram) {
+            Ok((_, frame)) => frame,
+            Err(e) => {
+                self.request_keyframe(sender);
+                return Err(e);
+            }
+        };
 
-        let mut h_bytes = [0u8; 8];
-        h_bytes.copy_from_slice(&datagram[8..16]);
-        let h = usize::from_be_bytes(h_bytes);
+        self.newest.insert(sender, header);
+        Ok(Some((sender, frame)))
+    }
 
-        AsciiFrame::from_bytes(w, h, &datagram[16..])
+    /// Forget the streams of senders that `keep` rejects, e.g. participants
+    /// that left the session
+    pub fn retain_streams(&mut self, mut keep: impl FnMut(&ParticipantId) -> bool) {
+        self.newest.retain(|id, _| keep(id));
+        self.reassembler.retain_senders(|id| keep(id));
+        self.decoder.retain_senders(|id| keep(id));
+        self.keyframe_requested_at.retain(|id, _| keep(id));
     }
 
-    /// Serializes an array of bytes into an `AsciiFrame`
-    pub fn serialize_frame(frame: &AsciiFrame) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(16 + frame.w * frame.h * 4);
-        bytes.extend_from_slice(&frame.w.to_be_bytes());
-        bytes.extend_from_slice(&frame.h.to_be_bytes());
-        bytes.extend_from_slice(&frame.bytes());
+    /// Queue up a keyframe request for `sender`, unless one was made recently
+    fn request_keyframe(&mut self, sender: ParticipantId) {
+        let now = Instant::now();
+        if let Some(at) = self.keyframe_requested_at.get(&sender)
+            && now.duration_since(*at) < KEYFRAME_REQUEST_INTERVAL
+        {
+            return;
+        }
+
+        self.keyframe_requested_at.insert(sender, now);
+        self.pending_keyframe_requests.push(sender);
+    }
 
-        bytes
+    /// Senders that should be asked for a keyframe, since their deltas
+    /// could not be applied
+    pub fn take_keyframe_requests(&mut self) -> Vec<ParticipantId> {
+        std::mem::take(&mut self.pending_keyframe_requests)
     }
 }
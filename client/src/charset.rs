@@ -0,0 +1,299 @@
+use crate::ascii_converter::AsciiConverter;
+use font8x8::{
+    BASIC_FONTS, BLOCK_FONTS, BOX_FONTS, GREEK_FONTS, HIRAGANA_FONTS, LATIN_FONTS, MISC_FONTS,
+    SGA_FONTS, UnicodeFonts,
+};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Named charsets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CharsetPreset {
+    /// the `AsciiConverter::DEFAULT_*` characters
+    #[default]
+    Default,
+    /// 7-bit ASCII only, for terminals and fonts without Unicode
+    Ascii,
+    /// shade blocks, which fill their cell the most evenly
+    Blocks,
+    /// light to heavy box-drawing lines
+    BoxDrawing,
+    /// 70 ASCII characters, finely graded by how much ink they use
+    Dense,
+}
+
+/// Characters an `AsciiConverter` draws with: an intensity ramp from dark
+/// to bright, and a set of characters for each `EdgeDirection`, from faint
+/// to strong edges
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Charset {
+    /// intensity ramp, from dark to bright
+    pub intensity: Vec<char>,
+    /// characters for edges with a horizontal gradient (vertical lines)
+    pub horizontal: Vec<char>,
+    /// characters for edges with a vertical gradient (horizontal lines)
+    pub vertical: Vec<char>,
+    /// characters for forward edges
+    pub forward: Vec<char>,
+    /// characters for back edges
+    pub back: Vec<char>,
+}
+
+impl Default for Charset {
+    fn default() -> Self {
+        Self::from_strs(
+            AsciiConverter::DEFAULT_ASCII_INTENSITY,
+            AsciiConverter::DEFAULT_ASCII_HORIZONTAL,
+            AsciiConverter::DEFAULT_ASCII_VERTICAL,
+            AsciiConverter::DEFAULT_ASCII_FORWARD,
+            AsciiConverter::DEFAULT_ASCII_BACK,
+        )
+    }
+}
+
+impl From<CharsetPreset> for Charset {
+    fn from(preset: CharsetPreset) -> Self {
+        match preset {
+            CharsetPreset::Default => Self::default(),
+            CharsetPreset::Ascii => Self::from_strs(" .:-=+*#%@", "|", "-", "/", "\\"),
+            CharsetPreset::Blocks => Self {
+                intensity: " ░▒▓█".chars().collect(),
+                ..Self::default()
+            },
+            CharsetPreset::BoxDrawing => Self::from_strs(" ·┈┄─┼╋█", "│┃║", "─━═", "╱", "╲"),
+            CharsetPreset::Dense => Self {
+                intensity:
+                    r##" .'`^",:;Il!i><~+_-?][}{1)(|\/tfjrxnuvczXYUJCLQ0OZmwqpdbkhao*#MW&8%B@$"##
+                        .chars()
+                        .collect(),
+                ..Self::from(CharsetPreset::Ascii)
+            },
+        }
+    }
+}
+
+impl Charset {
+    fn from_strs(
+        intensity: &str,
+        horizontal: &str,
+        vertical: &str,
+        forward: &str,
+        back: &str,
+    ) -> Self {
+        Self {
+            intensity: intensity.chars().collect(),
+            horizontal: horizontal.chars().collect(),
+            vertical: vertical.chars().collect(),
+            forward: forward.chars().collect(),
+            back: back.chars().collect(),
+        }
+    }
+
+    /// Every set by its name in charset files
+    fn sets(&self) -> [(&'static str, &Vec<char>); 5] {
+        [
+            ("intensity", &self.intensity),
+            ("horizontal", &self.horizontal),
+            ("vertical", &self.vertical),
+            ("forward", &self.forward),
+            ("back", &self.back),
+        ]
+    }
+
+    /// Check that every set has characters, and that none of them would
+    /// break the lines of a frame
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        for (name, set) in self.sets() {
+            if set.is_empty() {
+                return Err(format!("{name} characters must not be empty").into());
+            }
+            if let Some(c) = set.iter().find(|c| c.is_control()) {
+                return Err(format!("{name} characters contain control character {c:?}").into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a charset file, see `parse`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read charset {}: {e}", path.display()))?;
+        Self::parse(&text).map_err(|e| format!("charset {}: {e}", path.display()).into())
+    }
+
+    /// Parse the contents of a charset file: lines of `name = characters`,
+    /// where `name` is one of `intensity`, `horizontal`, `vertical`,
+    /// `forward` or `back`. Characters may be wrapped in double quotes to
+    /// keep leading or trailing spaces. Lines starting with `#` are
+    /// comments, and sets that aren't given keep their default:
+    ///
+    /// ```text
+    /// # dark to bright
+    /// intensity = " .:-=+*#%@"
+    /// horizontal = |
+    /// ```
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut charset = Self::default();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((name, chars)) = line.split_once('=') else {
+                return Err(format!("line {}: expected `name = characters`", n + 1).into());
+            };
+            let chars = chars.trim();
+            let chars = chars
+                .strip_prefix('"')
+                .and_then(|chars| chars.strip_suffix('"'))
+                .unwrap_or(chars);
+
+            let set = match name.trim() {
+                "intensity" => &mut charset.intensity,
+                "horizontal" => &mut charset.horizontal,
+                "vertical" => &mut charset.vertical,
+                "forward" => &mut charset.forward,
+                "back" => &mut charset.back,
+                other => return Err(format!("line {}: unknown set `{other}`", n + 1).into()),
+            };
+            *set = chars.chars().collect();
+        }
+
+        charset.validate()?;
+        Ok(charset)
+    }
+
+    /// Share of an 8x8 bitmap of `c` that is inked (0.0 - 1.0), or `None`
+    /// if there's no bitmap of it to measure
+    pub fn glyph_density(c: char) -> Option<f32> {
+        let glyph = BASIC_FONTS
+            .get(c)
+            .or_else(|| LATIN_FONTS.get(c))
+            .or_else(|| BOX_FONTS.get(c))
+            .or_else(|| BLOCK_FONTS.get(c))
+            .or_else(|| GREEK_FONTS.get(c))
+            .or_else(|| MISC_FONTS.get(c))
+            .or_else(|| SGA_FONTS.get(c))
+            .or_else(|| HIRAGANA_FONTS.get(c))?;
+
+        let inked: u32 = glyph.iter().map(|row| row.count_ones()).sum();
+        Some(inked as f32 / 64.0)
+    }
+
+    /// Order the intensity ramp from the least to the most inked character,
+    /// keeping the given order between characters that measure the same
+    pub fn sort_by_density(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut measured = Vec::with_capacity(self.intensity.len());
+        for &c in &self.intensity {
+            let density = Self::glyph_density(c)
+                .ok_or_else(|| format!("no glyph to measure the density of {c:?}"))?;
+            measured.push((density, c));
+        }
+
+        measured.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        self.intensity = measured.into_iter().map(|(_, c)| c).collect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Error message `parse` gives for `text`
+    fn parse_error(text: &str) -> String {
+        Charset::parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn quotes_keep_leading_and_trailing_spaces() {
+        let charset = Charset::parse("intensity = \"  .:# \"\nback = \\").unwrap();
+        assert_eq!(charset.intensity, vec![' ', ' ', '.', ':', '#', ' ']);
+        assert_eq!(charset.back, vec!['\\']);
+    }
+
+    #[test]
+    fn sets_not_given_keep_their_default() {
+        let text = "# only the ramp\n\nintensity = .:#\n";
+        let charset = Charset::parse(text).unwrap();
+        assert_eq!(charset.intensity, vec!['.', ':', '#']);
+        assert_eq!(
+            Charset {
+                intensity: Charset::default().intensity,
+                ..charset
+            },
+            Charset::default()
+        );
+    }
+
+    #[test]
+    fn unknown_set_is_an_error() {
+        assert_eq!(
+            parse_error("intensity = .:#\ndiagonal = /"),
+            "line 2: unknown set `diagonal`"
+        );
+    }
+
+    #[test]
+    fn line_without_equals_sign_is_an_error() {
+        assert_eq!(
+            parse_error("# comment\nintensity .:#"),
+            "line 2: expected `name = characters`"
+        );
+    }
+
+    #[test]
+    fn empty_sets_are_an_error() {
+        for text in ["forward =", "forward = \"\""] {
+            assert_eq!(parse_error(text), "forward characters must not be empty");
+        }
+    }
+
+    #[test]
+    fn control_characters_are_an_error() {
+        assert_eq!(
+            parse_error("vertical = -\t="),
+            "vertical characters contain control character '\\t'"
+        );
+    }
+
+    #[test]
+    fn presets_are_valid() {
+        for preset in [
+            CharsetPreset::Default,
+            CharsetPreset::Ascii,
+            CharsetPreset::Blocks,
+            CharsetPreset::BoxDrawing,
+            CharsetPreset::Dense,
+        ] {
+            assert!(Charset::from(preset).validate().is_ok(), "{preset:?}");
+        }
+    }
+
+    #[test]
+    fn density_sort_is_stable() {
+        // 'p', 'q', '6' and '9' all ink 24 of 64 pixels
+        let mut charset = Charset {
+            intensity: "M9.q6p ".chars().collect(),
+            ..Charset::default()
+        };
+        charset.sort_by_density().unwrap();
+        assert_eq!(charset.intensity, " .9q6pM".chars().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn density_sort_needs_a_glyph_for_every_character() {
+        let mut charset = Charset {
+            intensity: " .■#".chars().collect(),
+            ..Charset::default()
+        };
+        let error = charset.sort_by_density().unwrap_err().to_string();
+        assert_eq!(error, "no glyph to measure the density of '■'");
+        // and leaves the ramp as it was
+        assert_eq!(charset.intensity, " .■#".chars().collect::<Vec<_>>());
+    }
+}
@@ -1,24 +1,45 @@
 use crate::ascii_converter::AsciiConverter;
 use crate::ascii_renderer::AsciiRenderer;
 use crate::camera::Camera;
+use crate::color_depth::ColorDepth;
+use crate::edge_detector::EdgeSettings;
 use crate::image_frame::ImageFrame;
+use crate::layout::GridLayout;
 use crate::mock_frame_generator::{MockFrameGenerator, PatternType};
 use crate::video_config::VideoConfig;
 use common::ascii_frame::AsciiFrame;
+use common::compression::Codec;
+use common::datagram::timestamp_now;
+use common::delta::DeltaEncoder;
+use common::fragment::{MAX_FRAGMENT_LEN, fragment};
+use common::protocol::{
+    ControlCodec, ControlMessage, PROTOCOL_VERSION, ParticipantId, RELAY_HEADER_LEN, check_version,
+};
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
+use std::io::BufRead;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::{broadcast, watch};
-use tokio::task;
-use tokio::time::{Instant, sleep};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{Instant, sleep, timeout};
+use tokio::{select, task};
 
 /// Max amount of frames that can be buffered
 const FRAME_BUFFER: usize = 30;
 /// Target framerate for rendering
 const FPS: u64 = 30;
+/// How long to wait for the server to acknowledge a UDP registration `PING`
+const REGISTER_TIMEOUT: Duration = Duration::from_millis(250);
+/// How many registration `PING`s are sent before giving up
+const REGISTER_ATTEMPTS: usize = 20;
+/// Line typed on `stdin` that shows / hides the self-view
+const SELF_VIEW_TOGGLE: &str = "v";
+/// How often the terminal's size is checked where there is no `SIGWINCH`
+#[cfg(not(unix))]
+const SCREEN_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 /// Terminal-based client that connects to a server for ASCII video streaming.
 /// Session control is handled over TCP, frame forwarding is handled over UDP.
@@ -30,36 +51,62 @@ pub struct Client {
     server_udp_addr: String,
     /// Session ID client attempts to join
     session_id: String,
+    /// Name shown to the other participants, if chosen
+    name: Option<String>,
+    /// Frame codecs offered to the server, which picks one per session
+    codecs: Vec<Codec>,
     /// Flag for session connection.
     /// Written to by TCP-control, read by other tasks
     conn_flag_tx: watch::Sender<bool>,
     conn_flag_rx: watch::Receiver<bool>,
-    /// Flag for if peer is on other end of session
+    /// Participants currently streaming on the other end of the session,
+    /// along with their names.
     /// Written to by TCP-control, read by sender & renderer
-    peer_flag_tx: watch::Sender<bool>,
-    peer_flag_rx: watch::Receiver<bool>,
+    peers_tx: watch::Sender<BTreeMap<ParticipantId, String>>,
+    peers_rx: watch::Receiver<BTreeMap<ParticipantId, String>>,
+    /// Whether the local stream is shown as an inset on screen.
+    /// Toggled from `stdin`, read by renderer & frame generation
+    self_view_tx: watch::Sender<bool>,
+    self_view_rx: watch::Receiver<bool>,
+    /// Colors the terminal can show
+    color_depth: ColorDepth,
+    /// How the local video is captured and converted
+    video_config: VideoConfig,
     /// Optionally, pattern can be used instead of camera
     test_pattern: Option<PatternType>,
 }
 
 impl Client {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         server_tcp_addr: String,
         server_udp_addr: String,
         session_id: String,
+        name: Option<String>,
+        codecs: Vec<Codec>,
+        self_view: bool,
+        color_depth: ColorDepth,
+        video_config: VideoConfig,
         test_pattern: Option<PatternType>,
     ) -> Self {
         let (conn_flag_tx, conn_flag_rx) = watch::channel(false);
-        let (peer_flag_tx, peer_flag_rx) = watch::channel(false);
+        let (peers_tx, peers_rx) = watch::channel(BTreeMap::new());
+        let (self_view_tx, self_view_rx) = watch::channel(self_view);
 
         Self {
             server_tcp_addr,
             server_udp_addr,
             session_id,
+            name,
+            codecs,
             conn_flag_tx,
             conn_flag_rx,
-            peer_flag_tx,
-            peer_flag_rx,
+            peers_tx,
+            peers_rx,
+            self_view_tx,
+            self_view_rx,
+            color_depth,
+            video_config,
             test_pattern,
         }
     }
@@ -67,9 +114,10 @@ impl Client {
     /// Start client's runtime logic:
     /// - Connect to server
     /// - Join session
-    /// - Registers its UDP port
+    /// - Registers its UDP port with the token handed out on join
     /// - Spawns background tasks for:
     ///     - TCP control handling
+    ///     - Self-view toggling from `stdin`
     ///     - UDP receiving / rendering
     ///     - Frame generation / sending
     pub async fn run(&self) -> Result<(), Box<dyn Error>> {
@@ -81,40 +129,115 @@ impl Client {
         let udp_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
         udp_socket.connect(&self.server_udp_addr).await?;
 
-        // === SESSION HANDSHAKE (JOIN + REGISTER_UDP) ============================================
-        // Sends JOIN request to server to either create a new session or
-        // join a preexisting one
-        tcp_wr
-            .write_all(format!("JOIN {}\n", self.session_id).as_bytes())
-            .await?;
-        Self::expect_ok(&mut tcp_rd).await?;
-        udp_socket.send(b"PING").await?;
+        // codec picked by the server for the session's frames.
+        // Written to by TCP-control, read by sender
+        let (frame_codec_tx, mut frame_codec_rx) = watch::channel(Codec::None);
+        // size (in characters) every peer shows our frames at.
+        // Written to by TCP-control, read by frame generation
+        let (viewports_tx, viewports_rx) = watch::channel(BTreeMap::new());
+
+        // === SESSION HANDSHAKE (HELLO + JOIN + REGISTER_UDP) =====================================
+        // Agree on a protocol version, then send JOIN request to server to
+        // either create a new session or join a preexisting one
+        let mut codec = ControlCodec::new();
+        Self::send_message(
+            &mut tcp_wr,
+            &ControlMessage::Hello {
+                version: PROTOCOL_VERSION,
+                codecs: self.codecs.clone(),
+            },
+        )
+        .await?;
+        match Self::read_message(&mut tcp_rd, &mut codec).await? {
+            ControlMessage::Welcome { version } => check_version(version)?,
+            other => return Err(Self::unexpected_reply(other)),
+        }
+
+        Self::send_message(
+            &mut tcp_wr,
+            &ControlMessage::Join {
+                session_id: self.session_id.clone(),
+                name: self.name.clone(),
+            },
+        )
+        .await?;
+        let (participant_id, token) = match Self::read_message(&mut tcp_rd, &mut codec).await? {
+            ControlMessage::Joined {
+                participant_id,
+                token,
+                ..
+            } => (participant_id, token),
+            other => return Err(Self::unexpected_reply(other)),
+        };
+
+        // PING may be lost like any datagram, so repeat it until the server
+        // confirms the registration over TCP
+        let ping = token.ping_datagram();
+        let mut attempts = 0;
+        loop {
+            if attempts == REGISTER_ATTEMPTS {
+                return Err("server did not acknowledge UDP registration".into());
+            }
+            attempts += 1;
+            udp_socket.send(&ping).await?;
+
+            match timeout(
+                REGISTER_TIMEOUT,
+                Self::read_message(&mut tcp_rd, &mut codec),
+            )
+            .await
+            {
+                Ok(Ok(ControlMessage::Registered)) => break,
+                Ok(Ok(ControlMessage::Error { code, reason })) => {
+                    return Err(format!("server error {}: {}", code, reason).into());
+                }
+                // the session's codec is announced right after joining
+                Ok(Ok(ControlMessage::Codec { codec })) => {
+                    let _ = frame_codec_tx.send(codec);
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Err(e),
+                // no acknowledgement yet, send another PING
+                Err(_) => {}
+            }
+        }
 
         // update our session status to connected
         let _ = self.conn_flag_tx.send(true);
 
         // println!("joined session: {}", self.session_id);
 
-        let (frame_tx, _) = broadcast::channel::<AsciiFrame>(FRAME_BUFFER);
+        // frames are broadcast along with the time they were captured at
+        let (frame_tx, _) = broadcast::channel::<(AsciiFrame, u64)>(FRAME_BUFFER);
+        // a peer asked for a keyframe, written to by TCP-control, read by sender
+        let (keyframe_tx, mut keyframe_rx) = watch::channel(());
+
+        // === TCP CONTROL WRITER =================================================================
+        // Other tasks send control messages to the server through this
+        // channel. Leaving the session is the last message sent.
+        let (ctrl_tx, mut ctrl_rx) = mpsc::unbounded_channel::<ControlMessage>();
+        let ctrl_writer = task::spawn(async move {
+            while let Some(msg) = ctrl_rx.recv().await {
+                if let Err(e) = Self::send_message(&mut tcp_wr, &msg).await {
+                    eprintln!("[CONTROL] TCP write error: {e}");
+                    break;
+                }
+                if msg == ControlMessage::Leave {
+                    break;
+                }
+            }
+        });
 
         // === TCP SESSION CONTROL ================================================================
         // Reads control messages from server, updating local state about
         // session connection and / or peer presence.
         let ctrl_conn_tx = self.conn_flag_tx.clone();
-        let ctrl_peer_tx = self.peer_flag_tx.clone();
+        let ctrl_peers_tx = self.peers_tx.clone();
         task::spawn(async move {
-            let mut buf = vec![0u8; 1024];
-
             loop {
-                let n = match tcp_rd.read(&mut buf).await {
-                    // connection to SFU terminated
-                    Ok(0) => {
-                        let _ = ctrl_conn_tx.send(false);
-                        break;
-                    }
-                    // message received
-                    Ok(n) => n,
-                    // read error
+                let msg = match Self::read_message(&mut tcp_rd, &mut codec).await {
+                    Ok(msg) => msg,
+                    // connection to SFU terminated or unreadable
                     Err(e) => {
                         eprintln!("[CONTROL] TCP read error: {e}");
                         let _ = ctrl_conn_tx.send(false);
@@ -123,64 +246,202 @@ impl Client {
                 };
 
                 // actions for received message
-                match &buf[..n] {
-                    msg if msg.starts_with(b"CONNECTED") => {
-                        let _ = ctrl_peer_tx.send(true);
+                match msg {
+                    ControlMessage::PeerJoined {
+                        participant_id,
+                        name,
+                    } => {
+                        ctrl_peers_tx.send_modify(|peers| {
+                            peers.insert(participant_id, name);
+                        });
+                    }
+                    ControlMessage::PeerLeft { participant_id } => {
+                        ctrl_peers_tx.send_modify(|peers| {
+                            peers.remove(&participant_id);
+                        });
+                        viewports_tx.send_if_modified(|viewports| {
+                            viewports.remove(&participant_id).is_some()
+                        });
+                    }
+                    ControlMessage::Viewport {
+                        participant_id,
+                        width,
+                        height,
+                    } => {
+                        viewports_tx.send_modify(|viewports| {
+                            if width == 0 || height == 0 {
+                                viewports.remove(&participant_id);
+                            } else {
+                                viewports.insert(participant_id, (width as usize, height as usize));
+                            }
+                        });
+                    }
+                    ControlMessage::RequestKeyframe { .. } => {
+                        let _ = keyframe_tx.send(());
+                    }
+                    ControlMessage::Codec { codec } => {
+                        let _ = frame_codec_tx.send(codec);
                     }
-                    msg if msg.starts_with(b"DISCONNECTED") => {
-                        let _ = ctrl_peer_tx.send(false);
+                    ControlMessage::Error { code, reason } => {
+                        eprintln!("[CONTROL] server error {code}: {reason}");
                     }
                     _ => {}
                 }
             }
         });
 
+        let cfg = &self.video_config;
+        let fallback_size = (cfg.ascii_width, cfg.ascii_height);
+
+        // === TERMINAL RESIZING ==================================================================
+        // The terminal's size is only looked up again when it changes
+        let (screen_tx, screen_rx) = watch::channel(AsciiRenderer::screen_size(fallback_size));
+        task::spawn(Self::watch_screen_size(screen_tx, fallback_size));
+
+        // === SELF-VIEW TOGGLE ===================================================================
+        // Typing `v` + Enter shows / hides the self-view. A plain thread is
+        // used since a blocking read of `stdin` would otherwise hold up the
+        // runtime's shutdown.
+        let toggle_tx = self.self_view_tx.clone();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                match line {
+                    Ok(line) if line.trim() == SELF_VIEW_TOGGLE => {
+                        toggle_tx.send_modify(|shown| *shown = !*shown);
+                    }
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+        });
+
         // === FRAME RENDERING ====================================================================
-        // Receive incoming frames and render.
+        // Receive incoming frames, keeping the latest one of every peer, and
+        // render them tiled into a grid that fills the terminal.
+        // The grid is laid out again whenever peers join / leave or the
+        // terminal is resized, and every peer is told the size it is shown
+        // at, so it can send frames that fit. The local stream is drawn on
+        // top as an inset while the self-view is shown.
         let rend_conn_rx = self.conn_flag_rx.clone();
-        let mut rend_peer_rx = self.peer_flag_rx.clone();
+        let mut rend_peers_rx = self.peers_rx.clone();
+        let mut rend_self_view_rx = self.self_view_rx.clone();
+        let mut rend_screen_rx = screen_rx.clone();
+        let mut local_rx = frame_tx.subscribe();
+        let rend_ctrl_tx = ctrl_tx.clone();
         let udp_rend = udp_socket.clone();
-        let frame_interval = Duration::from_millis((1000 / FPS));
+        let frame_interval = Duration::from_millis(1000 / FPS);
+        let frame_aspect = cfg.ascii_width as f32 / cfg.ascii_height as f32;
+        let color_depth = self.color_depth;
+        let luminance = cfg.luminance;
+        let ramp = cfg.charset.intensity.clone();
         task::spawn(async move {
-            let mut buf = vec![0u8; 65536];
-            let mut renderer = AsciiRenderer::new().unwrap();
+            let mut buf = vec![0u8; RELAY_HEADER_LEN + MAX_FRAGMENT_LEN];
+            let mut renderer = AsciiRenderer::new(color_depth, luminance, ramp).unwrap();
+            let mut frames: BTreeMap<ParticipantId, AsciiFrame> = BTreeMap::new();
+            let mut local_frame: Option<AsciiFrame> = None;
+            let mut layout = GridLayout::new(0, 0, &[], frame_aspect);
+            // size of the area every peer was last told it is shown at
+            let mut viewports: HashMap<ParticipantId, (usize, usize)> = HashMap::new();
             let mut next_frame_time = Instant::now() + frame_interval;
 
             while *rend_conn_rx.borrow() {
-                // blocks until peer is present
-                let _ = rend_peer_rx.wait_for(|peer| *peer).await;
+                let peers = rend_peers_rx.borrow_and_update().clone();
+                let self_view_changed = rend_self_view_rx.has_changed().unwrap_or(false);
+                let self_view = *rend_self_view_rx.borrow_and_update();
 
-                let mut next_frame = None;
+                // blocks until a peer is present or the self-view is shown,
+                // once whatever was shown before has been cleared
+                let cleared = layout.tiles.is_empty() && !self_view_changed;
+                if peers.is_empty() && !self_view && cleared {
+                    select! {
+                        _ = rend_peers_rx.changed() => {}
+                        _ = rend_self_view_rx.changed() => {}
+                    }
+                    continue;
+                }
+
+                // forget the last frames of peers that left
+                frames.retain(|id, _| peers.contains_key(id));
+                renderer.retain_streams(|id| peers.contains_key(id));
+                viewports.retain(|id, _| peers.contains_key(id));
+
+                // the terminal may have echoed the toggle, so redraw everything
+                if self_view_changed {
+                    renderer.invalidate();
+                }
+
+                let mut received = self_view_changed;
+                loop {
+                    match local_rx.try_recv() {
+                        Ok((frame, _)) => {
+                            local_frame = Some(renderer.adapt(frame));
+                            received |= self_view;
+                        }
+                        Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+                        Err(_) => break,
+                    }
+                }
                 loop {
                     match udp_rend.try_recv(&mut buf) {
-                        // received frame, move on to rendering it
                         Ok(n) => {
-                            if let Ok(frame) = renderer.process_datagram(&buf[..n]) {
-                                next_frame = Some(frame);
-                            }
-                        }
-                        // expected, wait for frame to arrive
-                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                            if next_frame.is_some() {
-                                break;
-                            } else {
-                                // sleep for a tiny bit
-                                sleep(Duration::from_millis(1)).await;
+                            if let Ok(Some((sender, frame))) = renderer.process_datagram(&buf[..n])
+                                && peers.contains_key(&sender)
+                            {
+                                frames.insert(sender, renderer.adapt(frame));
+                                received = true;
                             }
                         }
+                        // expected, no more frames have arrived
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
                         // actual receive error
                         Err(e) => {
                             eprintln!("[RENDER] UDP receive error: {e}");
-                            if next_frame.is_some() {
-                                break;
-                            } else {
-                                // sleep for a tiny bit
-                                sleep(Duration::from_millis(1)).await;
-                            }
+                            break;
+                        }
+                    }
+                }
+
+                // peers whose deltas could not be applied need to send a keyframe
+                for participant_id in renderer
+                    .take_keyframe_requests()
+                    .into_iter()
+                    .filter(|id| peers.contains_key(id))
+                {
+                    let _ = rend_ctrl_tx.send(ControlMessage::RequestKeyframe { participant_id });
+                }
+
+                let (screen_w, screen_h) = *rend_screen_rx.borrow_and_update();
+                let ids: Vec<_> = peers.keys().copied().collect();
+                let relayout = !layout.matches(screen_w, screen_h, &ids);
+                if relayout {
+                    layout = GridLayout::new(screen_w, screen_h, &ids, frame_aspect);
+
+                    for tile in &layout.tiles {
+                        let (_, _, w, h) = tile.inner();
+                        if viewports.insert(tile.id, (w, h)) == Some((w, h)) {
+                            continue;
                         }
+                        let _ = rend_ctrl_tx.send(ControlMessage::Viewport {
+                            participant_id: tile.id,
+                            width: w.min(u16::MAX as usize) as u16,
+                            height: h.min(u16::MAX as usize) as u16,
+                        });
                     }
                 }
-                let _ = renderer.render(&next_frame.unwrap());
+
+                if !received && !relayout {
+                    // sleep for a tiny bit, waiting for frames to arrive
+                    sleep(Duration::from_millis(1)).await;
+                    continue;
+                }
+
+                let mut canvas = AsciiFrame::new(screen_w, screen_h, ' ').unwrap();
+                layout.compose(&mut canvas, &frames, &peers);
+                if self_view {
+                    let inset = layout.inset(participant_id, frame_aspect);
+                    GridLayout::draw_tile(&mut canvas, &inset, "you", local_frame.as_ref());
+                }
+                let _ = renderer.render(&canvas);
 
                 let now = Instant::now();
                 if next_frame_time > now {
@@ -198,18 +459,34 @@ impl Client {
         // === FRAME CAPTURE, ENCODING, AND SENDING ===============================================
         // Receive AsciiFrame, then serialize and send to peer via UDP if present.
         let send_conn_rx = self.conn_flag_rx.clone();
-        let mut send_peer_rx = self.peer_flag_rx.clone();
+        let mut send_peers_rx = self.peers_rx.clone();
         let udp_send = udp_socket.clone();
         let mut ser_rx = frame_tx.subscribe();
         task::spawn(async move {
+            let mut encoder = DeltaEncoder::default();
+            // sequence number of the next frame sent
+            let mut seq: u32 = 0;
             while *send_conn_rx.borrow() {
                 // blocks until peer is present
-                let _ = send_peer_rx.wait_for(|peer| *peer).await;
+                let _ = send_peers_rx.wait_for(|peers| !peers.is_empty()).await;
 
                 match ser_rx.recv().await {
-                    Ok(frame) => {
-                        let data = AsciiRenderer::serialize_frame(&frame);
-                        let _ = udp_send.send(&data).await;
+                    Ok((frame, timestamp_us)) => {
+                        // a peer could not apply our deltas
+                        if keyframe_rx.has_changed().unwrap_or(false) {
+                            keyframe_rx.borrow_and_update();
+                            encoder.request_keyframe();
+                        }
+                        // the session's codec changed, e.g. someone joined
+                        if frame_codec_rx.has_changed().unwrap_or(false) {
+                            encoder.set_codec(*frame_codec_rx.borrow_and_update());
+                        }
+
+                        let data = encoder.encode(&frame, seq, timestamp_us);
+                        for datagram in fragment(seq, &data) {
+                            let _ = udp_send.send(&datagram).await;
+                        }
+                        seq = seq.wrapping_add(1);
                     }
                     Err(broadcast::error::RecvError::Closed) => {
                         break;
@@ -224,20 +501,26 @@ impl Client {
         // === FRAME GENERATION (WEBCAM OR TEST PATTERN) ==========================================
         // From either a mock frame generator or the camera,
         // create the ASCII frames to send to the peer.
-        let cfg = VideoConfig::default();
         if let Some(pattern) = &self.test_pattern {
             let pattern_val = match pattern {
                 PatternType::Checkerboard => PatternType::Checkerboard,
                 &PatternType::MovingLine => PatternType::MovingLine,
             };
 
-            let mut frame_gen =
-                MockFrameGenerator::new(cfg.ascii_width, cfg.ascii_height, 30, pattern_val)?;
+            let mut frame_gen = MockFrameGenerator::new(
+                cfg.ascii_width,
+                cfg.ascii_height,
+                30,
+                pattern_val,
+                cfg.render_mode,
+            )?;
 
             while *self.conn_flag_rx.borrow() {
-                if *self.peer_flag_rx.borrow() {
+                if self.is_capturing() {
+                    let (w, h) = Self::frame_size(&viewports_rx.borrow(), fallback_size);
+                    frame_gen.resize(w, h);
                     let frame = frame_gen.generate_frame()?;
-                    let _ = frame_tx.send(frame);
+                    let _ = frame_tx.send((frame, timestamp_now()));
                 }
             }
         } else {
@@ -245,55 +528,144 @@ impl Client {
 
             let mut image_frame = ImageFrame::new(cfg.camera_width, cfg.camera_height, 3)?;
             let mut ascii_frame = AsciiFrame::new(cfg.ascii_width, cfg.ascii_height, ' ')?;
+            ascii_frame.mode = cfg.render_mode;
 
-            let converter = AsciiConverter::new(
-                AsciiConverter::DEFAULT_ASCII_INTENSITY.chars().collect(),
-                AsciiConverter::DEFAULT_ASCII_HORIZONTAL.chars().collect(),
-                AsciiConverter::DEFAULT_ASCII_VERTICAL.chars().collect(),
-                AsciiConverter::DEFAULT_ASCII_FORWARD.chars().collect(),
-                AsciiConverter::DEFAULT_ASCII_BACK.chars().collect(),
+            let mut converter = AsciiConverter::new(
+                cfg.charset.clone(),
                 cfg.camera_width,
                 cfg.camera_height,
-                cfg.edge_threshold,
+                EdgeSettings {
+                    operator: cfg.edge_operator,
+                    blur_sigma: cfg.edge_blur_sigma,
+                    low_threshold: cfg.edge_low_threshold,
+                    high_threshold: cfg.edge_high_threshold,
+                    luminance: cfg.luminance,
+                },
                 cfg.contrast,
                 cfg.brightness,
+                cfg.luminance,
+                cfg.equalization,
+                cfg.auto_exposure,
+                cfg.colors,
+                cfg.dithering,
+                cfg.resampling,
+                cfg.temporal_strength,
+                cfg.cell_aspect,
+                cfg.framing,
             )?;
 
             while *self.conn_flag_rx.borrow() {
-                if *self.peer_flag_rx.borrow() {
+                if self.is_capturing() {
+                    let (w, h) = Self::frame_size(&viewports_rx.borrow(), fallback_size);
+                    if (ascii_frame.w, ascii_frame.h) != (w, h) {
+                        ascii_frame = AsciiFrame::new(w, h, ' ')?;
+                        ascii_frame.mode = cfg.render_mode;
+                    }
+
                     camera.capture_frame(&mut image_frame)?;
+                    let captured_at = timestamp_now();
                     converter.convert(&image_frame, &mut ascii_frame)?;
 
-                    let mut output = AsciiFrame::new(cfg.ascii_width, cfg.ascii_height, ' ')?;
-                    output.set_chars(ascii_frame.chars());
-                    let _ = frame_tx.send(output);
+                    let _ = frame_tx.send((ascii_frame.clone(), captured_at));
                 }
             }
         }
 
         // connection stopped, signal to TCP CONTROL and leave
-        let _ = tcp_wr.write_all(b"LEAVE\n").await;
+        let _ = ctrl_tx.send(ControlMessage::Leave);
+        let _ = ctrl_writer.await;
         Ok(())
     }
 
-    /// Receive and respond to the initial handshake from the server
-    async fn expect_ok(rd: &mut OwnedReadHalf) -> Result<(), Box<dyn Error>> {
-        let mut line = Vec::with_capacity(64);
-        loop {
-            let mut byte = [0u8; 1];
-            if rd.read(&mut byte).await? == 0 {
-                return Err("unexpected EOF waiting for OK".into());
+    /// Frames are only produced while someone is watching them:
+    /// a peer, or the user through the self-view
+    fn is_capturing(&self) -> bool {
+        !self.peers_rx.borrow().is_empty() || *self.self_view_rx.borrow()
+    }
+
+    /// Size to make frames at: that of the largest area a peer shows them
+    /// in, or `fallback` until a peer says
+    fn frame_size(
+        viewports: &BTreeMap<ParticipantId, (usize, usize)>,
+        fallback: (usize, usize),
+    ) -> (usize, usize) {
+        viewports
+            .values()
+            .copied()
+            .max_by_key(|&(w, h)| w * h)
+            .unwrap_or(fallback)
+    }
+
+    /// Keep `screen_tx` up to date with the terminal's size, looking it up
+    /// again whenever the terminal is resized (`SIGWINCH`)
+    #[cfg(unix)]
+    async fn watch_screen_size(screen_tx: watch::Sender<(usize, usize)>, fallback: (usize, usize)) {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut resized = match signal(SignalKind::window_change()) {
+            Ok(resized) => resized,
+            Err(e) => {
+                eprintln!("[RENDER] can't watch for terminal resizes: {e}");
+                return;
             }
-            line.push(byte[0]);
-            if byte[0] == b'\n' {
+        };
+
+        while resized.recv().await.is_some() {
+            let size = AsciiRenderer::screen_size(fallback);
+            screen_tx.send_if_modified(|screen| std::mem::replace(screen, size) != size);
+            if screen_tx.is_closed() {
                 break;
             }
         }
-        let text = std::str::from_utf8(&line)?.trim_start();
-        if text.starts_with("OK") {
-            Ok(())
-        } else {
-            Err(format!("unexpected reply: {}", text).into())
+    }
+
+    /// Keep `screen_tx` up to date with the terminal's size, checking it
+    /// every `SCREEN_POLL_INTERVAL`
+    #[cfg(not(unix))]
+    async fn watch_screen_size(screen_tx: watch::Sender<(usize, usize)>, fallback: (usize, usize)) {
+        while !screen_tx.is_closed() {
+            sleep(SCREEN_POLL_INTERVAL).await;
+            let size = AsciiRenderer::screen_size(fallback);
+            screen_tx.send_if_modified(|screen| std::mem::replace(screen, size) != size);
+        }
+    }
+
+    /// Encode and write a single control message to the server
+    async fn send_message(
+        wr: &mut OwnedWriteHalf,
+        msg: &ControlMessage,
+    ) -> Result<(), Box<dyn Error>> {
+        wr.write_all(&ControlCodec::encode(msg)?).await?;
+        Ok(())
+    }
+
+    /// Read from the server until the next complete control message arrives.
+    /// Bytes belonging to later messages stay buffered in `codec`
+    async fn read_message(
+        rd: &mut OwnedReadHalf,
+        codec: &mut ControlCodec,
+    ) -> Result<ControlMessage, Box<dyn Error>> {
+        let mut buf = [0u8; 1024];
+        loop {
+            if let Some(msg) = codec.decode_next()? {
+                return Ok(msg);
+            }
+
+            let n = rd.read(&mut buf).await?;
+            if n == 0 {
+                return Err("connection closed by server".into());
+            }
+            codec.extend(&buf[..n]);
+        }
+    }
+
+    /// Turn a reply the handshake did not expect into an error
+    fn unexpected_reply(msg: ControlMessage) -> Box<dyn Error> {
+        match msg {
+            ControlMessage::Error { code, reason } => {
+                format!("server error {}: {}", code, reason).into()
+            }
+            other => format!("unexpected reply: {}", other).into(),
         }
     }
 }
@@ -0,0 +1,227 @@
+use common::ascii_frame::{CellColor, Rgb};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Index of the `colors` number in the terminfo database format
+const TERMINFO_COLORS: usize = 13;
+
+/// Values of the levels in the xterm 256-color palette's 6x6x6 color cube
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// Colors of the 16 basic ANSI colors, as xterm shows them
+const BASIC_COLORS: [Rgb; 16] = [
+    Rgb::new(0, 0, 0),
+    Rgb::new(205, 0, 0),
+    Rgb::new(0, 205, 0),
+    Rgb::new(205, 205, 0),
+    Rgb::new(0, 0, 238),
+    Rgb::new(205, 0, 205),
+    Rgb::new(0, 205, 205),
+    Rgb::new(229, 229, 229),
+    Rgb::new(127, 127, 127),
+    Rgb::new(255, 0, 0),
+    Rgb::new(0, 255, 0),
+    Rgb::new(255, 255, 0),
+    Rgb::new(92, 92, 255),
+    Rgb::new(255, 0, 255),
+    Rgb::new(0, 255, 255),
+    Rgb::new(255, 255, 255),
+];
+
+/// How many colors the terminal can show, deciding how character colors
+/// are written to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorDepth {
+    /// 24-bit colors
+    TrueColor,
+    /// xterm 256-color palette
+    Ansi256,
+    /// 16 basic ANSI colors
+    Ansi16,
+    /// no colors at all, only characters
+    Monochrome,
+}
+
+/// A single color, the way it is written to the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Paint {
+    Rgb(Rgb),
+    /// index into the 256-color palette
+    Palette(u8),
+    /// index into the 16 basic colors
+    Basic(u8),
+}
+
+/// Colors of a character cell, the way they are written to the terminal.
+/// `None` leaves the terminal's default color in place
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Pen {
+    fg: Option<Paint>,
+    bg: Option<Paint>,
+}
+
+impl Pen {
+    /// ANSI escape code sequence (SGR) that resets the terminal's colors,
+    /// then switches to the pen's colors
+    pub fn sgr(&self) -> String {
+        let mut out = String::from("\x1B[0");
+        for (is_fg, paint) in [(true, self.fg), (false, self.bg)] {
+            let layer = if is_fg { 38 } else { 48 };
+            match paint {
+                None => {}
+                Some(Paint::Rgb(rgb)) => {
+                    out += &format!(";{};2;{};{};{}", layer, rgb.r, rgb.g, rgb.b)
+                }
+                Some(Paint::Palette(i)) => out += &format!(";{};5;{}", layer, i),
+                Some(Paint::Basic(i)) => {
+                    // 30-37 / 40-47, bright colors at 90-97 / 100-107
+                    let base = if is_fg { 30 } else { 40 };
+                    let code = if i < 8 { base + i } else { base + 60 + i - 8 };
+                    out += &format!(";{}", code);
+                }
+            }
+        }
+        out.push('m');
+        out
+    }
+}
+
+impl ColorDepth {
+    /// Work out what the terminal supports, from (in order) `NO_COLOR`,
+    /// `COLORTERM`, `TERM` and the `colors` capability in `TERM`'s terminfo
+    /// entry
+    pub fn detect() -> Self {
+        if env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+            return ColorDepth::Monochrome;
+        }
+        if let Ok("truecolor" | "24bit") = env::var("COLORTERM").as_deref() {
+            return ColorDepth::TrueColor;
+        }
+
+        let term = match env::var("TERM") {
+            Ok(term) if !term.is_empty() && term != "dumb" => term,
+            _ => return ColorDepth::Monochrome,
+        };
+        if term.ends_with("-direct") {
+            return ColorDepth::TrueColor;
+        }
+
+        match terminfo_colors(&term) {
+            Some(n) if n >= 1 << 24 => ColorDepth::TrueColor,
+            Some(n) if n >= 256 => ColorDepth::Ansi256,
+            Some(n) if n >= 8 => ColorDepth::Ansi16,
+            Some(_) => ColorDepth::Monochrome,
+            // no terminfo to go by, most terminals understand 256 colors
+            None if term.contains("256color") => ColorDepth::Ansi256,
+            None => ColorDepth::Ansi16,
+        }
+    }
+
+    /// The closest colors to `color` that the terminal can show
+    pub fn pen(self, color: CellColor) -> Pen {
+        let paint = |rgb: Rgb| match self {
+            ColorDepth::TrueColor => Some(Paint::Rgb(rgb)),
+            ColorDepth::Ansi256 => Some(Paint::Palette(nearest_256(rgb))),
+            ColorDepth::Ansi16 => Some(Paint::Basic(nearest(&BASIC_COLORS, rgb))),
+            ColorDepth::Monochrome => None,
+        };
+
+        Pen {
+            fg: color.fg.and_then(paint),
+            bg: color.bg.and_then(paint),
+        }
+    }
+}
+
+/// Squared distance between two colors
+fn distance(a: Rgb, b: Rgb) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.r, b.r) + d(a.g, b.g) + d(a.b, b.b)
+}
+
+/// Index of the color in `palette` closest to `rgb`
+fn nearest(palette: &[Rgb], rgb: Rgb) -> u8 {
+    (0..palette.len())
+        .min_by_key(|&i| distance(palette[i], rgb))
+        .unwrap_or(0) as u8
+}
+
+/// Index of the closest color in the xterm 256-color palette, out of its
+/// 6x6x6 color cube (16-231) and 24 step grayscale ramp (232-255)
+fn nearest_256(rgb: Rgb) -> u8 {
+    let level = |v: u8| {
+        (0..CUBE_LEVELS.len())
+            .min_by_key(|&i| CUBE_LEVELS[i].abs_diff(v))
+            .unwrap_or(0) as u8
+    };
+    let (r, g, b) = (level(rgb.r), level(rgb.g), level(rgb.b));
+    let cube = Rgb::new(
+        CUBE_LEVELS[r as usize],
+        CUBE_LEVELS[g as usize],
+        CUBE_LEVELS[b as usize],
+    );
+
+    // grays run from 8 to 238 in steps of 10
+    let avg = (rgb.r as u32 + rgb.g as u32 + rgb.b as u32) / 3;
+    let step = (avg.saturating_sub(3) / 10).min(23) as u8;
+    let gray = 8 + 10 * step;
+
+    if distance(Rgb::new(gray, gray, gray), rgb) < distance(cube, rgb) {
+        232 + step
+    } else {
+        16 + 36 * r + 6 * g + b
+    }
+}
+
+/// The `colors` capability of `term`'s compiled terminfo entry, if it
+/// can be found. Entries without the capability have no colors
+fn terminfo_colors(term: &str) -> Option<u32> {
+    let first = term.chars().next()?;
+
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    if let Some(dir) = env::var_os("TERMINFO") {
+        dirs.push(dir.into());
+    }
+    if let Some(home) = env::var_os("HOME") {
+        dirs.push(PathBuf::from(home).join(".terminfo"));
+    }
+    if let Ok(list) = env::var("TERMINFO_DIRS") {
+        dirs.extend(list.split(':').filter(|d| !d.is_empty()).map(PathBuf::from));
+    }
+    dirs.extend(["/etc/terminfo", "/lib/terminfo", "/usr/share/terminfo"].map(PathBuf::from));
+
+    // entries are sorted by their first letter, or its hex code on macOS
+    let data = dirs.iter().find_map(|dir| {
+        fs::read(dir.join(first.to_string()).join(term))
+            .or_else(|_| fs::read(dir.join(format!("{:x}", first as u32)).join(term)))
+            .ok()
+    })?;
+
+    // header of 6 little-endian u16s: magic, then the sizes of the names,
+    // booleans, numbers and strings sections
+    let u16_at = |i: usize| Some(u16::from_le_bytes([*data.get(i)?, *data.get(i + 1)?]));
+    let number_len = match u16_at(0)? {
+        0o432 => 2,
+        0o1036 => 4,
+        _ => return None,
+    };
+    let names_len = u16_at(2)? as usize;
+    let bools_len = u16_at(4)? as usize;
+    let numbers = u16_at(6)? as usize;
+    if numbers <= TERMINFO_COLORS {
+        return Some(0);
+    }
+
+    // the numbers section starts on an even byte
+    let start = (12 + names_len + bools_len).next_multiple_of(2);
+    let at = start + TERMINFO_COLORS * number_len;
+    let bytes = data.get(at..at + number_len)?;
+    let colors = match number_len {
+        2 => i16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+        _ => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    };
+
+    // negative values mark a missing capability
+    Some(u32::try_from(colors).unwrap_or(0))
+}
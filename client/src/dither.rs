@@ -0,0 +1,292 @@
+/// 4x4 Bayer threshold map, in sixteenths
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// How a grid of intensities is spread over the few levels of a character
+/// ramp (or the two states of a braille dot)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dithering {
+    /// every cell is quantized on its own, which bands smooth gradients
+    #[default]
+    None,
+    /// error diffusion, passing a cell's rounding error on to its right and
+    /// lower neighbors in 7/16, 3/16, 5/16 and 1/16 parts
+    FloydSteinberg,
+    /// error diffusion, passing 1/8 of a cell's rounding error on to each of
+    /// 6 neighbors. Drops the other 1/4, keeping more contrast
+    Atkinson,
+    /// ordered dithering with a 4x4 Bayer threshold map. Noisier than error
+    /// diffusion, but a cell only changes when its own intensity does,
+    /// which keeps video from shimmering
+    Bayer,
+}
+
+impl Dithering {
+    /// Quantize a grid of intensities (0.0 - 255.0), `w` cells wide and
+    /// stored row by row, to `levels` evenly spaced levels. Returns the
+    /// level (`0..levels`) of every cell
+    pub fn quantize(self, intensities: &[f32], w: usize, levels: usize) -> Vec<usize> {
+        if levels < 2 || w == 0 {
+            return vec![0; intensities.len()];
+        }
+
+        let max = (levels - 1) as f32;
+        // intensity between two neighboring levels
+        let step = 255.0 / max;
+        let nearest = |v: f32| (v / step).round().clamp(0.0, max) as usize;
+
+        match self {
+            Dithering::None => intensities
+                .iter()
+                .map(|v| ((v / 255.0 * levels as f32) as usize).min(levels - 1))
+                .collect(),
+            Dithering::Bayer => intensities
+                .iter()
+                .enumerate()
+                .map(|(i, v)| {
+                    let threshold = BAYER_4X4[(i / w) % 4][(i % w) % 4] as f32 / 16.0;
+                    // spread the threshold map evenly around each level
+                    nearest(v + (threshold - 15.0 / 32.0) * step)
+                })
+                .collect(),
+            Dithering::FloydSteinberg => Self::diffuse(
+                intensities,
+                w,
+                nearest,
+                step,
+                &[(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)],
+                16.0,
+            ),
+            Dithering::Atkinson => Self::diffuse(
+                intensities,
+                w,
+                nearest,
+                step,
+                &[
+                    (1, 0, 1.0),
+                    (2, 0, 1.0),
+                    (-1, 1, 1.0),
+                    (0, 1, 1.0),
+                    (1, 1, 1.0),
+                    (0, 2, 1.0),
+                ],
+                8.0,
+            ),
+        }
+    }
+
+    /// Intensities that `quantize` turns into `level` (of `levels`) before
+    /// any dithering. `None` splits the range into even bands, the others
+    /// round to the nearest of evenly spaced levels
+    pub fn level_range(self, level: usize, levels: usize) -> (f32, f32) {
+        if levels < 2 {
+            return (0.0, 255.0);
+        }
+
+        match self {
+            Dithering::None => {
+                let band = 255.0 / levels as f32;
+                (level as f32 * band, (level + 1) as f32 * band)
+            }
+            _ => {
+                let step = 255.0 / (levels - 1) as f32;
+                ((level as f32 - 0.5) * step, (level as f32 + 0.5) * step)
+            }
+        }
+    }
+
+    /// Error diffusion: quantize cells left to right, top to bottom, passing
+    /// each cell's rounding error on to the `(dx, dy, weight)` neighbors,
+    /// every one getting `weight / divisor` of it
+    fn diffuse(
+        intensities: &[f32],
+        w: usize,
+        nearest: impl Fn(f32) -> usize,
+        step: f32,
+        neighbors: &[(isize, usize, f32)],
+        divisor: f32,
+    ) -> Vec<usize> {
+        let h = intensities.len() / w;
+        let mut values = intensities.to_vec();
+        let mut out = vec![0; intensities.len()];
+
+        for y in 0..h {
+            for x in 0..w {
+                let i = y * w + x;
+                let level = nearest(values[i]);
+                let error = values[i] - level as f32 * step;
+                out[i] = level;
+
+                for &(dx, dy, weight) in neighbors {
+                    let (nx, ny) = (x as isize + dx, y + dy);
+                    if nx >= 0 && (nx as usize) < w && ny < h {
+                        values[ny * w + nx as usize] += error * weight / divisor;
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_frame::ImageFrame;
+
+    const RAMP: [char; 4] = [' ', '.', ':', '#'];
+
+    /// Horizontal gradient from black to white
+    fn gradient(w: usize, h: usize) -> ImageFrame {
+        let mut frame = ImageFrame::new(w, h, 3).unwrap();
+        for y in 0..h {
+            for x in 0..w {
+                let v = (x * 255 / (w - 1)) as u8;
+                let i = (y * w + x) * 3;
+                frame.buffer_mut()[i..i + 3].copy_from_slice(&[v, v, v]);
+            }
+        }
+        frame
+    }
+
+    /// Bright disc on a dark background, brightest at its center
+    fn disc(w: usize, h: usize) -> ImageFrame {
+        let mut frame = ImageFrame::new(w, h, 3).unwrap();
+        let (cx, cy, r) = (w as f32 / 2.0, h as f32 / 2.0, h as f32 / 2.0);
+        for y in 0..h {
+            for x in 0..w {
+                // cells are twice as tall as wide
+                let d = ((x as f32 + 0.5 - cx) / 2.0).hypot(y as f32 + 0.5 - cy) / r;
+                let v = (255.0 * (1.0 - d).clamp(0.0, 1.0)) as u8;
+                let i = (y * w + x) * 3;
+                frame.buffer_mut()[i..i + 3].copy_from_slice(&[v, v, v]);
+            }
+        }
+        frame
+    }
+
+    /// Dither `image` onto `RAMP`, one line of characters per row
+    fn render(image: &ImageFrame, dithering: Dithering) -> Vec<String> {
+        let intensities: Vec<f32> = (0..image.w * image.h)
+            .map(|i| {
+                ImageFrame::calculate_intensity(image.get_pixel(i % image.w, i / image.w).unwrap())
+            })
+            .collect();
+        let levels = dithering.quantize(&intensities, image.w, RAMP.len());
+
+        levels
+            .chunks(image.w)
+            .map(|row| row.iter().map(|&l| RAMP[l]).collect())
+            .collect()
+    }
+
+    #[test]
+    fn gradient_without_dithering_bands() {
+        assert_eq!(
+            render(&gradient(16, 2), Dithering::None),
+            ["    ....::::####", "    ....::::####"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn gradient_floyd_steinberg() {
+        assert_eq!(
+            render(&gradient(16, 4), Dithering::FloydSteinberg),
+            [
+                "   .....:::::###",
+                "  . ...:.:::#:##",
+                "  . ....:::::###",
+                "   ....:.:::#:##",
+            ]
+            .map(String::from)
+        );
+    }
+
+    #[test]
+    fn gradient_atkinson() {
+        assert_eq!(
+            render(&gradient(16, 4), Dithering::Atkinson),
+            [
+                "   .....:::::###",
+                "   .....:::::###",
+                "  .....:::::####",
+                "    .....:::::##",
+            ]
+            .map(String::from)
+        );
+    }
+
+    #[test]
+    fn gradient_bayer() {
+        assert_eq!(
+            render(&gradient(16, 4), Dithering::Bayer),
+            [
+                "   . ..:.::::#:#",
+                "  ....:.::::#:##",
+                "   ......::::#:#",
+                "  . ..:.::::####",
+            ]
+            .map(String::from)
+        );
+    }
+
+    #[test]
+    fn disc_floyd_steinberg() {
+        assert_eq!(
+            render(&disc(16, 6), Dithering::FloydSteinberg),
+            [
+                "       . .      ",
+                "    ...:....    ",
+                "   ..:::#::..   ",
+                "   ..::#:::..   ",
+                "    ....:...    ",
+                "      . .       ",
+            ]
+            .map(String::from)
+        );
+    }
+
+    #[test]
+    fn uniform_gray_keeps_its_average() {
+        // a flat 1/3 gray sits exactly on a level, so nothing is dithered
+        let flat = vec![85.0; 64];
+        for dithering in [
+            Dithering::None,
+            Dithering::FloydSteinberg,
+            Dithering::Atkinson,
+            Dithering::Bayer,
+        ] {
+            let levels = dithering.quantize(&flat, 8, RAMP.len());
+            assert!(levels.iter().all(|&l| l == 1), "{:?}", dithering);
+        }
+    }
+
+    #[test]
+    fn two_levels_threshold_at_half() {
+        let levels = Dithering::None.quantize(&[0.0, 127.0, 128.0, 255.0], 4, 2);
+        assert_eq!(levels, [0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn level_range_matches_quantize() {
+        // a single cell has no neighbors to pass errors to, and Bayer
+        // thresholds shift levels on purpose
+        for dithering in [
+            Dithering::None,
+            Dithering::FloydSteinberg,
+            Dithering::Atkinson,
+        ] {
+            for levels in [2, 4, 10] {
+                for v in 0..=510 {
+                    let v = v as f32 / 2.0;
+                    let level = dithering.quantize(&[v], 1, levels)[0];
+                    let (lo, hi) = dithering.level_range(level, levels);
+                    assert!(
+                        (lo..=hi).contains(&v),
+                        "{dithering:?} put {v} on level {level} of {levels}, covering {lo}..={hi}"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,9 +1,12 @@
 use crate::image_frame::ImageFrame;
+use crate::luminance::LuminanceModel;
+use rayon::prelude::*;
 use std::error::Error;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, mpsc};
 use std::thread;
+use std::time::{Duration, Instant};
 
-// TODO: Look into Robert's Cross operator as potential alternative (if slow performance)
 // TODO: Remove `.unwrap()`s in the future for error recovery
 // TODO: Allow user to influence data members
 
@@ -13,218 +16,496 @@ pub struct EdgeInfo {
     pub magnitude: Vec<f32>,
     /// the angle of an edge, if it exists
     pub angle: Vec<f32>,
+    /// whether a pixel is part of an edge, strong or linked to a strong one
+    /// by hysteresis
+    pub linked: Vec<bool>,
     /// the width of the camera it will receive image frames from
     pub w: usize,
     /// the height of the camera it will receive image frames from
     pub h: usize,
 }
 
+impl EdgeInfo {
+    /// Edge information of a `w` x `h` image without any edges
+    pub fn none(w: usize, h: usize) -> Self {
+        Self {
+            magnitude: vec![0.0; w * h],
+            angle: vec![0.0; w * h],
+            linked: vec![false; w * h],
+            w,
+            h,
+        }
+    }
+}
+
+/// Convolution kernels estimating the gradient of an image's intensity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GradientOperator {
+    /// 3x3 kernels weighting the middle row / column twice
+    #[default]
+    Sobel,
+    /// 3x3 kernels weighting the middle row / column 10:3, giving the most
+    /// accurate angles
+    Scharr,
+    /// 3x3 kernels weighting all rows / columns the same. Cheap, but more
+    /// sensitive to noise
+    Prewitt,
+    /// 2x2 kernels across the diagonals. Finds the finest detail, but is
+    /// the most sensitive to noise
+    RobertsCross,
+}
+
+impl GradientOperator {
+    /// Every operator at once, e.g. to compare them
+    pub const ALL: [GradientOperator; 4] = [
+        GradientOperator::Sobel,
+        GradientOperator::Scharr,
+        GradientOperator::Prewitt,
+        GradientOperator::RobertsCross,
+    ];
+
+    /// Weights of the outer and middle rows of a 3x3 kernel's
+    /// `[-1, 0, 1]` columns, `None` for operators that aren't 3x3
+    fn kernel_weights(self) -> Option<(f32, f32)> {
+        match self {
+            GradientOperator::Sobel => Some((1.0, 2.0)),
+            GradientOperator::Scharr => Some((3.0, 10.0)),
+            GradientOperator::Prewitt => Some((1.0, 1.0)),
+            GradientOperator::RobertsCross => None,
+        }
+    }
+}
+
+/// Direction of an edge's gradient, binned into the four families of edge
+/// characters. Edges run across their gradient
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeDirection {
+    /// gradient to the left / right, the edge runs up and down (`|`)
+    Horizontal,
+    /// gradient to the lower right / upper left, the edge runs like `/`
+    Forward,
+    /// gradient up / down, the edge runs sideways (`-`)
+    Vertical,
+    /// gradient to the upper right / lower left, the edge runs like `\`
+    Back,
+}
+
+impl EdgeDirection {
+    /// Bin a gradient angle, in radians as given by `atan2(gy, gx)` with
+    /// y pointing down the image
+    pub fn of(angle: f32) -> Self {
+        // gradients pointing in opposite directions are the same edge
+        let degrees = angle.to_degrees().rem_euclid(180.0);
+
+        if !(22.5..157.5).contains(&degrees) {
+            EdgeDirection::Horizontal
+        } else if degrees < 67.5 {
+            EdgeDirection::Forward
+        } else if degrees < 112.5 {
+            EdgeDirection::Vertical
+        } else {
+            EdgeDirection::Back
+        }
+    }
+}
+
+/// How edges are found and told apart from noise, following the Canny edge
+/// detector
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EdgeSettings {
+    /// Kernels estimating the gradient
+    pub operator: GradientOperator,
+    /// Standard deviation (in pixels) of the Gaussian blur applied before
+    /// looking for edges, smoothing out sensor noise. `0.0` disables it
+    pub blur_sigma: f32,
+    /// Gradient magnitude below which nothing is an edge.
+    /// Operates from 0.0 to 255.0
+    pub low_threshold: f32,
+    /// Gradient magnitude from which on something is an edge. Edges between
+    /// the thresholds are only kept where they connect to one this strong.
+    /// Operates from 0.0 to 255.0
+    pub high_threshold: f32,
+    /// How the intensities edges are found in are computed, the same model
+    /// the characters are picked with
+    pub luminance: LuminanceModel,
+}
+
+impl EdgeSettings {
+    pub const DEFAULT_BLUR_SIGMA: f32 = 1.0;
+    pub const DEFAULT_LOW_THRESHOLD: f32 = 63.75;
+    pub const DEFAULT_HIGH_THRESHOLD: f32 = 127.5;
+}
+
+impl Default for EdgeSettings {
+    fn default() -> Self {
+        Self {
+            operator: GradientOperator::Sobel,
+            blur_sigma: Self::DEFAULT_BLUR_SIGMA,
+            low_threshold: Self::DEFAULT_LOW_THRESHOLD,
+            high_threshold: Self::DEFAULT_HIGH_THRESHOLD,
+            luminance: LuminanceModel::default(),
+        }
+    }
+}
+
+/// Buffers the edge detection thread reuses from frame to frame
+#[derive(Default)]
+struct Scratch {
+    /// grayscale intensity of every pixel
+    intensity: Vec<f32>,
+    /// intensities blurred horizontally, halfway through the Gaussian blur
+    blurred: Vec<f32>,
+    /// gradient magnitude of every pixel, before non-maximum suppression
+    gradient: Vec<f32>,
+    /// pixels `hysteresis` still has to follow edges from
+    pending: Vec<usize>,
+}
+
 /// Thread that processes given `ImageFrames` using our edge detection methods
-/// and returns that information to apply it to the final `AsciiFrame`
+/// and returns that information to apply it to the final `AsciiFrame`.
+///
+/// Frames are handed to the thread over a channel, tagged with a sequence
+/// number, and their results come back the same way. At most one frame
+/// waits while the thread works on another, later frames are skipped until
+/// it's free, so a slow thread never falls behind by more than a frame. The
+/// thread sleeps while there is none.
 pub struct EdgeDetector {
-    /// The edge magnitudes and angles of the latest processed `ImageFrame`.
-    edge_info: Arc<Mutex<EdgeInfo>>,
-    /// The raw image data of the latest processed `ImageFrame`
-    frame_buffer: Arc<Mutex<Vec<u8>>>,
-    /// Flag, indicates to `EdgeDetector` that there is a new `ImageFrame`
-    /// loaded in `frame_buffer`
-    new_frame_available: Arc<Mutex<bool>>,
-    /// Minimum gradient magnitude threshold.
-    /// Operates from 0.0 to 255.0
-    threshold: f32,
-    /// Control flag, will terminate the edge detection thread when `false`
-    running: Arc<Mutex<bool>>,
+    /// width of the `ImageFrame`s it receives
+    w: usize,
+    /// height of the `ImageFrame`s it receives
+    h: usize,
+    /// Blur and thresholds separating edges from noise
+    settings: EdgeSettings,
+    /// Raw image data of submitted `ImageFrame`s, sent to the edge detection
+    /// thread along with their sequence number, holding one frame at most.
+    /// Dropping it tells the thread to finish
+    frames_tx: Option<mpsc::SyncSender<(u64, Vec<u8>)>>,
+    /// Edge information of processed `ImageFrame`s, along with the sequence
+    /// number they were submitted with
+    results_rx: Option<Mutex<mpsc::Receiver<(u64, EdgeInfo)>>>,
+    /// Sequence number of the next submitted `ImageFrame`
+    next_seq: AtomicU64,
+    /// The edge detection thread, while it runs
+    handle: Option<thread::JoinHandle<()>>,
 }
 
 impl EdgeDetector {
-    /// default `threshold` value if none is provided
-    pub const DEFAULT_EDGE_THRESHOLD: f32 = 20.0;
+    /// Edge detector for `w` x `h` frames. Thresholds given the wrong way
+    /// round are swapped
+    pub fn new(w: usize, h: usize, settings: EdgeSettings) -> Self {
+        let settings = EdgeSettings {
+            operator: settings.operator,
+            blur_sigma: settings.blur_sigma.max(0.0),
+            low_threshold: settings.low_threshold.min(settings.high_threshold),
+            high_threshold: settings.low_threshold.max(settings.high_threshold),
+            luminance: settings.luminance,
+        };
 
-    pub fn new(w: usize, h: usize, threshold: f32) -> Self {
-        let edge_info = Arc::new(Mutex::new(EdgeInfo {
-            magnitude: vec![0.0; w * h],
-            angle: vec![0.0; w * h],
+        Self {
             w,
             h,
-        }));
-
-        let frame_buffer = Arc::new(Mutex::new(Vec::<u8>::with_capacity(w * h * 3)));
-        let new_frame_available = Arc::new(Mutex::new(false));
-        let running = Arc::new(Mutex::new(true));
-
-        Self {
-            edge_info,
-            frame_buffer,
-            new_frame_available,
-            threshold,
-            running,
+            settings,
+            frames_tx: None,
+            results_rx: None,
+            next_seq: AtomicU64::new(0),
+            handle: None,
         }
     }
 
     /// Launches the edge detection processing thread.
     ///
-    /// This processing thread continuously checks for new frames produced by
-    /// a camera, then processes them with various algorithms to obtain
-    /// edge information. Communication between threads is enforced by
-    /// shared state protected with mutexes.
-    ///
-    /// # Returns
-    ///
-    /// `JoinHandle` for the edge detection processing thread, to manage
-    /// or complete its lifetime.
-    pub fn start(
-        &self,
-        cam_w: usize,
-        cam_h: usize,
-    ) -> Result<thread::JoinHandle<()>, Box<dyn Error>> {
-        let edge_info = Arc::clone(&self.edge_info);
-        let frame_buffer = Arc::clone(&self.frame_buffer);
-        let new_frame_flag = Arc::clone(&self.new_frame_available);
-        let running = Arc::clone(&self.running);
-        let threshold = self.threshold;
-
-        let handle = thread::spawn(move || {
-            while *running.lock().unwrap() {
-                let process_frame = {
-                    let mut flag = new_frame_flag.lock().unwrap();
-                    let should_proc = *flag;
-                    *flag = false;
-                    should_proc
-                };
-
-                if process_frame {
-                    let frame_data = frame_buffer.lock().unwrap().clone();
+    /// The thread waits for frames from `submit_frame`, processes them with
+    /// various algorithms to obtain edge information, and sends that back
+    /// to be picked up by `wait_for_edges`. It runs until `stop` is called
+    /// or the `EdgeDetector` is dropped.
+    pub fn start(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.handle.is_some() {
+            return Err("edge detector is already running".into());
+        }
 
-                    let temp_frame = ImageFrame {
-                        w: cam_w,
-                        h: cam_h,
+        let (frames_tx, frames_rx) = mpsc::sync_channel::<(u64, Vec<u8>)>(1);
+        let (results_tx, results_rx) = mpsc::channel();
+        let (w, h, settings) = (self.w, self.h, self.settings);
+        let kernel = Self::gaussian_kernel(settings.blur_sigma);
+
+        let handle = thread::Builder::new()
+            .name("edge-detector".into())
+            .spawn(move || {
+                let mut scratch = Scratch::default();
+                // ends once `frames_tx` is dropped
+                for (seq, buffer) in frames_rx {
+                    let frame = ImageFrame {
+                        w,
+                        h,
                         bytes_per_pixel: 3,
-                        buffer: frame_data,
+                        buffer,
                     };
 
-                    if let Ok((magnitude, angle)) = Self::process_frame(&temp_frame, threshold) {
-                        let mut info = edge_info.lock().unwrap();
-                        info.magnitude = magnitude;
-                        info.angle = angle;
+                    // a result is always sent, so nobody waits for it in vain
+                    let info = Self::process_frame(&frame, settings, &kernel, &mut scratch)
+                        .unwrap_or_else(|_| EdgeInfo::none(w, h));
+                    if results_tx.send((seq, info)).is_err() {
+                        break;
                     }
-                } else {
-                    //thread::sleep(Duration::from_millis(5));
                 }
-            }
-        });
+            })?;
 
-        Ok(handle)
+        self.frames_tx = Some(frames_tx);
+        self.results_rx = Some(Mutex::new(results_rx));
+        self.handle = Some(handle);
+        Ok(())
     }
 
     /// Utilized by the main program thread to send video frames to
-    /// the edge detection thread to be processed
-    pub fn submit_frame(&self, frame: &ImageFrame) -> Result<(), Box<dyn Error>> {
-        let mut buffer = self.frame_buffer.lock().unwrap();
-
-        buffer.clear();
-        buffer.extend_from_slice(frame.buffer());
+    /// the edge detection thread to be processed. Returns the sequence
+    /// number to pass to `wait_for_edges` for the frame's result, or `None`
+    /// if the frame was skipped because another one is still waiting
+    pub fn submit_frame(&self, frame: &ImageFrame) -> Result<Option<u64>, Box<dyn Error>> {
+        if frame.w != self.w || frame.h != self.h || frame.bytes_per_pixel != 3 {
+            return Err(format!(
+                "edge detector expects {}x{} RGB frames, got {}x{}",
+                self.w, self.h, frame.w, frame.h
+            )
+            .into());
+        }
+        let frames_tx = self
+            .frames_tx
+            .as_ref()
+            .ok_or("edge detector is not running")?;
+
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        match frames_tx.try_send((seq, frame.buffer().to_vec())) {
+            Ok(()) => Ok(Some(seq)),
+            Err(mpsc::TrySendError::Full(_)) => Ok(None),
+            Err(mpsc::TrySendError::Disconnected(_)) => Err("edge detection thread stopped".into()),
+        }
+    }
 
-        let mut flag = self.new_frame_available.lock().unwrap();
-        *flag = true;
+    /// Wait up to `timeout` for the edge information of the frame submitted
+    /// as `seq`, skipping results of earlier frames nobody waited for.
+    /// Returns `None` if it isn't ready in time
+    pub fn wait_for_edges(
+        &self,
+        seq: u64,
+        timeout: Duration,
+    ) -> Result<Option<EdgeInfo>, Box<dyn Error>> {
+        let results_rx = self
+            .results_rx
+            .as_ref()
+            .ok_or("edge detector is not running")?
+            .lock()
+            .map_err(|_| "edge detector results poisoned")?;
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match results_rx.recv_timeout(remaining) {
+                Ok((done, info)) if done == seq => return Ok(Some(info)),
+                // a frame whose result came too late
+                Ok(_) => {}
+                Err(mpsc::RecvTimeoutError::Timeout) => return Ok(None),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err("edge detection thread stopped".into());
+                }
+            }
+        }
+    }
 
-        Ok(())
+    /// Stop the edge detection thread, waiting for it to finish the frame
+    /// it is working on
+    pub fn stop(&mut self) {
+        self.frames_tx = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        self.results_rx = None;
     }
 
-    /// Using the Sobel operator, processes an image frame fo edge detection
-    /// after retrieving the grayscale intensity map
+    /// Processes an image frame for edge detection the way the Canny edge
+    /// detector does: the grayscale intensity map is blurred with `kernel`,
+    /// the chosen gradient operator finds gradients, non-maximum suppression
+    /// thins them, and hysteresis drops weak edges that lead nowhere. All but
+    /// the last step work on rows of the image in parallel
     fn process_frame(
         frame: &ImageFrame,
-        threshold: f32,
-    ) -> Result<(Vec<f32>, Vec<f32>), Box<dyn Error>> {
-        let intensity = Self::create_intensity_map(frame);
-        let (gx, gy) = Self::sobel(&intensity, frame.w, frame.h);
-
-        let mut magnitude = vec![0.0; frame.w * frame.h];
-        let mut angle = vec![0.0; frame.w * frame.h];
-
-        // for each pixel...
-        for i in 0..gx.len() {
-            // get the strength / intensity of the edge
-            magnitude[i] = (gx[i] * gx[i] + gy[i] * gy[i]).sqrt();
-            // get the direction of the edge
-            angle[i] = gy[i].atan2(gx[i]);
+        settings: EdgeSettings,
+        kernel: &[f32],
+        scratch: &mut Scratch,
+    ) -> Result<EdgeInfo, Box<dyn Error>> {
+        let (w, h) = (frame.w, frame.h);
+        if frame.buffer().len() < w * h * frame.bytes_per_pixel || frame.bytes_per_pixel < 3 {
+            return Err("image frame buffer too small".into());
         }
 
-        // thin edges & remove edges that are most likely just noise
-        let magnitude =
-            Self::non_maximum_suppression(&magnitude, &angle, frame.w, frame.h, threshold);
+        scratch.intensity.resize(w * h, 0.0);
+        scratch.gradient.resize(w * h, 0.0);
+        let mut angle = vec![0.0; w * h];
 
-        Ok((magnitude, angle))
-    }
+        Self::create_intensity_map(frame, settings.luminance, &mut scratch.intensity);
+        if kernel.len() > 1 {
+            Self::gaussian_blur(&mut scratch.intensity, &mut scratch.blurred, w, h, kernel);
+        }
+        Self::gradients(
+            &scratch.intensity,
+            w,
+            h,
+            settings.operator,
+            &mut scratch.gradient,
+            &mut angle,
+        );
 
-    /// Retrieve the edge information from the `EdgeDetector`
-    pub fn get_edge_info(&self) -> Result<EdgeInfo, Box<dyn Error>> {
-        let edge_info = self.edge_info.lock().unwrap();
+        // thin edges & remove edges that are most likely just noise
+        let mut magnitude =
+            Self::non_maximum_suppression(&scratch.gradient, &angle, w, h, settings.low_threshold);
+        let linked = Self::hysteresis(&mut magnitude, w, h, settings.high_threshold, scratch);
 
         Ok(EdgeInfo {
-            magnitude: edge_info.magnitude.clone(),
-            angle: edge_info.angle.clone(),
-            w: edge_info.w,
-            h: edge_info.h,
+            magnitude,
+            angle,
+            linked,
+            w,
+            h,
         })
     }
 
-    pub fn stop(&self) {
-        let mut running = self.running.lock().unwrap();
-        *running = false;
-    }
-
     /// Extracts intensity values from an RGB image to be used
-    /// for edge detection
-    fn create_intensity_map(frame: &ImageFrame) -> Vec<f32> {
-        let mut intensity = vec![0.0; frame.w * frame.h];
-
-        for y in 0..frame.h {
-            for x in 0..frame.w {
-                if let Some((r, g, b)) = frame.get_pixel(x, y) {
-                    let gray = ImageFrame::calculate_intensity((r, g, b));
-                    intensity[y * frame.w + x] = gray;
+    /// for edge detection, computed by `luminance`, one row at a time
+    fn create_intensity_map(frame: &ImageFrame, luminance: LuminanceModel, intensity: &mut [f32]) {
+        let bpp = frame.bytes_per_pixel;
+
+        intensity
+            .par_chunks_mut(frame.w)
+            .zip(frame.buffer().par_chunks(frame.w * bpp))
+            .for_each(|(out, pixels)| {
+                for (gray, px) in out.iter_mut().zip(pixels.chunks_exact(bpp)) {
+                    *gray = luminance.intensity((px[0], px[1], px[2]));
                 }
-            }
+            });
+    }
+
+    /// Normalized 1D Gaussian kernel with standard deviation `sigma`,
+    /// reaching out 3 `sigma`s to either side of its center
+    fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+        if sigma <= 0.0 {
+            return vec![1.0];
         }
 
+        let radius = (sigma * 3.0).ceil() as usize;
+        let kernel: Vec<f32> = (0..=radius * 2)
+            .map(|i| {
+                let d = i as f32 - radius as f32;
+                (-d * d / (2.0 * sigma * sigma)).exp()
+            })
+            .collect();
+        let sum: f32 = kernel.iter().sum();
+
+        kernel.into_iter().map(|k| k / sum).collect()
+    }
+
+    /// Blurs a `w` x `h` matrix of intensities in place with a Gaussian
+    /// `kernel`, first along rows into `blurred`, then along columns back
+    /// into `intensity`. Past the border, the nearest edge pixel repeats
+    fn gaussian_blur(
+        intensity: &mut [f32],
+        blurred: &mut Vec<f32>,
+        w: usize,
+        h: usize,
+        kernel: &[f32],
+    ) {
+        let radius = kernel.len() / 2;
+        blurred.resize(w * h, 0.0);
+
+        blurred
+            .par_chunks_mut(w)
+            .zip(intensity.par_chunks(w))
+            .for_each(|(out, row)| {
+                for (x, out) in out.iter_mut().enumerate() {
+                    *out = kernel
+                        .iter()
+                        .enumerate()
+                        .map(|(k, weight)| row[(x + k).saturating_sub(radius).min(w - 1)] * weight)
+                        .sum();
+                }
+            });
+
         intensity
+            .par_chunks_mut(w)
+            .enumerate()
+            .for_each(|(y, out)| {
+                out.fill(0.0);
+                for (k, weight) in kernel.iter().enumerate() {
+                    let src = (y + k).saturating_sub(radius).min(h - 1);
+                    for (out, v) in out.iter_mut().zip(&blurred[src * w..(src + 1) * w]) {
+                        *out += v * weight;
+                    }
+                }
+            });
     }
 
-    /// Applies the Sobel operator to a matrix containing the intensities of
-    /// a processed `ImageFrame`. This is utilized for edge detection in the
-    /// image.
+    /// Applies a gradient `operator` to a matrix containing the
+    /// intensities of a processed `ImageFrame`. This is utilized for edge
+    /// detection in the image.
     ///
-    /// The Sobel kernels are defined as follows:
-    /// - `Gx = [[-1, 0, 1], [-2, 0, 2], [-1, 0, 1]]`
-    /// - `Gy = [[-1, -2, -1], [0, 0, 0], [1, 2, 1]]`
-    fn sobel(intensity: &[f32], w: usize, h: usize) -> (Vec<f32>, Vec<f32>) {
-        let mut gx = vec![0.0; w * h];
-        let mut gy = vec![0.0; w * h];
-
-        for y in 1..(h - 1) {
-            for x in 1..(w - 1) {
-                let i = y * w + x;
-
-                // skipping over entries w/ 0 due to initialization
-                gx[i] = -1.0 * intensity[(y - 1) * w + (x - 1)] + // Gx(0,0)
-                        1.0 * intensity[(y - 1) * w + (x + 1)] +  // Gx(0,2)
-                        -2.0 * intensity[y * w + (x - 1)] +       // Gx(1,0)
-                        2.0 * intensity[y * w + (x + 1)] +        // Gx(1,2)
-                        -1.0 * intensity[(y + 1) * w + (x - 1)] + // Gx(2,0)
-                        1.0 * intensity[(y + 1) * w + (x + 1)]; // Gx(2,2)
-
-                // ditto
-                gy[i] = -1.0 * intensity[(y - 1) * w + (x - 1)] + // Gy(0,0)
-                        -2.0 * intensity[(y - 1) * w + x] +       // Gy(0,1)
-                        -1.0 * intensity[(y - 1) * w + (x + 1)] + // Gy(0,2)
-                        1.0 * intensity[(y + 1) * w + (x - 1)] +  // Gy(2,0)
-                        2.0 * intensity[(y + 1) * w + x] +        // Gy(2,1)
-                        1.0 * intensity[(y + 1) * w + (x + 1)]; // Gy(2,2)
-            }
-        }
+    /// Writes the strength / intensity of every pixel's edge to
+    /// `magnitude` and its direction to `angle`, both 0 along the border
+    fn gradients(
+        intensity: &[f32],
+        w: usize,
+        h: usize,
+        operator: GradientOperator,
+        magnitude: &mut [f32],
+        angle: &mut [f32],
+    ) {
+        magnitude
+            .par_chunks_mut(w)
+            .zip(angle.par_chunks_mut(w))
+            .enumerate()
+            .for_each(|(y, (magnitude, angle))| {
+                magnitude.fill(0.0);
+                angle.fill(0.0);
+                if y == 0 || y + 1 >= h || w < 3 {
+                    return;
+                }
+
+                let above = &intensity[(y - 1) * w..y * w];
+                let row = &intensity[y * w..(y + 1) * w];
+                let below = &intensity[(y + 1) * w..(y + 2) * w];
+                let mut store = |x: usize, gx: f32, gy: f32| {
+                    magnitude[x] = (gx * gx + gy * gy).sqrt();
+                    angle[x] = gy.atan2(gx);
+                };
 
-        (gx, gy)
+                match operator.kernel_weights() {
+                    Some((outer, middle)) => {
+                        // scales the gradient to what Sobel would find
+                        let scale = 4.0 / (2.0 * outer + middle);
+
+                        for x in 1..(w - 1) {
+                            // the middle column / row of each kernel is all 0s
+                            let gx = outer * (above[x + 1] - above[x - 1])
+                                + middle * (row[x + 1] - row[x - 1])
+                                + outer * (below[x + 1] - below[x - 1]);
+                            let gy = outer * (below[x - 1] - above[x - 1])
+                                + middle * (below[x] - above[x])
+                                + outer * (below[x + 1] - above[x + 1]);
+
+                            store(x, gx * scale, gy * scale);
+                        }
+                    }
+                    None => {
+                        for x in 1..(w - 1) {
+                            // differences across the diagonals of the 2x2
+                            // block right of and below the pixel...
+                            let falling = below[x + 1] - row[x];
+                            let rising = row[x + 1] - below[x];
+
+                            // ...turned back to x / y, scaled to what Sobel
+                            // would find
+                            store(x, 2.0 * (falling + rising), 2.0 * (falling - rising));
+                        }
+                    }
+                }
+            });
     }
 
     /// Performs non-maximum suppression on a gradient magnitude to thin edges.
@@ -243,55 +524,326 @@ impl EdgeDetector {
         threshold: f32,
     ) -> Vec<f32> {
         let mut result = vec![0.0; w * h];
+        if w < 3 || h < 3 {
+            return result;
+        }
+
+        result
+            .par_chunks_mut(w)
+            .enumerate()
+            .skip(1)
+            .take(h - 2)
+            .for_each(|(y, result)| {
+                for (x, out) in result.iter_mut().enumerate().take(w - 1).skip(1) {
+                    let i = y * w + x;
+
+                    // below magnitude? weak edge, skip
+                    if magnitude[i] < threshold {
+                        continue;
+                    }
 
-        for y in 1..(h - 1) {
-            for x in 1..(w - 1) {
-                let i = y * w + x;
+                    // neighbors along the gradient, always inside the image
+                    let (n1, n2) = match EdgeDirection::of(angle[i]) {
+                        EdgeDirection::Horizontal => (i + 1, i - 1),
+                        EdgeDirection::Forward => (i + w + 1, i - w - 1),
+                        EdgeDirection::Vertical => (i + w, i - w),
+                        EdgeDirection::Back => (i - w + 1, i + w - 1),
+                    };
 
-                // below magnitude? weak edge, skip
-                if magnitude[i] < threshold {
-                    continue;
+                    // Keep only local maxima
+                    if magnitude[i] >= magnitude[n1] && magnitude[i] >= magnitude[n2] {
+                        *out = magnitude[i];
+                    }
                 }
+            });
 
-                // normalize to 0-180 degrees
-                let angle_deg = (angle[i].to_degrees() + 180.0) % 180.0;
-
-                let (nx1, ny1, nx2, ny2) = if (angle_deg >= 0.0 && angle_deg < 22.5)
-                    || (angle_deg >= 157.5 && angle_deg < 180.0)
-                {
-                    // horizontal edge
-                    (x + 1, y, x - 1, y)
-                } else if angle_deg >= 22.5 && angle_deg < 67.5 {
-                    // forward edge (/)
-                    (x + 1, y - 1, x - 1, y + 1)
-                } else if angle_deg >= 67.5 && angle_deg < 112.5 {
-                    // vertical edge
-                    (x, y - 1, x, y + 1)
-                } else {
-                    // back edge (\)
-                    (x - 1, y - 1, x + 1, y + 1)
-                };
-
-                // compare with neighboring values
-                let n1 = if nx1 < w && ny1 < h {
-                    magnitude[ny1 * w + nx1]
-                } else {
-                    0.0
-                };
+        result
+    }
 
-                let n2 = if nx2 < w && ny2 < h {
-                    magnitude[ny2 * w + nx2]
-                } else {
-                    0.0
-                };
+    /// Double-threshold hysteresis on thinned edges: edges at least `high`
+    /// strong are kept, along with the weaker edges connected to them
+    /// (through any of their 8 neighbors). Everything else is dropped,
+    /// which removes the speckles sensor noise leaves behind. Returns which
+    /// pixels were kept
+    fn hysteresis(
+        magnitude: &mut [f32],
+        w: usize,
+        h: usize,
+        high: f32,
+        scratch: &mut Scratch,
+    ) -> Vec<bool> {
+        let mut linked = vec![false; w * h];
+        let pending = &mut scratch.pending;
+        pending.clear();
+
+        for (i, &m) in magnitude.iter().enumerate() {
+            if m >= high {
+                linked[i] = true;
+                pending.push(i);
+            }
+        }
 
-                // Keep only local maxima
-                if magnitude[i] >= n1 && magnitude[i] >= n2 {
-                    result[i] = magnitude[i];
+        // follow the strong edges into the weak ones they touch, which
+        // non-maximum suppression left above the low threshold
+        while let Some(i) = pending.pop() {
+            let (x, y) = (i % w, i / w);
+            for ny in y.saturating_sub(1)..(y + 2).min(h) {
+                for nx in x.saturating_sub(1)..(x + 2).min(w) {
+                    let n = ny * w + nx;
+                    if !linked[n] && magnitude[n] > 0.0 {
+                        linked[n] = true;
+                        pending.push(n);
+                    }
                 }
             }
         }
 
-        result
+        for (m, &linked) in magnitude.iter_mut().zip(linked.iter()) {
+            if !linked {
+                *m = 0.0;
+            }
+        }
+        linked
+    }
+}
+
+impl Drop for EdgeDetector {
+    fn drop(&mut self) {
+        // don't leave the edge detection thread running without an owner
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 32;
+
+    /// Gray image with the brightness `value` gives every pixel
+    fn gray(value: impl Fn(usize, usize) -> u8) -> ImageFrame {
+        let mut frame = ImageFrame::new(SIZE, SIZE, 3).unwrap();
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let v = value(x, y);
+                let i = (y * SIZE + x) * 3;
+                frame.buffer_mut()[i..i + 3].copy_from_slice(&[v, v, v]);
+            }
+        }
+        frame
+    }
+
+    /// Black and white image, white wherever `bright` holds
+    fn image(bright: impl Fn(usize, usize) -> bool) -> ImageFrame {
+        gray(|x, y| if bright(x, y) { 255 } else { 0 })
+    }
+
+    /// Edges of `frame` found with `operator` after a blur of `blur_sigma`,
+    /// with thresholds of 50.0 and 100.0 (steps of 12.5 and 25 intensities)
+    fn detect(frame: &ImageFrame, operator: GradientOperator, blur_sigma: f32) -> EdgeInfo {
+        let settings = EdgeSettings {
+            operator,
+            blur_sigma,
+            low_threshold: 50.0,
+            high_threshold: 100.0,
+            luminance: LuminanceModel::default(),
+        };
+        let kernel = EdgeDetector::gaussian_kernel(settings.blur_sigma);
+        EdgeDetector::process_frame(frame, settings, &kernel, &mut Scratch::default()).unwrap()
+    }
+
+    /// Edges of `frame` found with `operator`, without blurring
+    fn edges(frame: &ImageFrame, operator: GradientOperator) -> (Vec<f32>, Vec<f32>) {
+        let info = detect(frame, operator, 0.0);
+        (info.magnitude, info.angle)
+    }
+
+    /// Asserts every operator finds edges in `frame` and classifies all of
+    /// them as `expected`
+    fn assert_directions(frame: &ImageFrame, expected: EdgeDirection) {
+        for operator in GradientOperator::ALL {
+            let (magnitude, angle) = edges(frame, operator);
+            let directions: Vec<EdgeDirection> = magnitude
+                .iter()
+                .zip(&angle)
+                .filter(|(m, _)| **m > 0.0)
+                .map(|(_, a)| EdgeDirection::of(*a))
+                .collect();
+
+            assert!(!directions.is_empty(), "{operator:?} found no edges");
+            assert!(
+                directions.iter().all(|d| *d == expected),
+                "{operator:?} classified edges as {directions:?}, expected {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn vertical_step_has_horizontal_gradient() {
+        assert_directions(&image(|x, _| x >= SIZE / 2), EdgeDirection::Horizontal);
+        assert_directions(&image(|x, _| x < SIZE / 2), EdgeDirection::Horizontal);
+    }
+
+    #[test]
+    fn horizontal_step_has_vertical_gradient() {
+        assert_directions(&image(|_, y| y >= SIZE / 2), EdgeDirection::Vertical);
+        assert_directions(&image(|_, y| y < SIZE / 2), EdgeDirection::Vertical);
+    }
+
+    #[test]
+    fn diagonal_steps() {
+        // brighter towards the bottom right
+        assert_directions(&image(|x, y| x + y >= SIZE), EdgeDirection::Forward);
+        // brighter towards the top right
+        assert_directions(&image(|x, y| x >= y), EdgeDirection::Back);
+    }
+
+    #[test]
+    fn operators_agree_on_step_strength() {
+        let frame = image(|x, _| x >= SIZE / 2);
+        for operator in GradientOperator::ALL {
+            let (magnitude, _) = edges(&frame, operator);
+            let peak = magnitude.iter().copied().fold(0.0, f32::max);
+            assert!((peak - 1020.0).abs() < 1.0, "{operator:?} peaked at {peak}");
+        }
+    }
+
+    #[test]
+    fn weak_edge_linked_to_strong_one_is_kept() {
+        // a vertical step, strong in the top quarter and weak (between the
+        // thresholds) below it
+        let frame = gray(|x, y| match (x >= SIZE / 2, y < SIZE / 4) {
+            (false, _) => 0,
+            (true, true) => 255,
+            (true, false) => 20,
+        });
+        for operator in GradientOperator::ALL {
+            let info = detect(&frame, operator, 0.0);
+            for y in SIZE / 2..SIZE - 1 {
+                let row = &info.linked[y * SIZE..(y + 1) * SIZE];
+                assert!(
+                    row.iter().any(|&linked| linked),
+                    "{operator:?} dropped the weak edge in row {y}"
+                );
+            }
+            for (m, &linked) in info.magnitude.iter().zip(&info.linked) {
+                assert_eq!(*m > 0.0, linked, "{operator:?} kept an unlinked magnitude");
+            }
+        }
+    }
+
+    #[test]
+    fn isolated_weak_edge_is_dropped() {
+        let frame = gray(|x, _| if x >= SIZE / 2 { 20 } else { 0 });
+        for operator in GradientOperator::ALL {
+            let info = detect(&frame, operator, 0.0);
+            assert!(info.linked.iter().all(|&linked| !linked), "{operator:?}");
+            assert!(info.magnitude.iter().all(|&m| m == 0.0), "{operator:?}");
+        }
+    }
+
+    #[test]
+    fn gaussian_kernel_is_normalized_and_symmetric() {
+        assert_eq!(EdgeDetector::gaussian_kernel(0.0), vec![1.0]);
+        for sigma in [0.5, 1.0, 2.5] {
+            let kernel = EdgeDetector::gaussian_kernel(sigma);
+            assert_eq!(kernel.len() % 2, 1);
+            assert!(
+                (kernel.iter().sum::<f32>() - 1.0).abs() < 1e-5,
+                "sigma {sigma}"
+            );
+            assert!(kernel.iter().eq(kernel.iter().rev()), "sigma {sigma}");
+        }
+    }
+
+    #[test]
+    fn blur_keeps_flat_images_flat() {
+        let kernel = EdgeDetector::gaussian_kernel(1.5);
+        let mut intensity = vec![100.0; SIZE * SIZE];
+        EdgeDetector::gaussian_blur(&mut intensity, &mut Vec::new(), SIZE, SIZE, &kernel);
+        assert!(intensity.iter().all(|v| (v - 100.0).abs() < 1e-3));
+    }
+
+    #[test]
+    fn blur_removes_speckles() {
+        let frame = gray(|x, y| {
+            if (x, y) == (SIZE / 2, SIZE / 2) {
+                128
+            } else {
+                0
+            }
+        });
+        for operator in GradientOperator::ALL {
+            let sharp = detect(&frame, operator, 0.0);
+            assert!(sharp.linked.iter().any(|&linked| linked), "{operator:?}");
+
+            let blurred = detect(&frame, operator, 1.0);
+            assert!(blurred.linked.iter().all(|&linked| !linked), "{operator:?}");
+        }
+    }
+
+    #[test]
+    fn edges_follow_the_luminance_model() {
+        // green next to the gray BT.601 sees it as, which BT.709 sees as
+        // clearly darker than the green
+        let mut frame = ImageFrame::new(SIZE, SIZE, 3).unwrap();
+        for (i, px) in frame.buffer_mut().chunks_exact_mut(3).enumerate() {
+            let rgb = if i % SIZE >= SIZE / 2 {
+                [0, 255, 0]
+            } else {
+                [150, 150, 150]
+            };
+            px.copy_from_slice(&rgb);
+        }
+
+        let found = |luminance| {
+            let settings = EdgeSettings {
+                blur_sigma: 0.0,
+                luminance,
+                ..EdgeSettings::default()
+            };
+            let kernel = EdgeDetector::gaussian_kernel(settings.blur_sigma);
+            let info =
+                EdgeDetector::process_frame(&frame, settings, &kernel, &mut Scratch::default())
+                    .unwrap();
+            info.linked.iter().any(|&linked| linked)
+        };
+        assert!(!found(LuminanceModel::Bt601));
+        assert!(found(LuminanceModel::Bt709));
+    }
+
+    #[test]
+    fn slow_detection_skips_frames_instead_of_queueing_them() {
+        // large enough that every frame takes far longer than a submit
+        let (w, h) = (1024, 1024);
+        let frame = ImageFrame::new(w, h, 3).unwrap();
+        let mut detector = EdgeDetector::new(w, h, EdgeSettings::default());
+        detector.start().unwrap();
+
+        let mut skipped = 0;
+        for _ in 0..10 {
+            match detector.submit_frame(&frame).unwrap() {
+                Some(seq) => assert!(
+                    detector
+                        .wait_for_edges(seq, Duration::ZERO)
+                        .unwrap()
+                        .is_none()
+                ),
+                None => skipped += 1,
+            }
+        }
+        assert!(skipped > 0, "no frame was skipped");
+
+        // with nothing queued up behind it, the next frame's edges come back
+        let seq = loop {
+            if let Some(seq) = detector.submit_frame(&frame).unwrap() {
+                break seq;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        let edges = detector
+            .wait_for_edges(seq, Duration::from_secs(30))
+            .unwrap();
+        assert!(edges.is_some());
     }
 }
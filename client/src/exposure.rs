@@ -0,0 +1,354 @@
+use crate::image_frame::ImageFrame;
+use crate::luminance::LuminanceModel;
+use std::time::{Duration, Instant};
+
+/// How the intensities of a frame are spread over the whole range before it
+/// is drawn, so dim or washed out scenes still use every character
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Equalization {
+    /// intensities are left as the camera sees them
+    #[default]
+    None,
+    /// histogram equalization over the whole frame
+    Global,
+    /// contrast limited adaptive histogram equalization (CLAHE): every tile
+    /// of the frame is equalized on its own, with its histogram clipped so
+    /// flat regions don't turn into noise, and blended with its neighbors
+    Adaptive,
+}
+
+/// Mapping of every intensity of a frame to its equalized intensity, built
+/// from the frame's histograms (see `Equalization`)
+#[derive(Debug, Clone, Default)]
+pub struct ToneMap {
+    /// how intensities are computed
+    luminance: LuminanceModel,
+    /// width of the frame the map was built from
+    w: usize,
+    /// height of the frame the map was built from
+    h: usize,
+    /// amount of tiles across the frame
+    cols: usize,
+    /// amount of tiles down the frame
+    rows: usize,
+    /// lookup table of every tile, row by row. Empty if nothing is mapped
+    luts: Vec<[u8; 256]>,
+}
+
+impl ToneMap {
+    /// Tiles across and down a frame for `Equalization::Adaptive`
+    pub const TILES: usize = 8;
+    /// Most pixels of a tile a single intensity may hold, as a multiple of
+    /// a flat histogram, before the rest are spread over all intensities
+    pub const CLIP_LIMIT: f32 = 3.0;
+
+    /// Rebuild the map for `frame`, as `equalization` says, with
+    /// intensities computed by `luminance`
+    pub fn update(
+        &mut self,
+        equalization: Equalization,
+        luminance: LuminanceModel,
+        frame: &ImageFrame,
+    ) {
+        self.luminance = luminance;
+        let (cols, rows, clip_limit) = match equalization {
+            Equalization::None => {
+                self.luts.clear();
+                return;
+            }
+            Equalization::Global => (1, 1, None),
+            Equalization::Adaptive => (
+                Self::TILES.min(frame.w),
+                Self::TILES.min(frame.h),
+                Some(Self::CLIP_LIMIT),
+            ),
+        };
+        if frame.w == 0 || frame.h == 0 || frame.bytes_per_pixel < 3 {
+            self.luts.clear();
+            return;
+        }
+
+        let mut histograms = vec![[0u32; 256]; cols * rows];
+        for (i, px) in frame
+            .buffer()
+            .chunks_exact(frame.bytes_per_pixel)
+            .take(frame.w * frame.h)
+            .enumerate()
+        {
+            let (x, y) = (i % frame.w, i / frame.w);
+            let tile = (y * rows / frame.h) * cols + x * cols / frame.w;
+            let intensity = luminance.intensity((px[0], px[1], px[2]));
+            histograms[tile][intensity as usize] += 1;
+        }
+
+        self.w = frame.w;
+        self.h = frame.h;
+        self.cols = cols;
+        self.rows = rows;
+        self.luts.clear();
+        self.luts.extend(
+            histograms
+                .iter_mut()
+                .map(|histogram| Self::equalize(histogram, clip_limit)),
+        );
+    }
+
+    /// Lookup table spreading the intensities of `histogram` evenly, after
+    /// clipping it to `clip_limit` times a flat histogram
+    fn equalize(histogram: &mut [u32; 256], clip_limit: Option<f32>) -> [u8; 256] {
+        let total: u32 = histogram.iter().sum();
+        if let Some(clip_limit) = clip_limit {
+            let limit = ((clip_limit * total as f32 / 256.0) as u32).max(1);
+            let mut excess = 0;
+            for count in histogram.iter_mut() {
+                excess += count.saturating_sub(limit);
+                *count = (*count).min(limit);
+            }
+            // hand the clipped pixels back, evenly, with the remainder going
+            // to the darkest intensities
+            for (v, count) in histogram.iter_mut().enumerate() {
+                *count += excess / 256 + u32::from((v as u32) < excess % 256);
+            }
+        }
+
+        // the darkest intensity present maps to 0
+        let first = histogram
+            .iter()
+            .copied()
+            .find(|&count| count > 0)
+            .unwrap_or(0);
+        let mut lut = [0u8; 256];
+        let mut cdf = 0;
+        for (v, count) in histogram.iter().enumerate() {
+            cdf += count;
+            lut[v] = if total > first {
+                ((cdf.saturating_sub(first)) as f32 * 255.0 / (total - first) as f32).round() as u8
+            } else {
+                v as u8
+            };
+        }
+        lut
+    }
+
+    /// Equalized `intensity` (0.0 - 255.0) of the pixel at (`x`, `y`) of the
+    /// frame. Between the centers of tiles, their mappings are blended
+    pub fn map(&self, intensity: f32, x: f32, y: f32) -> f32 {
+        if self.luts.is_empty() {
+            return intensity;
+        }
+
+        // fractional tile position, measured between tile centers
+        let axis = |pos: f32, len: usize, tiles: usize| {
+            let t = (pos * tiles as f32 / len as f32 - 0.5).clamp(0.0, (tiles - 1) as f32);
+            let t0 = t as usize;
+            (t0, (t0 + 1).min(tiles - 1), t - t0 as f32)
+        };
+        let (x0, x1, ax) = axis(x, self.w, self.cols);
+        let (y0, y1, ay) = axis(y, self.h, self.rows);
+
+        let v = intensity.clamp(0.0, 255.0) as usize;
+        let lut = |col: usize, row: usize| self.luts[row * self.cols + col][v] as f32;
+        let top = lut(x0, y0) * (1.0 - ax) + lut(x1, y0) * ax;
+        let bottom = lut(x0, y1) * (1.0 - ax) + lut(x1, y1) * ax;
+        top * (1.0 - ay) + bottom * ay
+    }
+
+    /// Equalize the color `rgb` of the pixel at (`x`, `y`) of the frame,
+    /// scaling its channels alike to keep its hue
+    pub fn apply(&self, (r, g, b): (u8, u8, u8), x: f32, y: f32) -> (u8, u8, u8) {
+        if self.luts.is_empty() {
+            return (r, g, b);
+        }
+
+        let intensity = self.luminance.intensity((r, g, b));
+        let mapped = self.map(intensity, x, y);
+        if intensity < 1.0 {
+            let v = mapped as u8;
+            return (v, v, v);
+        }
+        let scale = |c: u8| (c as f32 * mapped / intensity).clamp(0.0, 255.0) as u8;
+        (scale(r), scale(g), scale(b))
+    }
+
+    /// Mean and standard deviation of the mapped intensities of `frame`,
+    /// measured on every `step`th pixel across and down
+    pub fn statistics(&self, frame: &ImageFrame, step: usize) -> (f32, f32) {
+        let step = step.max(1);
+        let (mut sum, mut sum_sq, mut n) = (0.0, 0.0, 0.0);
+        for y in (0..frame.h).step_by(step) {
+            for x in (0..frame.w).step_by(step) {
+                let Some(px) = frame.get_pixel(x, y) else {
+                    continue;
+                };
+                let (px_x, px_y) = (x as f32 + 0.5, y as f32 + 0.5);
+                let v = self.map(self.luminance.intensity(px), px_x, px_y);
+                sum += v;
+                sum_sq += v * v;
+                n += 1.0;
+            }
+        }
+        if n == 0.0 {
+            return (0.0, 0.0);
+        }
+
+        let mean = sum / n;
+        (mean, (sum_sq / n - mean * mean).max(0.0).sqrt())
+    }
+}
+
+/// Contrast and brightness that follow the scene, easing toward values that
+/// bring a frame's mean intensity to `target` and spread its intensities
+/// over a good part of the range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoExposure {
+    /// contrast in effect, see `AsciiConverter`
+    pub contrast: f32,
+    /// brightness in effect, see `AsciiConverter`
+    pub brightness: f32,
+    /// mean intensity (0.0 - 255.0) frames are brought to
+    target: f32,
+    /// when the exposure was last updated
+    updated_at: Option<Instant>,
+}
+
+impl AutoExposure {
+    /// Standard deviation of intensities (0.0 - 255.0) frames are brought to
+    pub const TARGET_DEVIATION: f32 = 64.0;
+    pub const MIN_CONTRAST: f32 = 0.5;
+    pub const MAX_CONTRAST: f32 = 4.0;
+    /// Time over which the exposure closes about 63% of the distance to
+    /// the values a frame asks for
+    pub const TIME_CONSTANT: Duration = Duration::from_millis(500);
+
+    /// Auto-exposure starting from `contrast` and `brightness`
+    pub fn new(target: f32, contrast: f32, brightness: f32) -> Self {
+        Self {
+            contrast,
+            brightness,
+            target: target.clamp(0.0, 255.0),
+            updated_at: None,
+        }
+    }
+
+    /// Move the exposure toward the values that fit a frame with intensities
+    /// of `mean` and standard deviation `deviation` (see
+    /// `ToneMap::statistics`), as far as the time since the last update
+    /// allows. The first update only starts the clock
+    pub fn update(&mut self, (mean, deviation): (f32, f32), now: Instant) {
+        let Some(updated_at) = self.updated_at.replace(now) else {
+            return;
+        };
+        let elapsed = now.saturating_duration_since(updated_at);
+
+        // contrast and brightness that give exactly the target:
+        // (v - 0.5) * contrast + 0.5 + brightness, on intensities of 0.0 - 1.0
+        let contrast = (Self::TARGET_DEVIATION / deviation.max(1.0))
+            .clamp(Self::MIN_CONTRAST, Self::MAX_CONTRAST);
+        let brightness = (self.target - 127.5 - (mean - 127.5) * contrast) / 255.0;
+
+        let rate = 1.0 - (-elapsed.as_secs_f32() / Self::TIME_CONSTANT.as_secs_f32()).exp();
+        self.contrast += (contrast - self.contrast) * rate;
+        self.brightness += (brightness - self.brightness) * rate;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `w` x `h` gray frame with the gray of pixel (`x`, `y`) given by
+    /// `gray`
+    fn frame(w: usize, h: usize, gray: impl Fn(usize, usize) -> u8) -> ImageFrame {
+        let mut frame = ImageFrame::new(w, h, 3).unwrap();
+        for (i, px) in frame.buffer_mut().chunks_exact_mut(3).enumerate() {
+            px.fill(gray(i % w, i / w));
+        }
+        frame
+    }
+
+    /// Intensity of a gray pixel
+    fn intensity(gray: u8) -> f32 {
+        LuminanceModel::default().intensity((gray, gray, gray))
+    }
+
+    fn tone_map(equalization: Equalization, frame: &ImageFrame) -> ToneMap {
+        let mut tone_map = ToneMap::default();
+        tone_map.update(equalization, LuminanceModel::default(), frame);
+        tone_map
+    }
+
+    #[test]
+    fn global_equalization_spreads_a_narrow_histogram() {
+        // grays 100 to 110, equally often
+        let dim = frame(11, 10, |x, _| 100 + x as u8);
+        let tone_map = tone_map(Equalization::Global, &dim);
+
+        assert_eq!(tone_map.map(intensity(100), 0.0, 0.0), 0.0);
+        assert_eq!(tone_map.map(intensity(110), 0.0, 0.0), 255.0);
+        let middle = tone_map.map(intensity(105), 0.0, 0.0);
+        assert!((middle - 127.5).abs() < 26.0, "{middle}");
+
+        let (_, before) = ToneMap::default().statistics(&dim, 1);
+        let (_, after) = tone_map.statistics(&dim, 1);
+        assert!(before < 4.0 && after > 64.0, "{before} -> {after}");
+    }
+
+    #[test]
+    fn clip_limit_caps_the_gain_of_flat_regions() {
+        // a flat gray with a single step of noise, tiles of 16x16 pixels
+        let flat = frame(128, 128, |x, y| 100 + ((x + y) % 2) as u8);
+        let (low, high) = (intensity(100), intensity(101));
+        let gain =
+            |tone_map: &ToneMap| tone_map.map(high, 64.0, 64.0) - tone_map.map(low, 64.0, 64.0);
+
+        // unclipped, the noise is stretched over the whole range
+        assert_eq!(gain(&tone_map(Equalization::Global, &flat)), 255.0);
+
+        // a flat histogram maps one step to one step. Clipped, a single
+        // intensity holds at most `CLIP_LIMIT` times that, plus its share
+        // of the excess
+        let gain = gain(&tone_map(Equalization::Adaptive, &flat));
+        assert!(gain > 0.0 && gain <= ToneMap::CLIP_LIMIT + 1.0, "{gain}");
+    }
+
+    #[test]
+    fn no_equalization_maps_nothing() {
+        let dim = frame(11, 10, |x, _| 100 + x as u8);
+        let tone_map = tone_map(Equalization::None, &dim);
+        assert_eq!(tone_map.map(103.0, 1.0, 1.0), 103.0);
+        assert_eq!(tone_map.apply((1, 2, 3), 1.0, 1.0), (1, 2, 3));
+    }
+
+    #[test]
+    fn auto_exposure_eases_toward_the_target() {
+        let start = Instant::now();
+        // a dark frame with a good spread: only brightness needs to change
+        let dark = (64.0, AutoExposure::TARGET_DEVIATION);
+        let brightness = 64.0 / 255.0;
+
+        let mut exposure = AutoExposure::new(128.0, 1.0, 0.0);
+        exposure.update(dark, start);
+        assert_eq!((exposure.contrast, exposure.brightness), (1.0, 0.0));
+
+        // about 63% of the way after one time constant
+        exposure.update(dark, start + AutoExposure::TIME_CONSTANT);
+        let expected = brightness * (1.0 - (-1.0f32).exp());
+        assert!(
+            (exposure.brightness - expected).abs() < 1e-4,
+            "{}",
+            exposure.brightness
+        );
+        assert!((exposure.contrast - 1.0).abs() < 1e-6);
+
+        // the same in many small steps, and nearly there after 5
+        let mut stepped = AutoExposure::new(128.0, 1.0, 0.0);
+        let step = AutoExposure::TIME_CONSTANT / 10;
+        for i in 0..=50 {
+            stepped.update(dark, start + step * i);
+            if i == 10 {
+                assert!((stepped.brightness - expected).abs() < 1e-4);
+            }
+        }
+        assert!((stepped.brightness - brightness).abs() < brightness * 0.01);
+    }
+}
@@ -6,7 +6,7 @@ use std::process::{Child, Command, Stdio};
 ///
 /// # Examples
 ///
-/// ```
+/// ```ignore
 /// let mut ffmpeg_proc = match setup_default() {
 ///     Ok(ffmpeg) => ffmpeg,
 ///     Err(err) => {
@@ -1,4 +1,4 @@
-use crate::ascii_converter::{B_LUMINANCE, G_LUMINANCE, R_LUMINANCE};
+use crate::luminance::{B_LUMINANCE, G_LUMINANCE, R_LUMINANCE};
 use std::error::Error;
 
 /// Initial frame received from webcam feed
@@ -53,7 +53,8 @@ impl ImageFrame {
     }
 
     /// Calculate the grayscale intensity value (relative luminance)
-    /// of a given pixel
+    /// of a given pixel, with BT.601 weights on its gamma-encoded channels
+    /// (see `LuminanceModel` for other ways)
     pub fn calculate_intensity((r, g, b): (u8, u8, u8)) -> f32 {
         R_LUMINANCE * r as f32 + G_LUMINANCE * g as f32 + B_LUMINANCE * b as f32
     }
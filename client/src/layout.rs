@@ -0,0 +1,217 @@
+use common::ascii_frame::AsciiFrame;
+use common::protocol::ParticipantId;
+use std::collections::BTreeMap;
+
+/// Characters used to draw the border around each tile
+const BORDER_HORIZONTAL: char = '─';
+const BORDER_VERTICAL: char = '│';
+const BORDER_TOP_LEFT: char = '┌';
+const BORDER_TOP_RIGHT: char = '┐';
+const BORDER_BOTTOM_LEFT: char = '└';
+const BORDER_BOTTOM_RIGHT: char = '┘';
+
+/// Shown in a tile until its participant's first frame arrives
+const WAITING_TEXT: &str = "waiting for video...";
+
+/// Picture-in-picture insets are this fraction of the screen's width...
+const INSET_FRACTION: usize = 4;
+/// ...but never narrower than this, so the label stays readable
+const INSET_MIN_WIDTH: usize = 16;
+
+/// Region of the screen assigned to a single participant's stream,
+/// including its border
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub id: ParticipantId,
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
+impl Tile {
+    /// Region inside the border, where the stream itself is drawn
+    pub fn inner(&self) -> (usize, usize, usize, usize) {
+        (
+            self.x + 1,
+            self.y + 1,
+            self.w.saturating_sub(2),
+            self.h.saturating_sub(2),
+        )
+    }
+}
+
+/// Tiles several participants' streams into a grid that fills the screen.
+///
+/// The amount of columns is chosen so that a stream with the expected
+/// aspect ratio can be drawn as large as possible in every tile.
+pub struct GridLayout {
+    /// width of the screen the layout was computed for
+    pub screen_w: usize,
+    /// height of the screen the layout was computed for
+    pub screen_h: usize,
+    /// one tile per participant, in participant order
+    pub tiles: Vec<Tile>,
+}
+
+impl GridLayout {
+    /// Compute a layout for the given participants on a `screen_w` x `screen_h`
+    /// screen. `frame_aspect` is the expected width / height of a stream,
+    /// in characters.
+    pub fn new(screen_w: usize, screen_h: usize, ids: &[ParticipantId], frame_aspect: f32) -> Self {
+        let n = ids.len();
+        let mut tiles = Vec::with_capacity(n);
+
+        if n > 0 {
+            let (cols, rows) = Self::grid_size(screen_w, screen_h, n, frame_aspect);
+            let tile_w = screen_w / cols;
+            let tile_h = screen_h / rows;
+
+            for (i, &id) in ids.iter().enumerate() {
+                let row = i / cols;
+                let col = i % cols;
+
+                // center the (possibly shorter) last row
+                let in_row = (n - row * cols).min(cols);
+                let offset = (screen_w - in_row * tile_w) / 2;
+
+                tiles.push(Tile {
+                    id,
+                    x: offset + col * tile_w,
+                    y: row * tile_h,
+                    w: tile_w,
+                    h: tile_h,
+                });
+            }
+        }
+
+        Self {
+            screen_w,
+            screen_h,
+            tiles,
+        }
+    }
+
+    /// Whether the layout was computed for this screen and these participants
+    pub fn matches(&self, screen_w: usize, screen_h: usize, ids: &[ParticipantId]) -> bool {
+        self.screen_w == screen_w
+            && self.screen_h == screen_h
+            && self.tiles.iter().map(|t| t.id).eq(ids.iter().copied())
+    }
+
+    /// Pick the amount of columns & rows that fit `n` streams the largest
+    fn grid_size(screen_w: usize, screen_h: usize, n: usize, frame_aspect: f32) -> (usize, usize) {
+        let mut best = (1, n);
+        let mut best_scale = f32::MIN;
+
+        for cols in 1..=n {
+            let rows = n.div_ceil(cols);
+            let inner_w = (screen_w / cols).saturating_sub(2) as f32;
+            let inner_h = (screen_h / rows).saturating_sub(2) as f32;
+
+            // height of the largest stream that fits in the tile
+            let scale = (inner_w / frame_aspect).min(inner_h);
+            if scale > best_scale {
+                best = (cols, rows);
+                best_scale = scale;
+            }
+        }
+
+        best
+    }
+
+    /// Draw every tile onto `canvas`: a border labelled with the
+    /// participant's name, and its latest frame inside the border
+    pub fn compose(
+        &self,
+        canvas: &mut AsciiFrame,
+        frames: &BTreeMap<ParticipantId, AsciiFrame>,
+        names: &BTreeMap<ParticipantId, String>,
+    ) {
+        for tile in &self.tiles {
+            let label = match names.get(&tile.id) {
+                Some(name) => name.clone(),
+                None => format!("participant-{}", tile.id),
+            };
+            Self::draw_tile(canvas, tile, &label, frames.get(&tile.id));
+        }
+    }
+
+    /// Tile in the bottom-right corner of the screen for a picture-in-picture
+    /// inset, a quarter of the screen wide and fitting `frame_aspect`
+    pub fn inset(&self, id: ParticipantId, frame_aspect: f32) -> Tile {
+        let w = (self.screen_w / INSET_FRACTION)
+            .max(INSET_MIN_WIDTH)
+            .min(self.screen_w);
+        let inner_h = (w.saturating_sub(2) as f32 / frame_aspect).round() as usize;
+        let h = (inner_h.max(1) + 2).min(self.screen_h);
+
+        Tile {
+            id,
+            x: self.screen_w - w,
+            y: self.screen_h - h,
+            w,
+            h,
+        }
+    }
+
+    /// Draw a single tile onto `canvas`: a border labelled with `label`,
+    /// and `frame` scaled down to fit and centered inside the border
+    pub fn draw_tile(
+        canvas: &mut AsciiFrame,
+        tile: &Tile,
+        label: &str,
+        frame: Option<&AsciiFrame>,
+    ) {
+        Self::draw_border(canvas, tile);
+
+        let label: String = format!(" {} ", label)
+            .chars()
+            .take(tile.w.saturating_sub(4))
+            .collect();
+        canvas.draw_text(tile.x + 2, tile.y, &label);
+
+        let (x, y, w, h) = tile.inner();
+        canvas.fill(x, y, w, h, ' ');
+        if w == 0 || h == 0 {
+            return;
+        }
+
+        match frame {
+            Some(frame) => {
+                // only ever shrink, keeping the frame's aspect ratio
+                let scale = (w as f32 / frame.w as f32)
+                    .min(h as f32 / frame.h as f32)
+                    .min(1.0);
+                let fw = ((frame.w as f32 * scale) as usize).clamp(1, w);
+                let fh = ((frame.h as f32 * scale) as usize).clamp(1, h);
+
+                canvas.draw_frame(frame, x + (w - fw) / 2, y + (h - fh) / 2, fw, fh);
+            }
+            None => {
+                let text_w = WAITING_TEXT.len().min(w);
+                canvas.draw_text(x + (w - text_w) / 2, y + h / 2, &WAITING_TEXT[..text_w]);
+            }
+        }
+    }
+
+    /// Draw a box around the edge of `tile`
+    fn draw_border(canvas: &mut AsciiFrame, tile: &Tile) {
+        if tile.w < 2 || tile.h < 2 {
+            return;
+        }
+
+        let right = tile.x + tile.w - 1;
+        let bottom = tile.y + tile.h - 1;
+
+        canvas.fill(tile.x + 1, tile.y, tile.w - 2, 1, BORDER_HORIZONTAL);
+        canvas.fill(tile.x + 1, bottom, tile.w - 2, 1, BORDER_HORIZONTAL);
+        canvas.fill(tile.x, tile.y + 1, 1, tile.h - 2, BORDER_VERTICAL);
+        canvas.fill(right, tile.y + 1, 1, tile.h - 2, BORDER_VERTICAL);
+
+        canvas.set_char(tile.x, tile.y, BORDER_TOP_LEFT);
+        canvas.set_char(right, tile.y, BORDER_TOP_RIGHT);
+        canvas.set_char(tile.x, bottom, BORDER_BOTTOM_LEFT);
+        canvas.set_char(right, bottom, BORDER_BOTTOM_RIGHT);
+    }
+}
@@ -0,0 +1,21 @@
+//! Terminal-based video call client, streaming camera images as ASCII art
+//! through the SFU. The `client` binary parses the command line and runs a
+//! `client::Client`; the modules are exposed here for it and for benchmarks.
+
+pub mod ascii_converter;
+pub mod ascii_renderer;
+pub mod camera;
+pub mod charset;
+pub mod client;
+pub mod color_depth;
+pub mod dither;
+pub mod edge_detector;
+pub mod exposure;
+pub mod ffmpeg;
+pub mod image_frame;
+pub mod layout;
+pub mod luminance;
+pub mod mock_frame_generator;
+pub mod resample;
+pub mod temporal;
+pub mod video_config;
@@ -0,0 +1,91 @@
+use std::sync::LazyLock;
+
+/// The coefficients below are derived from Rec. ITU-R BT.601-7.
+/// In the specification, these luminance coefficients represent
+/// how much they influence / contribute to the human eye's
+/// perception of brightness.
+pub const R_LUMINANCE: f32 = 0.2989;
+pub const G_LUMINANCE: f32 = 0.5870;
+pub const B_LUMINANCE: f32 = 0.1140;
+
+/// Rec. ITU-R BT.709-6 luminance coefficients, which sRGB shares
+pub const R_LUMINANCE_709: f32 = 0.2126;
+pub const G_LUMINANCE_709: f32 = 0.7152;
+pub const B_LUMINANCE_709: f32 = 0.0722;
+
+/// Entries of `LINEAR_TO_SRGB`, enough that neighboring entries never skip
+/// an encoded value
+const ENCODE_STEPS: usize = 4096;
+
+/// Light (0.0 - 1.0) of every gamma-encoded sRGB byte
+static SRGB_TO_LINEAR: LazyLock<[f32; 256]> = LazyLock::new(|| {
+    let mut lut = [0.0; 256];
+    for (v, linear) in lut.iter_mut().enumerate() {
+        let c = v as f32 / 255.0;
+        *linear = if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        };
+    }
+    lut
+});
+
+/// Gamma-encoded sRGB value (0.0 - 255.0) of light, in `ENCODE_STEPS` even
+/// steps from 0.0 to 1.0
+static LINEAR_TO_SRGB: LazyLock<Vec<f32>> = LazyLock::new(|| {
+    (0..ENCODE_STEPS)
+        .map(|i| {
+            let linear = i as f32 / (ENCODE_STEPS - 1) as f32;
+            let c = if linear <= 0.0031308 {
+                linear * 12.92
+            } else {
+                1.055 * linear.powf(1.0 / 2.4) - 0.055
+            };
+            c * 255.0
+        })
+        .collect()
+});
+
+/// How the brightness of a color is computed, deciding the character (or
+/// braille dot) it becomes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LuminanceModel {
+    /// BT.601 weights applied directly to the gamma-encoded bytes. Cheap,
+    /// but too dark for saturated colors
+    #[default]
+    Bt601,
+    /// BT.709 weights applied directly to the gamma-encoded bytes
+    Bt709,
+    /// BT.709 weights applied to light, decoding the sRGB gamma first and
+    /// encoding the result again, so equal steps look equally bright
+    LinearSrgb,
+}
+
+impl LuminanceModel {
+    /// Red, green and blue weights of the model
+    pub fn weights(self) -> (f32, f32, f32) {
+        match self {
+            LuminanceModel::Bt601 => (R_LUMINANCE, G_LUMINANCE, B_LUMINANCE),
+            LuminanceModel::Bt709 | LuminanceModel::LinearSrgb => {
+                (R_LUMINANCE_709, G_LUMINANCE_709, B_LUMINANCE_709)
+            }
+        }
+    }
+
+    /// Brightness (0.0 - 255.0) of an RGB color
+    pub fn intensity(self, (r, g, b): (u8, u8, u8)) -> f32 {
+        let (wr, wg, wb) = self.weights();
+        match self {
+            LuminanceModel::Bt601 | LuminanceModel::Bt709 => {
+                wr * r as f32 + wg * g as f32 + wb * b as f32
+            }
+            LuminanceModel::LinearSrgb => {
+                let decode = |c: u8| SRGB_TO_LINEAR[c as usize];
+                let linear = wr * decode(r) + wg * decode(g) + wb * decode(b);
+                let i = (linear * (ENCODE_STEPS - 1) as f32).round() as usize;
+                LINEAR_TO_SRGB[i.min(ENCODE_STEPS - 1)]
+            }
+        }
+    }
+}
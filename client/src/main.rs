@@ -1,20 +1,22 @@
-extern crate alloc;
-
-mod ascii_converter;
-mod ascii_renderer;
-mod camera;
-mod client;
-mod edge_detector;
-mod ffmpeg;
-mod image_frame;
-mod mock_frame_generator;
-mod video_config;
-
-use crate::client::Client;
-use crate::mock_frame_generator::PatternType;
 use clap::{Parser, ValueEnum};
+use client::ascii_renderer::AsciiRenderer;
+use client::charset::{Charset, CharsetPreset};
+use client::client::Client;
+use client::color_depth::ColorDepth;
+use client::dither::Dithering;
+use client::edge_detector::GradientOperator;
+use client::exposure::Equalization;
+use client::luminance::LuminanceModel;
+use client::mock_frame_generator::PatternType;
+use client::resample::{Framing, Resampling};
+use client::temporal::TemporalFilter;
+use client::video_config::VideoConfig;
+use common::ascii_frame::RenderMode;
+use common::compression::Codec;
+use common::protocol::is_single_argument;
 use rand::Rng;
 use std::error::Error;
+use std::path::PathBuf;
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
 enum TestPattern {
@@ -33,6 +35,206 @@ impl From<TestPattern> for PatternType {
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+enum CellStyle {
+    /// Characters picked by intensity and edges
+    Ascii,
+    /// Colored half blocks, two pixels per character
+    HalfBlock,
+    /// Braille patterns, 2x4 dots per character
+    Braille,
+}
+
+impl From<CellStyle> for RenderMode {
+    fn from(style: CellStyle) -> Self {
+        match style {
+            CellStyle::Ascii => RenderMode::Ascii,
+            CellStyle::HalfBlock => RenderMode::HalfBlock,
+            CellStyle::Braille => RenderMode::Braille,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+enum DitherStyle {
+    /// Round every character on its own
+    None,
+    /// Floyd-Steinberg error diffusion
+    FloydSteinberg,
+    /// Atkinson error diffusion
+    Atkinson,
+    /// Ordered dithering with a 4x4 Bayer matrix
+    Bayer,
+}
+
+impl From<DitherStyle> for Dithering {
+    fn from(style: DitherStyle) -> Self {
+        match style {
+            DitherStyle::None => Dithering::None,
+            DitherStyle::FloydSteinberg => Dithering::FloydSteinberg,
+            DitherStyle::Atkinson => Dithering::Atkinson,
+            DitherStyle::Bayer => Dithering::Bayer,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+enum SamplingStyle {
+    /// Single pixel per character
+    Nearest,
+    /// Average of all pixels behind a character
+    Area,
+    /// Interpolated at the center of a character
+    Bilinear,
+}
+
+impl From<SamplingStyle> for Resampling {
+    fn from(style: SamplingStyle) -> Self {
+        match style {
+            SamplingStyle::Nearest => Resampling::Nearest,
+            SamplingStyle::Area => Resampling::Area,
+            SamplingStyle::Bilinear => Resampling::Bilinear,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+enum EdgeOperator {
+    /// 3x3, balanced
+    Sobel,
+    /// 3x3, most accurate angles
+    Scharr,
+    /// 3x3, cheapest
+    Prewitt,
+    /// 2x2, finest detail but noisiest
+    RobertsCross,
+}
+
+impl From<EdgeOperator> for GradientOperator {
+    fn from(operator: EdgeOperator) -> Self {
+        match operator {
+            EdgeOperator::Sobel => GradientOperator::Sobel,
+            EdgeOperator::Scharr => GradientOperator::Scharr,
+            EdgeOperator::Prewitt => GradientOperator::Prewitt,
+            EdgeOperator::RobertsCross => GradientOperator::RobertsCross,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+enum CharsetStyle {
+    /// Ramp " .:coPO?@■" with Unicode edge lines
+    Default,
+    /// 7-bit ASCII only
+    Ascii,
+    /// Shade blocks " ░▒▓█"
+    Blocks,
+    /// Light to heavy box-drawing lines
+    BoxDrawing,
+    /// 70-character ASCII ramp
+    Dense,
+}
+
+impl From<CharsetStyle> for CharsetPreset {
+    fn from(style: CharsetStyle) -> Self {
+        match style {
+            CharsetStyle::Default => CharsetPreset::Default,
+            CharsetStyle::Ascii => CharsetPreset::Ascii,
+            CharsetStyle::Blocks => CharsetPreset::Blocks,
+            CharsetStyle::BoxDrawing => CharsetPreset::BoxDrawing,
+            CharsetStyle::Dense => CharsetPreset::Dense,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+enum LuminanceStyle {
+    /// BT.601 weights on the gamma-encoded colors
+    Bt601,
+    /// BT.709 weights on the gamma-encoded colors
+    Bt709,
+    /// BT.709 weights on linear light (decoding the sRGB gamma)
+    LinearSrgb,
+}
+
+impl From<LuminanceStyle> for LuminanceModel {
+    fn from(style: LuminanceStyle) -> Self {
+        match style {
+            LuminanceStyle::Bt601 => LuminanceModel::Bt601,
+            LuminanceStyle::Bt709 => LuminanceModel::Bt709,
+            LuminanceStyle::LinearSrgb => LuminanceModel::LinearSrgb,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+enum EqualizeStyle {
+    /// Intensities as the camera sees them
+    None,
+    /// Histogram equalization over the whole frame
+    Global,
+    /// Contrast limited equalization of every part of the frame (CLAHE)
+    Adaptive,
+}
+
+impl From<EqualizeStyle> for Equalization {
+    fn from(style: EqualizeStyle) -> Self {
+        match style {
+            EqualizeStyle::None => Equalization::None,
+            EqualizeStyle::Global => Equalization::Global,
+            EqualizeStyle::Adaptive => Equalization::Adaptive,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+enum FitStyle {
+    /// Cut off what doesn't fit
+    Crop,
+    /// Shrink to fit, with blank bars around
+    Letterbox,
+    /// Fill the frame, distorting the image
+    Stretch,
+}
+
+impl From<FitStyle> for Framing {
+    fn from(style: FitStyle) -> Self {
+        match style {
+            FitStyle::Crop => Framing::Crop,
+            FitStyle::Letterbox => Framing::Letterbox,
+            FitStyle::Stretch => Framing::Stretch,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+enum ColorSupport {
+    /// Detect from the terminal (NO_COLOR, COLORTERM, TERM, terminfo)
+    Auto,
+    /// 24-bit colors
+    Truecolor,
+    /// xterm 256-color palette
+    #[value(name = "256")]
+    Ansi256,
+    /// 16 basic ANSI colors
+    #[value(name = "16")]
+    Ansi16,
+    /// No colors
+    Mono,
+}
+
+impl From<ColorSupport> for ColorDepth {
+    fn from(support: ColorSupport) -> Self {
+        match support {
+            ColorSupport::Auto => ColorDepth::detect(),
+            ColorSupport::Truecolor => ColorDepth::TrueColor,
+            ColorSupport::Ansi256 => ColorDepth::Ansi256,
+            ColorSupport::Ansi16 => ColorDepth::Ansi16,
+            ColorSupport::Mono => ColorDepth::Monochrome,
+        }
+    }
+}
+
 /// if wanting to test locally, the command would look something like this:
 ///
 /// ```bash
@@ -55,18 +257,161 @@ struct Args {
     udp_addr: String,
 
     /// Session ID to join (random if not given)
-    #[arg(short = 's', long, default_value = "")]
+    #[arg(short = 's', long, default_value = "", value_parser = parse_session_id)]
     session_id: String,
 
+    /// Name shown to the other participants (defaults to participant ID)
+    #[arg(short = 'n', long, value_parser = parse_name)]
+    name: Option<String>,
+
+    /// Frame compression codecs to offer, comma-separated
+    /// (the server picks one all participants support)
+    #[arg(
+        short = 'c',
+        long,
+        value_delimiter = ',',
+        default_value = "none,rle,lz4"
+    )]
+    codecs: Vec<Codec>,
+
+    /// Start with the self-view shown (type `v` + Enter to toggle it)
+    #[arg(long)]
+    self_view: bool,
+
+    /// Colors the terminal can show
+    #[arg(long, default_value = "auto")]
+    color_depth: ColorSupport,
+
+    /// How the video is drawn into characters
+    #[arg(short = 'm', long, default_value = "ascii")]
+    render_mode: CellStyle,
+
+    /// Send frames without colors, leaving them to the viewers' terminal
+    /// (not with half blocks, which are drawn with colors alone)
+    #[arg(long)]
+    no_color: bool,
+
+    /// Characters the video is drawn with
+    #[arg(long, default_value = "default")]
+    charset: CharsetStyle,
+
+    /// Read the characters the video is drawn with from a file of
+    /// `name = characters` lines (names: intensity, horizontal, vertical,
+    /// forward, back)
+    #[arg(long, value_name = "PATH", conflicts_with = "charset")]
+    charset_file: Option<PathBuf>,
+
+    /// Order the intensity ramp by how much of its cell each character
+    /// covers
+    #[arg(long)]
+    sort_ramp: bool,
+
+    /// How the intensity ramp (or braille dots) are dithered
+    #[arg(short = 'd', long, default_value = "none")]
+    dithering: DitherStyle,
+
+    /// How the camera image is scaled down to characters
+    #[arg(long, default_value = "area")]
+    resampling: SamplingStyle,
+
+    /// How strongly characters are kept from flickering between frames,
+    /// from 0 (off) to 1
+    #[arg(long, default_value_t = TemporalFilter::DEFAULT_STRENGTH, value_parser = parse_temporal_strength)]
+    temporal_strength: f32,
+
+    /// Kernels used to find edges in the camera image
+    #[arg(long, default_value = "sobel")]
+    edge_operator: EdgeOperator,
+
+    /// How the brightness of a color is computed
+    #[arg(long, default_value = "bt601")]
+    luminance: LuminanceStyle,
+
+    /// How the intensities of the camera image are spread out
+    #[arg(long, default_value = "none")]
+    equalization: EqualizeStyle,
+
+    /// Adapt contrast and brightness to the scene, bringing the mean
+    /// intensity to the given value (0-255, 127.5 if not given)
+    #[arg(
+        long,
+        value_name = "TARGET",
+        num_args = 0..=1,
+        default_missing_value = "127.5",
+        value_parser = parse_exposure_target
+    )]
+    auto_exposure: Option<f32>,
+
+    /// Height of a character cell divided by its width
+    /// (asked from the terminal if not given)
+    #[arg(long, value_parser = parse_cell_aspect)]
+    cell_aspect: Option<f32>,
+
+    /// How the camera image is fit onto the frame
+    #[arg(long, default_value = "crop")]
+    framing: FitStyle,
+
     /// Test pattern (if not using a camera)
     #[arg(short = 'p', long)]
     test_pattern: Option<TestPattern>,
 }
 
+/// Names are sent as a single protocol argument, so may not contain whitespace
+fn parse_name(name: &str) -> Result<String, String> {
+    if !is_single_argument(name) {
+        return Err(
+            "name must be non-empty and contain no whitespace or control characters".into(),
+        );
+    }
+    Ok(name.to_owned())
+}
+
+/// Session IDs are sent as a single protocol argument too, but may be left
+/// empty for a random one
+fn parse_session_id(session_id: &str) -> Result<String, String> {
+    if !session_id.is_empty() && !is_single_argument(session_id) {
+        return Err("session ID must contain no whitespace or control characters".into());
+    }
+    Ok(session_id.to_owned())
+}
+
+fn parse_cell_aspect(aspect: &str) -> Result<f32, String> {
+    match aspect.parse::<f32>() {
+        Ok(aspect) if aspect.is_finite() && aspect > 0.0 => Ok(aspect),
+        _ => Err("cell aspect must be a positive number".into()),
+    }
+}
+
+fn parse_exposure_target(target: &str) -> Result<f32, String> {
+    match target.parse::<f32>() {
+        Ok(target) if (0.0..=255.0).contains(&target) => Ok(target),
+        _ => Err("exposure target must be a number from 0 to 255".into()),
+    }
+}
+
+fn parse_temporal_strength(strength: &str) -> Result<f32, String> {
+    match strength.parse::<f32>() {
+        Ok(strength) if (0.0..=1.0).contains(&strength) => Ok(strength),
+        _ => Err("temporal strength must be a number from 0 to 1".into()),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
+    if args.no_color && args.render_mode == CellStyle::HalfBlock {
+        return Err("half blocks can't be drawn without colors".into());
+    }
+
+    let mut charset = match &args.charset_file {
+        Some(path) => Charset::load(path)?,
+        None => Charset::from(CharsetPreset::from(args.charset)),
+    };
+    if args.sort_ramp {
+        charset.sort_by_density()?;
+    }
+
     let session_id = if args.session_id.is_empty() {
         let rand_id: u32 = rand::rng().random();
         format!("session-{}", rand_id)
@@ -76,15 +421,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     println!("connection to session: {}", session_id);
 
-    let pattern_type = args.test_pattern.map(|p| PatternType::from(p));
-    if let Some(_) = &pattern_type {
+    let pattern_type = args.test_pattern.map(PatternType::from);
+    if pattern_type.is_some() {
         println!("using test pattern: {:?}", args.test_pattern);
     }
 
+    let video_config = VideoConfig {
+        charset,
+        render_mode: RenderMode::from(args.render_mode),
+        colors: !args.no_color,
+        dithering: Dithering::from(args.dithering),
+        resampling: Resampling::from(args.resampling),
+        temporal_strength: args.temporal_strength,
+        cell_aspect: args
+            .cell_aspect
+            .or_else(AsciiRenderer::cell_aspect)
+            .unwrap_or(VideoConfig::DEFAULT_CELL_ASPECT),
+        framing: Framing::from(args.framing),
+        edge_operator: GradientOperator::from(args.edge_operator),
+        luminance: LuminanceModel::from(args.luminance),
+        equalization: Equalization::from(args.equalization),
+        auto_exposure: args.auto_exposure,
+        ..VideoConfig::default()
+    };
+
     let client = Client::new(
         args.tcp_addr,
         args.udp_addr,
         session_id.clone(),
+        args.name,
+        args.codecs,
+        args.self_view,
+        ColorDepth::from(args.color_depth),
+        video_config,
         pattern_type,
     );
 
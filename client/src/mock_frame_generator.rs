@@ -1,4 +1,5 @@
-use common::ascii_frame::AsciiFrame;
+use crate::ascii_converter::AsciiConverter;
+use common::ascii_frame::{AsciiFrame, CellColor, RenderMode, Rgb};
 use std::error::Error;
 use std::time::{Duration, Instant};
 
@@ -23,6 +24,8 @@ pub struct MockFrameGenerator {
     frame_delay: Duration,
     /// pattern to generate
     pattern_type: PatternType,
+    /// how the pattern is drawn into the frame's cells
+    render_mode: RenderMode,
 }
 
 impl MockFrameGenerator {
@@ -31,6 +34,7 @@ impl MockFrameGenerator {
         h: usize,
         fps: u32,
         pattern_type: PatternType,
+        render_mode: RenderMode,
     ) -> Result<Self, Box<dyn Error>> {
         if w == 0 || h == 0 || fps < 1 {
             return Err("failed to create mock frame generator".into());
@@ -45,9 +49,18 @@ impl MockFrameGenerator {
             last_frame_time: Instant::now(),
             frame_delay,
             pattern_type,
+            render_mode,
         })
     }
 
+    /// Make the following frames `w` x `h` characters large
+    pub fn resize(&mut self, w: usize, h: usize) {
+        if w > 0 && h > 0 {
+            self.w = w;
+            self.h = h;
+        }
+    }
+
     /// Generate a mock frame
     pub fn generate_frame(&mut self) -> Result<AsciiFrame, Box<dyn Error>> {
         let elapsed = self.last_frame_time.elapsed();
@@ -56,7 +69,13 @@ impl MockFrameGenerator {
         }
         self.last_frame_time = Instant::now();
 
-        let mut frame = AsciiFrame::new(self.w, self.h, ' ')?;
+        // half blocks show two rows of the pattern per cell, braille 2x4 dots
+        let (cols, rows) = match self.render_mode {
+            RenderMode::Ascii => (self.w, self.h),
+            RenderMode::HalfBlock => (self.w, self.h * 2),
+            RenderMode::Braille => (self.w * 2, self.h * 4),
+        };
+        let mut frame = AsciiFrame::new(cols, rows, ' ')?;
 
         match self.pattern_type {
             PatternType::Checkerboard => self.generate_checkerboard(&mut frame),
@@ -65,6 +84,84 @@ impl MockFrameGenerator {
 
         self.frame_counter += 1;
 
+        match self.render_mode {
+            RenderMode::Ascii => Ok(frame),
+            RenderMode::HalfBlock => Self::to_half_blocks(&frame),
+            RenderMode::Braille => Self::to_braille(&frame),
+        }
+    }
+
+    /// How bright a character of a pattern is, by its place in the
+    /// intensity ramp. Characters outside of it are fully bright
+    fn level(c: char) -> u8 {
+        let ramp: Vec<char> = AsciiConverter::DEFAULT_ASCII_INTENSITY.chars().collect();
+        ramp.iter()
+            .position(|&r| r == c)
+            .map_or(255, |p| (p * 255 / (ramp.len() - 1)) as u8)
+    }
+
+    /// Color a character of a pattern stands for: its own color, or a gray
+    /// as bright as the character
+    fn pixel(pattern: &AsciiFrame, x: usize, y: usize) -> Rgb {
+        let i = y * pattern.w + x;
+        if let Some(rgb) = pattern.colors().and_then(|colors| colors[i].fg) {
+            return rgb;
+        }
+
+        let level = Self::level(pattern.chars()[i]);
+        Rgb::new(level, level, level)
+    }
+
+    /// Fold every two rows of `tall` into a row of half blocks
+    fn to_half_blocks(tall: &AsciiFrame) -> Result<AsciiFrame, Box<dyn Error>> {
+        let mut frame = AsciiFrame::new(tall.w, tall.h / 2, RenderMode::HALF_BLOCK)?;
+        frame.mode = RenderMode::HalfBlock;
+
+        for y in 0..frame.h {
+            for x in 0..frame.w {
+                let color = CellColor {
+                    fg: Some(Self::pixel(tall, x, y * 2)),
+                    bg: Some(Self::pixel(tall, x, y * 2 + 1)),
+                };
+                frame.set_color(x, y, color);
+            }
+        }
+
+        Ok(frame)
+    }
+
+    /// Fold every 2x4 characters of `big` into a braille pattern, with a dot
+    /// set for every bright character
+    fn to_braille(big: &AsciiFrame) -> Result<AsciiFrame, Box<dyn Error>> {
+        let mut frame = AsciiFrame::new(big.w / 2, big.h / 4, ' ')?;
+        frame.mode = RenderMode::Braille;
+
+        for y in 0..frame.h {
+            for x in 0..frame.w {
+                let mut dots = 0u8;
+                let mut color = None;
+                for dot in 0..8 {
+                    let (dx, dy) = (x * 2 + dot % 2, y * 4 + dot / 2);
+                    if Self::level(big.chars()[dy * big.w + dx]) as f32
+                        > AsciiConverter::BRAILLE_THRESHOLD
+                    {
+                        dots |= 1 << dot;
+                        color = Some(Self::pixel(big, dx, dy));
+                    }
+                }
+
+                frame.set_char(x, y, RenderMode::braille(dots));
+                frame.set_color(
+                    x,
+                    y,
+                    CellColor {
+                        fg: color,
+                        bg: None,
+                    },
+                );
+            }
+        }
+
         Ok(frame)
     }
 
@@ -72,8 +169,8 @@ impl MockFrameGenerator {
     fn generate_checkerboard(&self, frame: &mut AsciiFrame) {
         let chars = ['.', '#'];
 
-        for y in 0..self.h {
-            for x in 0..self.w {
+        for y in 0..frame.h {
+            for x in 0..frame.w {
                 let pattern_offset = (self.frame_counter / 5) % 2;
                 let is_odd = (x + y) % 2;
                 let i = (is_odd + pattern_offset) % 2;
@@ -83,14 +180,25 @@ impl MockFrameGenerator {
         }
     }
 
-    /// Create a moving line pattern in the mock frame
+    /// Create a moving line pattern in the mock frame, changing color
+    /// every time it wraps around
     fn generate_moving_line(&self, frame: &mut AsciiFrame) {
+        const LINE_COLORS: [Rgb; 3] = [
+            Rgb::new(255, 64, 64),
+            Rgb::new(64, 255, 64),
+            Rgb::new(64, 64, 255),
+        ];
         let line_pos = self.frame_counter % frame.h;
+        let color = CellColor {
+            fg: Some(LINE_COLORS[(self.frame_counter / frame.h) % LINE_COLORS.len()]),
+            bg: None,
+        };
 
-        for y in 0..self.h {
-            for x in 0..self.w {
+        for y in 0..frame.h {
+            for x in 0..frame.w {
                 if y == line_pos {
                     frame.set_char(x, y, '=');
+                    frame.set_color(x, y, color);
                 } else {
                     frame.set_char(x, y, ' ');
                 }
@@ -0,0 +1,372 @@
+use crate::image_frame::ImageFrame;
+
+/// Part of a source image behind a single output cell, in (fractional)
+/// pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+impl Region {
+    /// The whole of a `w` x `h` image
+    pub fn whole(w: usize, h: usize) -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            w: w as f32,
+            h: h as f32,
+        }
+    }
+
+    /// Part of the region behind cell (`col`, `row`) of a `cols` x `rows`
+    /// grid laid over it
+    pub fn cell(&self, col: usize, row: usize, cols: usize, rows: usize) -> Self {
+        let cell_w = self.w / cols as f32;
+        let cell_h = self.h / rows as f32;
+
+        Self {
+            x: self.x + col as f32 * cell_w,
+            y: self.y + row as f32 * cell_h,
+            w: cell_w,
+            h: cell_h,
+        }
+    }
+}
+
+/// How an image is fit onto a grid of cells whose shape differs from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// cut off the image's excess width or height, filling every cell
+    #[default]
+    Crop,
+    /// shrink the image to fit, leaving blank bars on two sides
+    Letterbox,
+    /// fill every cell with the whole image, distorting it
+    Stretch,
+}
+
+/// Where an image ends up on a grid of cells, see `Framing::place`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    /// part of the image that is shown
+    pub src: Region,
+    /// first column showing the image
+    pub col: usize,
+    /// first row showing the image
+    pub row: usize,
+    /// amount of columns showing the image
+    pub cols: usize,
+    /// amount of rows showing the image
+    pub rows: usize,
+}
+
+impl Placement {
+    /// Part of the image behind cell (`col`, `row`) of the grid, or `None`
+    /// if the cell is outside of the picture (in a letterbox bar)
+    pub fn region(&self, col: usize, row: usize) -> Option<Region> {
+        if col < self.col || row < self.row {
+            return None;
+        }
+        let (col, row) = (col - self.col, row - self.row);
+        if col >= self.cols || row >= self.rows {
+            return None;
+        }
+
+        Some(self.src.cell(col, row, self.cols, self.rows))
+    }
+}
+
+impl Framing {
+    /// Place a `w` x `h` image onto a `cols` x `rows` grid of cells, each
+    /// `cell_aspect` times as tall as it is wide, keeping the image's
+    /// aspect ratio (unless stretching)
+    pub fn place(
+        self,
+        w: usize,
+        h: usize,
+        cols: usize,
+        rows: usize,
+        cell_aspect: f32,
+    ) -> Placement {
+        let whole = Placement {
+            src: Region::whole(w, h),
+            col: 0,
+            row: 0,
+            cols,
+            rows,
+        };
+        if w == 0 || h == 0 || cols == 0 || rows == 0 || cell_aspect <= 0.0 {
+            return whole;
+        }
+
+        // width over height, as seen on screen
+        let image_aspect = w as f32 / h as f32;
+        let grid_aspect = cols as f32 / (rows as f32 * cell_aspect);
+
+        match self {
+            Framing::Stretch => whole,
+            Framing::Crop if image_aspect > grid_aspect => {
+                let src_w = h as f32 * grid_aspect;
+                Placement {
+                    src: Region {
+                        x: (w as f32 - src_w) / 2.0,
+                        w: src_w,
+                        ..whole.src
+                    },
+                    ..whole
+                }
+            }
+            Framing::Crop => {
+                let src_h = w as f32 / grid_aspect;
+                Placement {
+                    src: Region {
+                        y: (h as f32 - src_h) / 2.0,
+                        h: src_h,
+                        ..whole.src
+                    },
+                    ..whole
+                }
+            }
+            Framing::Letterbox if image_aspect > grid_aspect => {
+                let used =
+                    ((rows as f32 * grid_aspect / image_aspect).round() as usize).clamp(1, rows);
+                Placement {
+                    row: (rows - used) / 2,
+                    rows: used,
+                    ..whole
+                }
+            }
+            Framing::Letterbox => {
+                let used =
+                    ((cols as f32 * image_aspect / grid_aspect).round() as usize).clamp(1, cols);
+                Placement {
+                    col: (cols - used) / 2,
+                    cols: used,
+                    ..whole
+                }
+            }
+        }
+    }
+}
+
+/// How the source pixels behind an output cell are combined into the
+/// cell's value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Resampling {
+    /// the single pixel at the region's top left corner. Cheap, but fine
+    /// detail between sampled pixels is lost and flickers between frames
+    Nearest,
+    /// box filter: the average of all pixels in the region, weighted by how
+    /// much of them the region covers
+    #[default]
+    Area,
+    /// interpolation between the 4 pixels around the region's center.
+    /// Smoother than `Nearest`, but still ignores most of a large region
+    Bilinear,
+}
+
+impl Resampling {
+    /// Combine `N` channels of the pixels of a `w` x `h` image behind
+    /// `region`, reading pixels through `pixel`. The region is clipped to
+    /// the image
+    pub fn sample<const N: usize>(
+        self,
+        w: usize,
+        h: usize,
+        region: Region,
+        pixel: impl Fn(usize, usize) -> [f32; N],
+    ) -> [f32; N] {
+        if w == 0 || h == 0 {
+            return [0.0; N];
+        }
+
+        match self {
+            Resampling::Nearest => pixel(
+                (region.x.max(0.0) as usize).min(w - 1),
+                (region.y.max(0.0) as usize).min(h - 1),
+            ),
+            Resampling::Area => {
+                let (x0, x1) = (region.x.max(0.0), (region.x + region.w).min(w as f32));
+                let (y0, y1) = (region.y.max(0.0), (region.y + region.h).min(h as f32));
+                if x1 <= x0 || y1 <= y0 {
+                    return Resampling::Nearest.sample(w, h, region, pixel);
+                }
+
+                let mut sum = [0.0; N];
+                let mut total = 0.0;
+                for py in y0 as usize..(y1.ceil() as usize).min(h) {
+                    // share of the pixel's row inside the region
+                    let wy = y1.min(py as f32 + 1.0) - y0.max(py as f32);
+                    for px in x0 as usize..(x1.ceil() as usize).min(w) {
+                        let weight = wy * (x1.min(px as f32 + 1.0) - x0.max(px as f32));
+                        for (acc, v) in sum.iter_mut().zip(pixel(px, py)) {
+                            *acc += v * weight;
+                        }
+                        total += weight;
+                    }
+                }
+
+                sum.map(|acc| acc / total)
+            }
+            Resampling::Bilinear => {
+                // pixel centers sit at +0.5
+                let cx = (region.x + region.w / 2.0 - 0.5).clamp(0.0, (w - 1) as f32);
+                let cy = (region.y + region.h / 2.0 - 0.5).clamp(0.0, (h - 1) as f32);
+                let (x0, y0) = (cx as usize, cy as usize);
+                let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+                let (fx, fy) = (cx - x0 as f32, cy - y0 as f32);
+
+                let (p00, p10) = (pixel(x0, y0), pixel(x1, y0));
+                let (p01, p11) = (pixel(x0, y1), pixel(x1, y1));
+                let mut out = [0.0; N];
+                for (i, v) in out.iter_mut().enumerate() {
+                    let top = p00[i] + (p10[i] - p00[i]) * fx;
+                    let bottom = p01[i] + (p11[i] - p01[i]) * fx;
+                    *v = top + (bottom - top) * fy;
+                }
+                out
+            }
+        }
+    }
+
+    /// RGB values of `frame` behind `region`
+    pub fn sample_rgb(self, frame: &ImageFrame, region: Region) -> (u8, u8, u8) {
+        let [r, g, b] = self.sample(frame.w, frame.h, region, |x, y| {
+            let (r, g, b) = frame.get_pixel(x, y).unwrap_or((0, 0, 0));
+            [r as f32, g as f32, b as f32]
+        });
+
+        // round, so averaging a flat region gives back its exact color
+        (r.round() as u8, g.round() as u8, b.round() as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sample a 4x4 image whose pixels are `x + 4 * y`
+    fn sample(resampling: Resampling, region: Region) -> f32 {
+        let [v] = resampling.sample(4, 4, region, |x, y| [(x + 4 * y) as f32]);
+        v
+    }
+
+    fn region(x: f32, y: f32, w: f32, h: f32) -> Region {
+        Region { x, y, w, h }
+    }
+
+    /// Columns and rows `placement` shows the image in, as
+    /// `(col, row, cols, rows)`
+    fn cells(placement: Placement) -> (usize, usize, usize, usize) {
+        (placement.col, placement.row, placement.cols, placement.rows)
+    }
+
+    #[test]
+    fn letterbox_bars_account_for_the_cell_aspect() {
+        // an 80x20 grid of cells twice as tall as wide is 2:1, like the image
+        let fit = Framing::Letterbox.place(800, 400, 80, 20, 2.0);
+        assert_eq!(cells(fit), (0, 0, 80, 20));
+
+        // square cells make the grid 4:1, leaving bars left and right
+        let square = Framing::Letterbox.place(800, 400, 80, 20, 1.0);
+        assert_eq!(cells(square), (20, 0, 40, 20));
+        assert_eq!(square.region(19, 0), None);
+        assert_eq!(square.region(20, 0), Some(region(0.0, 0.0, 20.0, 20.0)));
+        assert_eq!(
+            square.region(59, 19),
+            Some(region(780.0, 380.0, 20.0, 20.0))
+        );
+        assert_eq!(square.region(60, 0), None);
+
+        let tall = Framing::Letterbox.place(400, 800, 80, 20, 2.0);
+        assert_eq!(cells(tall), (30, 0, 20, 20));
+
+        // bars above and below
+        let wide = Framing::Letterbox.place(800, 100, 80, 20, 2.0);
+        assert_eq!(cells(wide), (0, 7, 80, 5));
+        assert_eq!(wide.region(0, 6), None);
+        assert_eq!(wide.region(0, 12), None);
+        assert_eq!(wide.src, Region::whole(800, 100));
+    }
+
+    #[test]
+    fn crop_cuts_off_the_excess_around_the_center() {
+        let fit = Framing::Crop.place(800, 400, 80, 20, 2.0);
+        assert_eq!(fit.src, Region::whole(800, 400));
+
+        let square = Framing::Crop.place(800, 400, 80, 20, 1.0);
+        assert_eq!(cells(square), (0, 0, 80, 20));
+        assert_eq!(square.src, region(0.0, 100.0, 800.0, 200.0));
+
+        let tall = Framing::Crop.place(400, 800, 80, 20, 2.0);
+        assert_eq!(tall.src, region(0.0, 300.0, 400.0, 200.0));
+
+        let wide = Framing::Crop.place(800, 100, 80, 20, 2.0);
+        assert_eq!(wide.src, region(300.0, 0.0, 200.0, 100.0));
+        assert_eq!(wide.region(0, 0), Some(region(300.0, 0.0, 2.5, 5.0)));
+    }
+
+    #[test]
+    fn stretch_shows_the_whole_image_on_every_cell() {
+        for aspect in [1.0, 2.0] {
+            let stretch = Framing::Stretch.place(800, 100, 80, 20, aspect);
+            assert_eq!(cells(stretch), (0, 0, 80, 20));
+            assert_eq!(stretch.src, Region::whole(800, 100));
+        }
+    }
+
+    #[test]
+    fn area_averages_the_covered_pixels() {
+        assert_eq!(sample(Resampling::Area, Region::whole(4, 4)), 7.5);
+        // pixels 5, 6, 9 and 10
+        assert_eq!(sample(Resampling::Area, region(1.0, 1.0, 2.0, 2.0)), 7.5);
+        // half of pixel 0 and half of pixel 1
+        assert_eq!(sample(Resampling::Area, region(0.5, 0.0, 1.0, 1.0)), 0.5);
+        // quarter of pixel 0, three quarters of pixel 4
+        assert_eq!(sample(Resampling::Area, region(0.0, 0.75, 1.0, 1.0)), 3.0);
+    }
+
+    #[test]
+    fn area_clips_the_region_to_the_image() {
+        // pixels 14, 15 and the nothing beyond them
+        assert_eq!(sample(Resampling::Area, region(2.0, 3.0, 4.0, 2.0)), 14.5);
+    }
+
+    #[test]
+    fn bilinear_interpolates_around_the_center() {
+        // center at (1, 1), between pixels 0, 1, 4 and 5
+        assert_eq!(
+            sample(Resampling::Bilinear, region(0.0, 0.0, 2.0, 2.0)),
+            2.5
+        );
+        // center on pixel 5's center
+        assert_eq!(
+            sample(Resampling::Bilinear, region(1.0, 1.0, 1.0, 1.0)),
+            5.0
+        );
+        // a large region only sees the pixels around its center
+        assert_eq!(sample(Resampling::Bilinear, Region::whole(4, 4)), 7.5);
+        assert_eq!(
+            sample(Resampling::Bilinear, region(0.0, 0.0, 4.0, 2.0)),
+            3.5
+        );
+    }
+
+    #[test]
+    fn nearest_takes_the_top_left_pixel() {
+        assert_eq!(sample(Resampling::Nearest, region(1.5, 2.5, 2.0, 1.0)), 9.0);
+    }
+
+    #[test]
+    fn flat_color_samples_exactly() {
+        let mut frame = ImageFrame::new(3, 3, 3).unwrap();
+        frame.buffer_mut().fill(37);
+        for resampling in [Resampling::Nearest, Resampling::Area, Resampling::Bilinear] {
+            let rgb = resampling.sample_rgb(&frame, region(0.3, 0.7, 1.9, 1.1));
+            assert_eq!(rgb, (37, 37, 37), "{resampling:?}");
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use common::ascii_frame::RenderMode;
+
+/// Keeps the cells of consecutive frames from flickering between
+/// neighboring characters on sensor noise. Intensities and edge magnitudes
+/// of every cell are smoothed over time, and a cell keeps its ramp level
+/// until its intensity clearly leaves it
+#[derive(Debug, Clone, Default)]
+pub struct TemporalFilter {
+    /// 0.0 (off) to 1.0 (smoothest)
+    strength: f32,
+    /// width of the grid of cells being filtered
+    w: usize,
+    /// height of the grid of cells being filtered
+    h: usize,
+    /// how the cells being filtered are drawn
+    mode: RenderMode,
+    /// smoothed intensity of every cell, `NAN` before the first frame
+    intensities: Vec<f32>,
+    /// smoothed edge magnitude of every cell, `NAN` before the first frame
+    magnitudes: Vec<f32>,
+    /// ramp level last shown in every cell, if it showed one
+    levels: Vec<Option<usize>>,
+}
+
+impl TemporalFilter {
+    pub const DEFAULT_STRENGTH: f32 = 0.5;
+    /// Weight of a new frame in the smoothed values at full strength
+    pub const MIN_WEIGHT: f32 = 0.1;
+    /// Change of intensity (0.0 - 255.0) taken as motion rather than noise,
+    /// which is shown at once instead of smoothed
+    pub const MOTION_THRESHOLD: f32 = 48.0;
+
+    pub fn new(strength: f32) -> Self {
+        Self {
+            strength: strength.clamp(0.0, 1.0),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.strength > 0.0
+    }
+
+    /// Start filtering a frame of `w` x `h` cells drawn in `mode`,
+    /// forgetting the previous frames if their size or mode differs
+    pub fn begin(&mut self, w: usize, h: usize, mode: RenderMode) {
+        if (self.w, self.h, self.mode) == (w, h, mode) {
+            return;
+        }
+
+        self.w = w;
+        self.h = h;
+        self.mode = mode;
+        self.intensities.clear();
+        self.intensities.resize(w * h, f32::NAN);
+        self.magnitudes.clear();
+        self.magnitudes.resize(w * h, f32::NAN);
+        self.levels.clear();
+        self.levels.resize(w * h, None);
+    }
+
+    /// Weight of a new frame in the smoothed values
+    fn weight(&self) -> f32 {
+        1.0 - self.strength * (1.0 - Self::MIN_WEIGHT)
+    }
+
+    /// Smoothed intensity of cell `i`, now at `intensity`
+    pub fn intensity(&mut self, i: usize, intensity: f32) -> f32 {
+        let weight = self.weight();
+        let Some(smoothed) = self.intensities.get_mut(i) else {
+            return intensity;
+        };
+
+        if smoothed.is_nan() || (intensity - *smoothed).abs() > Self::MOTION_THRESHOLD {
+            *smoothed = intensity;
+        } else {
+            *smoothed += (intensity - *smoothed) * weight;
+        }
+        *smoothed
+    }
+
+    /// Smoothed edge magnitude of cell `i`, now at `magnitude`
+    pub fn magnitude(&mut self, i: usize, magnitude: f32) -> f32 {
+        let weight = self.weight();
+        let Some(smoothed) = self.magnitudes.get_mut(i) else {
+            return magnitude;
+        };
+
+        if smoothed.is_nan() {
+            *smoothed = magnitude;
+        } else {
+            *smoothed += (magnitude - *smoothed) * weight;
+        }
+        *smoothed
+    }
+
+    /// Keep cells on the ramp level they showed last frame while their
+    /// `intensities` stay within that level's `range` (see
+    /// `Dithering::level_range`), widened by up to half its width. `None`
+    /// in `levels` marks cells showing no ramp level (edges, blank bars)
+    pub fn hold_levels(
+        &mut self,
+        intensities: &[f32],
+        levels: &mut [Option<usize>],
+        range: impl Fn(usize) -> (f32, f32),
+    ) {
+        for ((level, intensity), previous) in levels
+            .iter_mut()
+            .zip(intensities)
+            .zip(self.levels.iter_mut())
+        {
+            if let (Some(current), Some(prev)) = (level.as_mut(), *previous)
+                && *current != prev
+            {
+                let (lo, hi) = range(prev);
+                let margin = (hi - lo) * self.strength / 2.0;
+                if (lo - margin..=hi + margin).contains(intensity) {
+                    *current = prev;
+                }
+            }
+            *previous = *level;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dither::Dithering;
+
+    /// Ramp levels the filtered cells are quantized to
+    const LEVELS: usize = 4;
+
+    /// Level a single cell shows for each of `frames`, filtered by `filter`
+    fn levels_of(filter: &mut TemporalFilter, frames: &[f32]) -> Vec<usize> {
+        filter.begin(1, 1, RenderMode::Ascii);
+        frames
+            .iter()
+            .map(|&v| {
+                let intensities = [filter.intensity(0, v)];
+                let mut levels: Vec<Option<usize>> = Dithering::None
+                    .quantize(&intensities, 1, LEVELS)
+                    .into_iter()
+                    .map(Some)
+                    .collect();
+                filter.hold_levels(&intensities, &mut levels, |level| {
+                    Dithering::None.level_range(level, LEVELS)
+                });
+                levels[0].unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn noise_around_a_level_boundary_keeps_the_level() {
+        // the boundary between the first two levels is at 63.75
+        let noisy: Vec<f32> = (0..20)
+            .map(|i| if i % 2 == 0 { 58.0 } else { 70.0 })
+            .collect();
+
+        let unfiltered = levels_of(&mut TemporalFilter::new(0.0), &noisy);
+        assert!(unfiltered.windows(2).any(|w| w[0] != w[1]));
+
+        let filtered = levels_of(&mut TemporalFilter::new(0.5), &noisy);
+        assert!(filtered.iter().all(|&l| l == filtered[0]), "{filtered:?}");
+    }
+
+    #[test]
+    fn motion_passes_through_at_once() {
+        let mut filter = TemporalFilter::new(1.0);
+        filter.begin(1, 1, RenderMode::Ascii);
+        for _ in 0..10 {
+            filter.intensity(0, 50.0);
+        }
+
+        let jump = 50.0 + TemporalFilter::MOTION_THRESHOLD + 1.0;
+        assert_eq!(filter.intensity(0, jump), jump);
+        // while a small change is only eased toward
+        assert!(filter.intensity(0, jump + 10.0) < jump + 10.0);
+    }
+
+    #[test]
+    fn size_or_mode_change_forgets_previous_frames() {
+        let mut filter = TemporalFilter::new(1.0);
+        filter.begin(2, 2, RenderMode::Ascii);
+        filter.intensity(0, 100.0);
+        filter.magnitude(0, 100.0);
+
+        // same size and mode: smoothed
+        filter.begin(2, 2, RenderMode::Ascii);
+        assert!(filter.intensity(0, 120.0) < 120.0);
+
+        filter.begin(3, 2, RenderMode::Ascii);
+        assert_eq!(filter.intensity(0, 120.0), 120.0);
+        assert_eq!(filter.magnitude(0, 0.0), 0.0);
+
+        filter.begin(3, 2, RenderMode::Braille);
+        assert_eq!(filter.intensity(0, 140.0), 140.0);
+    }
+}
@@ -1,3 +1,13 @@
+use crate::ascii_converter::AsciiConverter;
+use crate::charset::Charset;
+use crate::dither::Dithering;
+use crate::edge_detector::{EdgeSettings, GradientOperator};
+use crate::exposure::Equalization;
+use crate::luminance::LuminanceModel;
+use crate::resample::{Framing, Resampling};
+use crate::temporal::TemporalFilter;
+use common::ascii_frame::RenderMode;
+
 /// Shared configuration values used by different systems
 /// in the entire program
 pub struct VideoConfig {
@@ -5,24 +15,38 @@ pub struct VideoConfig {
     pub camera_height: usize,
     pub ascii_width: usize,
     pub ascii_height: usize,
-    pub edge_threshold: f32,
+    /// characters frames are drawn with
+    pub charset: Charset,
+    /// see `EdgeSettings`
+    pub edge_operator: GradientOperator,
+    pub edge_blur_sigma: f32,
+    pub edge_low_threshold: f32,
+    pub edge_high_threshold: f32,
     pub contrast: f32,
     pub brightness: f32,
+    pub luminance: LuminanceModel,
+    pub equalization: Equalization,
+    /// mean intensity (0.0 - 255.0) that contrast and brightness adapt
+    /// toward, or `None` to keep them fixed
+    pub auto_exposure: Option<f32>,
+    /// whether frames carry colors, half blocks always do
+    pub colors: bool,
+    pub render_mode: RenderMode,
+    pub dithering: Dithering,
+    pub resampling: Resampling,
+    /// see `TemporalFilter`, 0.0 turns it off
+    pub temporal_strength: f32,
+    /// height of a character cell divided by its width
+    pub cell_aspect: f32,
+    pub framing: Framing,
 }
 
 impl VideoConfig {
-    pub fn default() -> Self {
-        Self {
-            camera_width: 640,
-            camera_height: 480,
-            ascii_width: 120,
-            ascii_height: 40,
-            edge_threshold: 127.50,
-            contrast: 1.5,
-            brightness: 0.0,
-        }
-    }
+    /// Shape of the cells of most terminal fonts
+    pub const DEFAULT_CELL_ASPECT: f32 = 2.0;
 
+    /// Default config with the given sizes and image adjustments.
+    /// `edge_threshold` is the high threshold of `EdgeSettings`
     pub fn new(
         camera_width: usize,
         camera_height: usize,
@@ -37,9 +61,38 @@ impl VideoConfig {
             camera_height,
             ascii_width,
             ascii_height,
-            edge_threshold,
+            edge_high_threshold: edge_threshold,
             contrast,
             brightness,
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            camera_width: 640,
+            camera_height: 480,
+            ascii_width: 120,
+            ascii_height: 40,
+            charset: Charset::default(),
+            edge_operator: GradientOperator::Sobel,
+            edge_blur_sigma: EdgeSettings::DEFAULT_BLUR_SIGMA,
+            edge_low_threshold: EdgeSettings::DEFAULT_LOW_THRESHOLD,
+            edge_high_threshold: EdgeSettings::DEFAULT_HIGH_THRESHOLD,
+            contrast: AsciiConverter::DEFAULT_CONTRAST,
+            brightness: AsciiConverter::DEFAULT_BRIGHTNESS,
+            luminance: LuminanceModel::Bt601,
+            equalization: Equalization::None,
+            auto_exposure: None,
+            colors: true,
+            render_mode: RenderMode::Ascii,
+            dithering: Dithering::None,
+            resampling: Resampling::Area,
+            temporal_strength: TemporalFilter::DEFAULT_STRENGTH,
+            cell_aspect: Self::DEFAULT_CELL_ASPECT,
+            framing: Framing::Crop,
         }
     }
 }
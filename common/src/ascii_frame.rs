@@ -1,8 +1,133 @@
 use std::error::Error;
 use std::str::from_utf8;
 
-const DEFAULT_W: usize = 120;
-const DEFAULT_H: usize = 40;
+/// 24-bit color
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
+
+impl From<(u8, u8, u8)> for Rgb {
+    fn from((r, g, b): (u8, u8, u8)) -> Self {
+        Self { r, g, b }
+    }
+}
+
+/// Colors a character cell is drawn with. `None` leaves the terminal's
+/// default color in place
+///
+/// # Examples
+///
+/// ```
+/// use common::ascii_frame::{CellColor, Rgb};
+///
+/// let color = CellColor {
+///     fg: Some(Rgb::new(255, 128, 0)),
+///     bg: None,
+/// };
+/// let mut encoded = Vec::new();
+/// color.encode(&mut encoded);
+/// assert_eq!(encoded, [0b01, 255, 128, 0]);
+/// assert_eq!(CellColor::decode(&encoded), Some((color, 4)));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CellColor {
+    /// color of the character itself
+    pub fg: Option<Rgb>,
+    /// color of the cell behind the character
+    pub bg: Option<Rgb>,
+}
+
+impl CellColor {
+    const HAS_FG: u8 = 0b01;
+    const HAS_BG: u8 = 0b10;
+
+    /// Append the wire form of the color to `out`: a byte telling which
+    /// colors are set, followed by 3 bytes (R, G, B) for each of them
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let mask = self.fg.map_or(0, |_| Self::HAS_FG) | self.bg.map_or(0, |_| Self::HAS_BG);
+        out.push(mask);
+        for rgb in [self.fg, self.bg].into_iter().flatten() {
+            out.extend_from_slice(&[rgb.r, rgb.g, rgb.b]);
+        }
+    }
+
+    /// Decode the color at the start of `bytes`, along with its length
+    pub fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+        let mask = *bytes.first()?;
+        if mask & !(Self::HAS_FG | Self::HAS_BG) != 0 {
+            return None;
+        }
+
+        let mut len = 1;
+        let mut next = |present: bool| -> Option<Option<Rgb>> {
+            if !present {
+                return Some(None);
+            }
+            let rgb = bytes.get(len..len + 3)?;
+            len += 3;
+            Some(Some(Rgb::new(rgb[0], rgb[1], rgb[2])))
+        };
+        let fg = next(mask & Self::HAS_FG != 0)?;
+        let bg = next(mask & Self::HAS_BG != 0)?;
+
+        Some((Self { fg, bg }, len))
+    }
+}
+
+/// How the cells of a frame depict the image they were converted from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RenderMode {
+    /// every cell is a character picked by the intensity or edges of the
+    /// image region it covers
+    #[default]
+    Ascii,
+    /// every cell is `HALF_BLOCK`, showing two image rows: the upper one in
+    /// its foreground color and the lower one in its background color
+    HalfBlock,
+    /// every cell is a braille pattern, a 2x4 grid of dots that are each
+    /// either on or off, see `RenderMode::braille`
+    Braille,
+}
+
+impl RenderMode {
+    /// Character drawn in every cell of a `RenderMode::HalfBlock` frame
+    pub const HALF_BLOCK: char = '▀';
+
+    /// Bit of a braille pattern (U+2800 + bits) for each dot of its grid,
+    /// row by row
+    const BRAILLE_DOTS: [u8; 8] = [0x01, 0x08, 0x02, 0x10, 0x04, 0x20, 0x40, 0x80];
+
+    /// Braille pattern showing the dots set in `dots`, a 2x4 grid read row
+    /// by row: bit 0 is the top left dot, bit 1 the top right one, and bit 7
+    /// the bottom right one
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use common::ascii_frame::RenderMode;
+    ///
+    /// assert_eq!(RenderMode::braille(0), '⠀');
+    /// assert_eq!(RenderMode::braille(0b0000_0001), '⠁');
+    /// assert_eq!(RenderMode::braille(0b0000_0010), '⠈');
+    /// assert_eq!(RenderMode::braille(0b1100_0000), '⣀');
+    /// assert_eq!(RenderMode::braille(0xFF), '⣿');
+    /// ```
+    pub fn braille(dots: u8) -> char {
+        let bits = (0..8)
+            .filter(|i| dots & (1 << i) != 0)
+            .fold(0u32, |bits, i| bits | Self::BRAILLE_DOTS[i] as u32);
+        char::from_u32(0x2800 + bits).unwrap_or(' ')
+    }
+}
 
 /// ASCII representation of an `ImageFrame` after contrast, brightness,
 /// and luminance transformations
@@ -12,8 +137,12 @@ pub struct AsciiFrame {
     pub w: usize,
     /// The amount of rows in the frame
     pub h: usize,
+    /// How the cells depict the image
+    pub mode: RenderMode,
     /// processed image pixels, interpreted as characters
     chars: Vec<char>,
+    /// color of every character, or `None` for a monochrome frame
+    colors: Option<Vec<CellColor>>,
 }
 
 impl AsciiFrame {
@@ -25,7 +154,9 @@ impl AsciiFrame {
         Ok(Self {
             w,
             h,
+            mode: RenderMode::Ascii,
             chars: vec![default_char; w * h],
+            colors: None,
         })
     }
 
@@ -52,7 +183,24 @@ impl AsciiFrame {
             return Err("Extra data after frame".into());
         }
 
-        Ok(Self { w, h, chars: grid })
+        Ok(Self {
+            w,
+            h,
+            mode: RenderMode::Ascii,
+            chars: grid,
+            colors: None,
+        })
+    }
+
+    /// Give the frame a color for every character, in the same order as
+    /// `chars`
+    pub fn with_colors(mut self, colors: Vec<CellColor>) -> Result<Self, Box<dyn Error>> {
+        if colors.len() != self.chars.len() {
+            return Err("color plane does not match frame size".into());
+        }
+
+        self.colors = Some(colors);
+        Ok(self)
     }
 
     /// Set individual characters, with bounds check
@@ -70,6 +218,40 @@ impl AsciiFrame {
         }
     }
 
+    /// Set the color of an individual character, with bounds check.
+    /// A monochrome frame gains a color plane, with every other character
+    /// left in the default color
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use common::ascii_frame::{AsciiFrame, CellColor, Rgb};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut frame = AsciiFrame::new(2, 2, '#')?;
+    /// assert!(frame.colors().is_none());
+    ///
+    /// let red = CellColor {
+    ///     fg: Some(Rgb::new(255, 0, 0)),
+    ///     bg: None,
+    /// };
+    /// assert!(frame.set_color(1, 0, red));
+    /// assert_eq!(frame.colors().unwrap()[..2], [CellColor::default(), red]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_color(&mut self, x: usize, y: usize, color: CellColor) -> bool {
+        if x >= self.w || y >= self.h {
+            return false;
+        }
+
+        let len = self.chars.len();
+        let colors = self
+            .colors
+            .get_or_insert_with(|| vec![CellColor::default(); len]);
+        colors[y * self.w + x] = color;
+        true
+    }
+
     /// Set range of characters, with bounds check
     pub fn set_chars(&mut self, data: &[char]) -> bool {
         if data.len() > self.chars.len() {
@@ -80,6 +262,52 @@ impl AsciiFrame {
         true
     }
 
+    /// Fill a rectangular region with a single character in the default
+    /// color, clipped to the frame
+    pub fn fill(&mut self, x: usize, y: usize, w: usize, h: usize, c: char) {
+        for row in y..(y + h).min(self.h) {
+            for col in x..(x + w).min(self.w) {
+                let i = row * self.w + col;
+                self.chars[i] = c;
+                if let Some(colors) = &mut self.colors {
+                    colors[i] = CellColor::default();
+                }
+            }
+        }
+    }
+
+    /// Write `text` left to right starting at (`x`, `y`), clipped to the frame
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str) {
+        for (i, c) in text.chars().enumerate() {
+            if !self.set_char(x + i, y, c) {
+                break;
+            }
+        }
+    }
+
+    /// Draw `src` into the `w` x `h` region at (`x`, `y`), resampling it
+    /// with nearest-neighbor sampling. The region is clipped to the frame
+    pub fn draw_frame(&mut self, src: &AsciiFrame, x: usize, y: usize, w: usize, h: usize) {
+        if src.colors.is_some() && self.colors.is_none() {
+            self.colors = Some(vec![CellColor::default(); self.chars.len()]);
+        }
+
+        for dy in 0..h.min(self.h.saturating_sub(y)) {
+            let src_y = dy * src.h / h;
+            for dx in 0..w.min(self.w.saturating_sub(x)) {
+                let src_x = dx * src.w / w;
+                let (i, src_i) = ((y + dy) * self.w + x + dx, src_y * src.w + src_x);
+                self.chars[i] = src.chars[src_i];
+                if let Some(colors) = &mut self.colors {
+                    colors[i] = src
+                        .colors
+                        .as_ref()
+                        .map_or_else(CellColor::default, |c| c[src_i]);
+                }
+            }
+        }
+    }
+
     /// Return raw `AsciiFrame` data
     pub fn chars(&self) -> &[char] {
         &self.chars
@@ -90,6 +318,16 @@ impl AsciiFrame {
         &mut self.chars
     }
 
+    /// Return the color of every character, if the frame has any
+    pub fn colors(&self) -> Option<&[CellColor]> {
+        self.colors.as_deref()
+    }
+
+    /// Return the mutable color of every character, if the frame has any
+    pub fn colors_mut(&mut self) -> Option<&mut [CellColor]> {
+        self.colors.as_deref_mut()
+    }
+
     /// Encode a frame into variable-width UTF-8
     pub fn bytes(&self) -> Vec<u8> {
         // worst case scenario - EVERY character uses all 4 code points
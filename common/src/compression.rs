@@ -0,0 +1,210 @@
+use crate::datagram::DatagramError;
+use std::fmt;
+use std::str::FromStr;
+
+/// Largest payload a compressed frame may expand to, so a corrupt or
+/// malicious datagram can't make the receiver allocate without bound
+pub const MAX_DECOMPRESSED_LEN: usize = 16 * 1024 * 1024;
+
+/// Longest run PackBits can describe with a single control byte
+const MAX_RUN: usize = 128;
+
+/// Compression applied to the payload of a frame datagram.
+///
+/// Which codec is used is decided per session by the SFU, out of the codecs
+/// every participant supports, and is written into every frame's header.
+///
+/// # Examples
+///
+/// ```
+/// use common::compression::Codec;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let payload = "      ....::::cccc      ".repeat(20).into_bytes();
+///
+/// for codec in Codec::ALL {
+///     let compressed = codec.compress(&payload);
+///     assert_eq!(codec.decompress(&compressed)?, payload);
+/// }
+/// assert!(Codec::Rle.compress(&payload).len() < payload.len());
+/// assert_eq!("lz4".parse::<Codec>(), Ok(Codec::Lz4));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Codec {
+    /// payload is sent as is
+    None = 0,
+    /// PackBits run-length encoding of repeated bytes
+    Rle = 1,
+    /// LZ4 block compression, prefixed with the uncompressed size
+    Lz4 = 2,
+}
+
+impl Codec {
+    /// Every supported codec, from least to most preferred
+    pub const ALL: [Codec; 3] = [Codec::None, Codec::Rle, Codec::Lz4];
+
+    /// Numeric value of the codec as written in frame headers
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// Map a numeric codec from a frame header back to a `Codec`
+    pub fn from_u8(codec: u8) -> Option<Self> {
+        match codec {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Rle),
+            2 => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+
+    /// Most preferred codec that all of the given codec lists contain.
+    /// Every participant can decode uncompressed payloads, so this falls
+    /// back to `Codec::None`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use common::compression::Codec;
+    ///
+    /// let no_lz4 = [Codec::None, Codec::Rle];
+    /// assert_eq!(Codec::negotiate(&[&Codec::ALL, &Codec::ALL]), Codec::Lz4);
+    /// assert_eq!(Codec::negotiate(&[&Codec::ALL, &no_lz4]), Codec::Rle);
+    /// assert_eq!(Codec::negotiate(&[&Codec::ALL, &[]]), Codec::None);
+    /// ```
+    pub fn negotiate(supported: &[&[Codec]]) -> Codec {
+        Codec::ALL
+            .into_iter()
+            .rev()
+            .find(|codec| supported.iter().all(|list| list.contains(codec)))
+            .unwrap_or(Codec::None)
+    }
+
+    /// Compress a frame payload
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Codec::None => data.to_vec(),
+            Codec::Rle => pack_bits(data),
+            Codec::Lz4 => {
+                let mut out = (data.len() as u32).to_be_bytes().to_vec();
+                out.extend_from_slice(&lz4_flex::block::compress(data));
+                out
+            }
+        }
+    }
+
+    /// Undo `compress`
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>, DatagramError> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Rle => unpack_bits(data),
+            Codec::Lz4 => {
+                if data.len() < 4 {
+                    return Err(DatagramError::BadCompression);
+                }
+                let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                if len > MAX_DECOMPRESSED_LEN {
+                    return Err(DatagramError::BadCompression);
+                }
+
+                lz4_flex::block::decompress(&data[4..], len)
+                    .map_err(|_| DatagramError::BadCompression)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Codec::None => write!(f, "none"),
+            Codec::Rle => write!(f, "rle"),
+            Codec::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Codec::None),
+            "rle" => Ok(Codec::Rle),
+            "lz4" => Ok(Codec::Lz4),
+            other => Err(format!("unknown codec {}", other)),
+        }
+    }
+}
+
+/// PackBits: a control byte `n` is followed by either `n + 1` literal bytes
+/// (`n` < 128), or a single byte repeated `257 - n` times (`n` > 128)
+fn pack_bits(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_RUN + 1);
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    let flush_literals = |out: &mut Vec<u8>, literals: &[u8]| {
+        for chunk in literals.chunks(MAX_RUN) {
+            out.push((chunk.len() - 1) as u8);
+            out.extend_from_slice(chunk);
+        }
+    };
+
+    while i < data.len() {
+        let run = data[i..]
+            .iter()
+            .take(MAX_RUN)
+            .take_while(|&&b| b == data[i])
+            .count();
+
+        // runs of 2 cost as much as literals, only longer ones are worth it
+        if run >= 3 {
+            flush_literals(&mut out, &data[literal_start..i]);
+            out.push((257 - run) as u8);
+            out.push(data[i]);
+            i += run;
+            literal_start = i;
+        } else {
+            i += run;
+        }
+    }
+    flush_literals(&mut out, &data[literal_start..]);
+
+    out
+}
+
+/// Inverse of `pack_bits`
+fn unpack_bits(data: &[u8]) -> Result<Vec<u8>, DatagramError> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut i = 0;
+
+    while i < data.len() {
+        let n = data[i] as usize;
+        i += 1;
+
+        match n {
+            0..=127 => {
+                let literals = data
+                    .get(i..i + n + 1)
+                    .ok_or(DatagramError::BadCompression)?;
+                out.extend_from_slice(literals);
+                i += n + 1;
+            }
+            // no-op in PackBits, never written by `pack_bits`
+            128 => {}
+            _ => {
+                let byte = *data.get(i).ok_or(DatagramError::BadCompression)?;
+                out.resize(out.len() + 257 - n, byte);
+                i += 1;
+            }
+        }
+
+        if out.len() > MAX_DECOMPRESSED_LEN {
+            return Err(DatagramError::BadCompression);
+        }
+    }
+
+    Ok(out)
+}
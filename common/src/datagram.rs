@@ -0,0 +1,481 @@
+use crate::ascii_frame::{AsciiFrame, CellColor, RenderMode};
+use crate::compression::Codec;
+use std::error::Error;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bytes every frame datagram starts with
+pub const FRAME_MAGIC: [u8; 2] = *b"PH";
+
+/// Version of the frame datagram layout, bumped whenever it changes
+pub const FRAME_VERSION: u8 = 4;
+
+/// Layout before colors and render modes were added to the flags. Frames
+/// without them are still written with it, so clients that predate them
+/// can read them
+pub const COMPRESSED_FRAME_VERSION: u8 = 3;
+
+/// Layout before the codec was added to the header. Uncompressed frames
+/// without colors or render modes are still written with it, so clients
+/// that predate compression can read them
+pub const UNCOMPRESSED_FRAME_VERSION: u8 = 2;
+
+/// Flag set on frames that only carry the cells that changed since a
+/// keyframe, see `delta`
+pub const FLAG_DELTA: u8 = 0b0000_0001;
+
+/// Flag set on frames that carry a color for every character, see
+/// `encode_frame`
+pub const FLAG_COLOR: u8 = 0b0000_0010;
+
+/// Flag set on frames made of half blocks (`RenderMode::HalfBlock`), so
+/// receivers that can't show colors know to draw them differently
+pub const FLAG_HALF_BLOCK: u8 = 0b0000_0100;
+
+/// Flag set on frames made of braille patterns (`RenderMode::Braille`)
+pub const FLAG_BRAILLE: u8 = 0b0000_1000;
+
+/// Flags known to layouts before `FRAME_VERSION`
+const LEGACY_FLAGS: u8 = FLAG_DELTA;
+
+/// Flags known to `FRAME_VERSION`, frames with any other are rejected
+const KNOWN_FLAGS: u8 = FLAG_DELTA | FLAG_COLOR | FLAG_HALF_BLOCK | FLAG_BRAILLE;
+
+/// Size of an encoded `FrameHeader` without a codec
+pub const FRAME_HEADER_LEN: usize = 20;
+
+/// Frames older than the newest one by more than this many sequence
+/// numbers are taken as a sign that the sender restarted its stream
+pub const MAX_REORDER: u32 = 64;
+
+/// Failure to decode a frame datagram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DatagramError {
+    /// Datagram is shorter than a `FrameHeader`
+    TooShort(usize),
+    /// Datagram does not start with `FRAME_MAGIC`
+    BadMagic,
+    /// Datagram was written with a different `FRAME_VERSION`
+    UnsupportedVersion(u8),
+    /// Header sets flags (the bits given) its layout version doesn't know
+    UnknownFlags(u8),
+    /// Header announces a frame without any characters
+    EmptyFrame,
+    /// Fragment index is out of range, or disagrees with the other
+    /// fragments of its frame
+    BadFragment,
+    /// Delta frame is based on a keyframe (by sequence number) that the
+    /// receiver does not have
+    MissingKeyframe(u32),
+    /// Delta frame's changes do not fit its keyframe
+    BadDelta,
+    /// Header names a codec this build does not know
+    UnknownCodec(u8),
+    /// Payload could not be decompressed with the codec in the header
+    BadCompression,
+    /// Color plane is cut short or holds an invalid color
+    BadColor,
+}
+
+impl fmt::Display for DatagramError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatagramError::TooShort(len) => write!(f, "datagram too short ({} bytes)", len),
+            DatagramError::BadMagic => write!(f, "datagram is not a frame"),
+            DatagramError::UnsupportedVersion(v) => {
+                write!(f, "unsupported frame version {}", v)
+            }
+            DatagramError::UnknownFlags(flags) => write!(f, "unknown frame flags {:#04x}", flags),
+            DatagramError::EmptyFrame => write!(f, "frame dimensions must be greater than zero"),
+            DatagramError::BadFragment => write!(f, "inconsistent fragment header"),
+            DatagramError::MissingKeyframe(seq) => write!(f, "missing keyframe {}", seq),
+            DatagramError::BadDelta => write!(f, "delta does not fit its keyframe"),
+            DatagramError::UnknownCodec(c) => write!(f, "unknown codec {}", c),
+            DatagramError::BadCompression => write!(f, "payload could not be decompressed"),
+            DatagramError::BadColor => write!(f, "invalid color plane"),
+        }
+    }
+}
+
+impl Error for DatagramError {}
+
+/// Fixed-size header in front of every frame datagram.
+///
+/// All fields are big-endian, laid out as:
+///
+/// | bytes  | field          |
+/// |--------|----------------|
+/// | 0..2   | `FRAME_MAGIC`  |
+/// | 2      | `FRAME_VERSION`|
+/// | 3      | flags          |
+/// | 4..6   | width          |
+/// | 6..8   | height         |
+/// | 8..12  | sequence       |
+/// | 12..20 | timestamp (µs) |
+/// | 20     | codec          |
+///
+/// The codec byte only exists from version 3 on, and is left out (written
+/// as version 2) for uncompressed frames. Version 4 adds the color and
+/// render mode flags, frames without them keep the older versions.
+///
+/// # Examples
+///
+/// ```
+/// use common::compression::Codec;
+/// use common::datagram::{FRAME_HEADER_LEN, FrameHeader};
+///
+/// let mut header = FrameHeader::new(120, 40, 7, 1_700_000_000_000_000);
+/// assert_eq!(header.encode().len(), FRAME_HEADER_LEN);
+/// header.codec = Codec::Rle;
+/// let mut datagram = header.encode();
+/// datagram.extend_from_slice(b"payload");
+///
+/// let (decoded, payload) = FrameHeader::decode(&datagram).unwrap();
+/// assert_eq!(decoded, header);
+/// assert_eq!(payload, b"payload");
+/// assert!(FrameHeader::decode(&datagram[..FRAME_HEADER_LEN - 1]).is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// bit set describing how the payload is encoded, e.g. `FLAG_DELTA`
+    pub flags: u8,
+    /// amount of columns in the frame
+    pub width: u16,
+    /// amount of rows in the frame
+    pub height: u16,
+    /// position of the frame in its sender's stream, wrapping around
+    pub seq: u32,
+    /// when the frame was captured, in microseconds since the UNIX epoch
+    pub timestamp_us: u64,
+    /// compression applied to the payload
+    pub codec: Codec,
+}
+
+impl FrameHeader {
+    pub fn new(width: u16, height: u16, seq: u32, timestamp_us: u64) -> Self {
+        Self {
+            flags: 0,
+            width,
+            height,
+            seq,
+            timestamp_us,
+            codec: Codec::None,
+        }
+    }
+
+    /// Serialize the header into its wire layout, using the oldest layout
+    /// version that can describe it
+    pub fn encode(&self) -> Vec<u8> {
+        let version = if self.flags & !LEGACY_FLAGS != 0 {
+            FRAME_VERSION
+        } else if self.codec == Codec::None {
+            UNCOMPRESSED_FRAME_VERSION
+        } else {
+            COMPRESSED_FRAME_VERSION
+        };
+
+        let mut out = Vec::with_capacity(FRAME_HEADER_LEN + 1);
+        out.extend_from_slice(&FRAME_MAGIC);
+        out.push(version);
+        out.push(self.flags);
+        out.extend_from_slice(&self.width.to_be_bytes());
+        out.extend_from_slice(&self.height.to_be_bytes());
+        out.extend_from_slice(&self.seq.to_be_bytes());
+        out.extend_from_slice(&self.timestamp_us.to_be_bytes());
+        if version >= COMPRESSED_FRAME_VERSION {
+            out.push(self.codec.as_u8());
+        }
+        out
+    }
+
+    /// Split a datagram into its header and payload. Flags the datagram's
+    /// layout version doesn't know are rejected rather than ignored, as
+    /// they may change the layout of the payload
+    pub fn decode(datagram: &[u8]) -> Result<(Self, &[u8]), DatagramError> {
+        if datagram.len() < FRAME_HEADER_LEN {
+            return Err(DatagramError::TooShort(datagram.len()));
+        }
+        if datagram[0..2] != FRAME_MAGIC {
+            return Err(DatagramError::BadMagic);
+        }
+        let version = datagram[2];
+        let (codec, header_len) = match version {
+            UNCOMPRESSED_FRAME_VERSION => (Codec::None, FRAME_HEADER_LEN),
+            COMPRESSED_FRAME_VERSION | FRAME_VERSION => {
+                let raw = *datagram
+                    .get(FRAME_HEADER_LEN)
+                    .ok_or(DatagramError::TooShort(datagram.len()))?;
+                let codec = Codec::from_u8(raw).ok_or(DatagramError::UnknownCodec(raw))?;
+                (codec, FRAME_HEADER_LEN + 1)
+            }
+            other => return Err(DatagramError::UnsupportedVersion(other)),
+        };
+        let known = if version == FRAME_VERSION {
+            KNOWN_FLAGS
+        } else {
+            LEGACY_FLAGS
+        };
+        let flags = datagram[3];
+        if flags & !known != 0 {
+            return Err(DatagramError::UnknownFlags(flags & !known));
+        }
+
+        let u16_at = |i: usize| u16::from_be_bytes([datagram[i], datagram[i + 1]]);
+        let mut seq = [0u8; 4];
+        seq.copy_from_slice(&datagram[8..12]);
+        let mut timestamp_us = [0u8; 8];
+        timestamp_us.copy_from_slice(&datagram[12..20]);
+
+        let header = Self {
+            flags,
+            width: u16_at(4),
+            height: u16_at(6),
+            seq: u32::from_be_bytes(seq),
+            timestamp_us: u64::from_be_bytes(timestamp_us),
+            codec,
+        };
+        if header.width == 0 || header.height == 0 {
+            return Err(DatagramError::EmptyFrame);
+        }
+
+        Ok((header, &datagram[header_len..]))
+    }
+
+    /// Whether the payload holds changes against a keyframe rather than
+    /// the whole frame
+    pub fn is_delta(&self) -> bool {
+        self.flags & FLAG_DELTA != 0
+    }
+
+    /// Whether every character of the frame comes with a color
+    pub fn is_color(&self) -> bool {
+        self.flags & FLAG_COLOR != 0
+    }
+
+    /// How the cells of the frame depict the image
+    pub fn render_mode(&self) -> RenderMode {
+        if self.flags & FLAG_HALF_BLOCK != 0 {
+            RenderMode::HalfBlock
+        } else if self.flags & FLAG_BRAILLE != 0 {
+            RenderMode::Braille
+        } else {
+            RenderMode::Ascii
+        }
+    }
+
+    /// Flags describing the contents of `frame`
+    pub fn flags_for(frame: &AsciiFrame) -> u8 {
+        let mut flags = 0;
+        if frame.colors().is_some() {
+            flags |= FLAG_COLOR;
+        }
+        match frame.mode {
+            RenderMode::Ascii => {}
+            RenderMode::HalfBlock => flags |= FLAG_HALF_BLOCK,
+            RenderMode::Braille => flags |= FLAG_BRAILLE,
+        }
+        flags
+    }
+
+    /// Whether this frame comes after `other` in the sender's stream,
+    /// accounting for the sequence number wrapping around
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use common::datagram::FrameHeader;
+    ///
+    /// let at = |seq| FrameHeader::new(1, 1, seq, 0);
+    /// assert!(at(5).is_newer_than(&at(4)));
+    /// assert!(!at(4).is_newer_than(&at(5)));
+    /// assert!(at(0).is_newer_than(&at(u32::MAX)));
+    /// ```
+    pub fn is_newer_than(&self, other: &FrameHeader) -> bool {
+        (self.seq.wrapping_sub(other.seq) as i32) > 0
+    }
+}
+
+/// Serialize a frame into a datagram, `FrameHeader` followed by the frame's
+/// characters as UTF-8, compressed with `codec`. The characters of a frame
+/// with colors are preceded by every character's `CellColor`, and the
+/// header gets `FLAG_COLOR`. Frames may be at most `u16::MAX` characters in
+/// either dimension
+pub fn encode_frame(frame: &AsciiFrame, seq: u32, timestamp_us: u64, codec: Codec) -> Vec<u8> {
+    let mut header = FrameHeader::new(frame.w as u16, frame.h as u16, seq, timestamp_us);
+    header.codec = codec;
+    header.flags |= FrameHeader::flags_for(frame);
+
+    let mut out = header.encode();
+    out.extend_from_slice(&codec.compress(&keyframe_payload(frame)));
+    out
+}
+
+/// Uncompressed payload of a keyframe, see `encode_frame`
+pub(crate) fn keyframe_payload(frame: &AsciiFrame) -> Vec<u8> {
+    let mut payload = Vec::new();
+    if let Some(colors) = frame.colors() {
+        for color in colors {
+            color.encode(&mut payload);
+        }
+    }
+    payload.extend_from_slice(&frame.bytes());
+    payload
+}
+
+/// Split a datagram into its header and decompressed payload
+pub fn decode_payload(datagram: &[u8]) -> Result<(FrameHeader, Vec<u8>), DatagramError> {
+    let (header, payload) = FrameHeader::decode(datagram)?;
+    Ok((header, header.codec.decompress(payload)?))
+}
+
+/// Deserialize a keyframe datagram written by `encode_frame`
+///
+/// # Examples
+///
+/// ```
+/// use common::ascii_frame::{AsciiFrame, CellColor, RenderMode, Rgb};
+/// use common::compression::Codec;
+/// use common::datagram::{decode_frame, encode_frame};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut frame = AsciiFrame::new(3, 2, '━')?;
+///
+/// let (header, decoded) = decode_frame(&encode_frame(&frame, 42, 1000, Codec::Lz4))?;
+/// assert_eq!((header.seq, header.timestamp_us), (42, 1000));
+/// assert_eq!(decoded.chars(), frame.chars());
+/// assert!(decoded.colors().is_none());
+///
+/// let teal = CellColor {
+///     fg: Some(Rgb::new(0, 128, 128)),
+///     bg: None,
+/// };
+/// frame.set_color(2, 1, teal);
+/// let (header, decoded) = decode_frame(&encode_frame(&frame, 43, 1000, Codec::None))?;
+/// assert!(header.is_color());
+/// assert_eq!(decoded.colors(), frame.colors());
+///
+/// frame.mode = RenderMode::HalfBlock;
+/// let (_, decoded) = decode_frame(&encode_frame(&frame, 44, 1000, Codec::Rle))?;
+/// assert_eq!(decoded.mode, RenderMode::HalfBlock);
+/// # Ok(())
+/// # }
+/// ```
+pub fn decode_frame(datagram: &[u8]) -> Result<(FrameHeader, AsciiFrame), Box<dyn Error>> {
+    let (header, payload) = decode_payload(datagram)?;
+    if header.is_delta() {
+        // needs its keyframe, see `delta::DeltaDecoder`
+        return Err("frame is a delta".into());
+    }
+    let frame = keyframe_from_payload(&header, &payload)?;
+    Ok((header, frame))
+}
+
+/// Frame held by the decompressed payload of a keyframe, see
+/// `keyframe_payload`
+pub(crate) fn keyframe_from_payload(
+    header: &FrameHeader,
+    payload: &[u8],
+) -> Result<AsciiFrame, Box<dyn Error>> {
+    let (w, h) = (header.width as usize, header.height as usize);
+    if !header.is_color() {
+        let mut frame = AsciiFrame::from_bytes(w, h, payload)?;
+        frame.mode = header.render_mode();
+        return Ok(frame);
+    }
+
+    let mut colors = Vec::with_capacity(w * h);
+    let mut rest = payload;
+    for _ in 0..w * h {
+        let (color, len) = CellColor::decode(rest).ok_or(DatagramError::BadColor)?;
+        colors.push(color);
+        rest = &rest[len..];
+    }
+    let mut frame = AsciiFrame::from_bytes(w, h, rest)?.with_colors(colors)?;
+    frame.mode = header.render_mode();
+    Ok(frame)
+}
+
+/// Current time in microseconds since the UNIX epoch, used to stamp frames
+/// when they are captured
+pub fn timestamp_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ascii_frame::Rgb;
+
+    /// 3x2 frame drawn in `mode`, with a color if `colored`
+    fn frame(mode: RenderMode, colored: bool) -> AsciiFrame {
+        let mut frame = AsciiFrame::new(3, 2, '#').unwrap();
+        frame.mode = mode;
+        if colored {
+            let color = CellColor {
+                fg: Some(Rgb::new(255, 0, 0)),
+                bg: None,
+            };
+            frame.set_color(0, 0, color);
+        }
+        frame
+    }
+
+    #[test]
+    fn frames_use_the_oldest_version_that_describes_them() {
+        let version = |frame: &AsciiFrame, codec| encode_frame(frame, 1, 0, codec)[2];
+
+        let plain = frame(RenderMode::Ascii, false);
+        assert_eq!(version(&plain, Codec::None), UNCOMPRESSED_FRAME_VERSION);
+        assert_eq!(version(&plain, Codec::Lz4), COMPRESSED_FRAME_VERSION);
+
+        for (mode, colored) in [
+            (RenderMode::Ascii, true),
+            (RenderMode::HalfBlock, true),
+            (RenderMode::Braille, false),
+        ] {
+            for codec in Codec::ALL {
+                let frame = frame(mode, colored);
+                let datagram = encode_frame(&frame, 1, 0, codec);
+                assert_eq!(datagram[2], FRAME_VERSION, "{mode:?} {codec}");
+
+                let (header, decoded) = decode_frame(&datagram).unwrap();
+                assert_eq!(header.codec, codec);
+                assert_eq!(decoded.mode, mode);
+                assert_eq!(decoded.colors(), frame.colors());
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_flags_are_rejected() {
+        let mut header = FrameHeader::new(3, 2, 1, 0);
+        header.flags = 0b1000_0000;
+        assert_eq!(
+            FrameHeader::decode(&header.encode()),
+            Err(DatagramError::UnknownFlags(0b1000_0000))
+        );
+    }
+
+    #[test]
+    fn older_versions_reject_newer_flags() {
+        // as a peer that predates colors would see a color frame if the
+        // version weren't bumped for it
+        let colored = frame(RenderMode::Ascii, true);
+        for (version, codec) in [
+            (UNCOMPRESSED_FRAME_VERSION, Codec::None),
+            (COMPRESSED_FRAME_VERSION, Codec::Rle),
+        ] {
+            let mut datagram = encode_frame(&colored, 1, 0, codec);
+            datagram[2] = version;
+            if version == UNCOMPRESSED_FRAME_VERSION {
+                datagram.remove(FRAME_HEADER_LEN);
+            }
+            assert_eq!(
+                FrameHeader::decode(&datagram).unwrap_err(),
+                DatagramError::UnknownFlags(FLAG_COLOR)
+            );
+        }
+    }
+}
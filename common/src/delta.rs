@@ -0,0 +1,310 @@
+use crate::ascii_frame::{AsciiFrame, CellColor};
+use crate::compression::Codec;
+use crate::datagram::{
+    DatagramError, FLAG_DELTA, FrameHeader, decode_payload, encode_frame, keyframe_from_payload,
+    keyframe_payload,
+};
+use crate::protocol::ParticipantId;
+use std::collections::HashMap;
+use std::error::Error;
+
+/// Default amount of delta frames sent between two keyframes
+pub const DEFAULT_KEYFRAME_INTERVAL: u32 = 60;
+
+/// Size of the header in front of every run of changed cells
+const RUN_HEADER_LEN: usize = 6;
+
+/// Encode the cells of `frame` that differ from `base` as runs, each made of
+/// the index of its first cell (4 bytes, big-endian), the amount of cells
+/// (2 bytes, big-endian) and the cells' characters as UTF-8. In frames with
+/// colors, every character is preceded by its encoded `CellColor`.
+///
+/// Unchanged gaps shorter than a run header are folded into the
+/// surrounding runs. Returns `None` if the frames differ in size or
+/// `RenderMode`, or only one of them has colors.
+///
+/// # Examples
+///
+/// ```
+/// use common::ascii_frame::{AsciiFrame, CellColor, Rgb};
+/// use common::delta::{apply, diff};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let base = AsciiFrame::new(4, 2, '.')?;
+/// let mut frame = base.clone();
+/// frame.set_char(1, 1, '#');
+///
+/// let runs = diff(&base, &frame).unwrap();
+/// assert_eq!(runs, [0, 0, 0, 5, 0, 1, b'#']);
+/// assert_eq!(apply(&base, &runs)?.chars(), frame.chars());
+///
+/// // only the color changed
+/// let green = CellColor {
+///     fg: Some(Rgb::new(0, 255, 0)),
+///     bg: None,
+/// };
+/// let base = frame.clone().with_colors(vec![CellColor::default(); 8])?;
+/// let mut frame = base.clone();
+/// frame.set_color(1, 1, green);
+///
+/// let runs = diff(&base, &frame).unwrap();
+/// assert_eq!(runs, [0, 0, 0, 5, 0, 1, 0b01, 0, 255, 0, b'#']);
+/// assert_eq!(apply(&base, &runs)?.colors(), frame.colors());
+/// # Ok(())
+/// # }
+/// ```
+pub fn diff(base: &AsciiFrame, frame: &AsciiFrame) -> Option<Vec<u8>> {
+    if base.w != frame.w
+        || base.h != frame.h
+        || base.mode != frame.mode
+        || base.colors().is_some() != frame.colors().is_some()
+    {
+        return None;
+    }
+
+    let (old, new) = (base.chars(), frame.chars());
+    let (old_colors, new_colors) = (base.colors(), frame.colors());
+    let changed = |i: usize| {
+        old[i] != new[i]
+            || old_colors
+                .zip(new_colors)
+                .is_some_and(|(o, n)| o[i] != n[i])
+    };
+    let mut out = Vec::new();
+
+    let mut i = 0;
+    while i < new.len() {
+        if !changed(i) {
+            i += 1;
+            continue;
+        }
+
+        // grow the run until the gap of unchanged cells gets too long
+        let start = i;
+        let mut end = i + 1;
+        let mut j = end;
+        while j < new.len() && j - end < RUN_HEADER_LEN && j - start < u16::MAX as usize {
+            if changed(j) {
+                end = j + 1;
+            }
+            j += 1;
+        }
+
+        out.extend_from_slice(&(start as u32).to_be_bytes());
+        out.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        for i in start..end {
+            if let Some(colors) = new_colors {
+                colors[i].encode(&mut out);
+            }
+            let mut buf = [0u8; 4];
+            out.extend_from_slice(new[i].encode_utf8(&mut buf).as_bytes());
+        }
+
+        i = end;
+    }
+
+    Some(out)
+}
+
+/// Apply runs written by `diff` to a copy of `base`
+pub fn apply(base: &AsciiFrame, runs: &[u8]) -> Result<AsciiFrame, DatagramError> {
+    let mut frame = base.clone();
+    let len = frame.chars().len();
+
+    let mut rest = runs;
+    while !rest.is_empty() {
+        if rest.len() < RUN_HEADER_LEN {
+            return Err(DatagramError::BadDelta);
+        }
+        let start = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let run_len = u16::from_be_bytes([rest[4], rest[5]]) as usize;
+        rest = &rest[RUN_HEADER_LEN..];
+        if start + run_len > len {
+            return Err(DatagramError::BadDelta);
+        }
+
+        for i in start..start + run_len {
+            if let Some(colors) = frame.colors_mut() {
+                let (color, width) = CellColor::decode(rest).ok_or(DatagramError::BadColor)?;
+                colors[i] = color;
+                rest = &rest[width..];
+            }
+            let (c, width) = next_char(rest).ok_or(DatagramError::BadDelta)?;
+            frame.chars_mut()[i] = c;
+            rest = &rest[width..];
+        }
+    }
+
+    Ok(frame)
+}
+
+/// Decode the UTF-8 character at the start of `bytes`, along with its width
+fn next_char(bytes: &[u8]) -> Option<(char, usize)> {
+    let width = match *bytes.first()? {
+        b if b < 0x80 => 1,
+        b if b >= 0xF0 => 4,
+        b if b >= 0xE0 => 3,
+        b if b >= 0xC0 => 2,
+        _ => return None,
+    };
+
+    let c = std::str::from_utf8(bytes.get(..width)?)
+        .ok()?
+        .chars()
+        .next()?;
+    Some((c, width))
+}
+
+/// Sending side of the keyframe + delta encoding.
+///
+/// Every `interval` frames (or when asked to) a keyframe carrying the whole
+/// frame is sent. The frames in between only carry the cells that changed
+/// since the last keyframe sent, so a lost delta never breaks the ones
+/// after it. Keyframes aren't acknowledged: a receiver that lost one asks
+/// for a new one (see `request_keyframe`).
+///
+/// # Examples
+///
+/// ```
+/// use common::ascii_frame::AsciiFrame;
+/// use common::delta::{DeltaDecoder, DeltaEncoder};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut encoder = DeltaEncoder::default();
+/// let mut decoder = DeltaDecoder::default();
+///
+/// let mut frame = AsciiFrame::new(40, 10, ' ')?;
+/// let keyframe = encoder.encode(&frame, 0, 0);
+///
+/// frame.set_char(3, 3, '@');
+/// let delta = encoder.encode(&frame, 1, 0);
+/// assert!(delta.len() < keyframe.len());
+///
+/// // deltas can't be applied before their keyframe arrived
+/// assert!(decoder.decode(7, &delta).is_err());
+/// decoder.decode(7, &keyframe)?;
+/// let (header, decoded) = decoder.decode(7, &delta)?;
+/// assert!(header.is_delta());
+/// assert_eq!(decoded.chars(), frame.chars());
+/// # Ok(())
+/// # }
+/// ```
+pub struct DeltaEncoder {
+    /// amount of delta frames sent between two keyframes
+    interval: u32,
+    /// sequence number and contents of the last keyframe sent
+    keyframe: Option<(u32, AsciiFrame)>,
+    /// amount of delta frames sent since the last keyframe
+    since_keyframe: u32,
+    /// a receiver could not apply a delta, so the next frame is a keyframe
+    keyframe_requested: bool,
+    /// compression applied to every frame's payload
+    codec: Codec,
+}
+
+impl Default for DeltaEncoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_KEYFRAME_INTERVAL)
+    }
+}
+
+impl DeltaEncoder {
+    pub fn new(interval: u32) -> Self {
+        Self {
+            interval,
+            keyframe: None,
+            since_keyframe: 0,
+            keyframe_requested: false,
+            codec: Codec::None,
+        }
+    }
+
+    /// Compress the payloads of the following frames with `codec`
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// Make the next encoded frame a keyframe
+    pub fn request_keyframe(&mut self) {
+        self.keyframe_requested = true;
+    }
+
+    /// Serialize a frame into a datagram, either as a delta against the last
+    /// keyframe sent, or as a new keyframe when one is due, the frame changed
+    /// size, or the delta would not be any smaller
+    pub fn encode(&mut self, frame: &AsciiFrame, seq: u32, timestamp_us: u64) -> Vec<u8> {
+        if !self.keyframe_requested
+            && self.since_keyframe < self.interval
+            && let Some((base_seq, base)) = &self.keyframe
+            && let Some(runs) = diff(base, frame)
+            && runs.len() + 4 < keyframe_payload(frame).len()
+        {
+            let mut header = FrameHeader::new(frame.w as u16, frame.h as u16, seq, timestamp_us);
+            header.flags |= FLAG_DELTA | FrameHeader::flags_for(frame);
+            header.codec = self.codec;
+
+            let mut payload = base_seq.to_be_bytes().to_vec();
+            payload.extend_from_slice(&runs);
+
+            let mut out = header.encode();
+            out.extend_from_slice(&self.codec.compress(&payload));
+
+            self.since_keyframe += 1;
+            return out;
+        }
+
+        self.keyframe = Some((seq, frame.clone()));
+        self.since_keyframe = 0;
+        self.keyframe_requested = false;
+        encode_frame(frame, seq, timestamp_us, self.codec)
+    }
+}
+
+/// Receiving side of the keyframe + delta encoding, keeping the last
+/// keyframe of every sender
+#[derive(Default)]
+pub struct DeltaDecoder {
+    keyframes: HashMap<ParticipantId, (u32, AsciiFrame)>,
+}
+
+impl DeltaDecoder {
+    /// Deserialize a datagram written by a `DeltaEncoder`.
+    ///
+    /// Fails with `DatagramError::MissingKeyframe` if the frame is a delta
+    /// against a keyframe that never arrived. On that or any other failure
+    /// the sender should be asked for a new keyframe.
+    pub fn decode(
+        &mut self,
+        sender: ParticipantId,
+        datagram: &[u8],
+    ) -> Result<(FrameHeader, AsciiFrame), Box<dyn Error>> {
+        let (header, payload) = decode_payload(datagram)?;
+        if !header.is_delta() {
+            let frame = keyframe_from_payload(&header, &payload)?;
+            self.keyframes.insert(sender, (header.seq, frame.clone()));
+            return Ok((header, frame));
+        }
+
+        if payload.len() < 4 {
+            return Err(DatagramError::TooShort(datagram.len()).into());
+        }
+        let base_seq = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+
+        let base = match self.keyframes.get(&sender) {
+            Some((seq, base)) if *seq == base_seq => base,
+            _ => return Err(DatagramError::MissingKeyframe(base_seq).into()),
+        };
+        if base.w != header.width as usize
+            || base.h != header.height as usize
+            || base.mode != header.render_mode()
+            || base.colors().is_some() != header.is_color()
+        {
+            return Err(DatagramError::BadDelta.into());
+        }
+
+        Ok((header, apply(base, &payload[4..])?))
+    }
+
+    /// Forget the keyframes of senders that `keep` rejects
+    pub fn retain_senders(&mut self, mut keep: impl FnMut(&ParticipantId) -> bool) {
+        self.keyframes.retain(|id, _| keep(id));
+    }
+}
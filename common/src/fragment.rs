@@ -0,0 +1,183 @@
+use crate::datagram::DatagramError;
+use crate::protocol::ParticipantId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Bytes every fragment starts with
+pub const FRAGMENT_MAGIC: [u8; 2] = *b"PF";
+
+/// Size of an encoded fragment header
+pub const FRAGMENT_HEADER_LEN: usize = 10;
+
+/// Most frame bytes carried by a single fragment. Together with the
+/// fragment, relay, UDP and IP headers this stays below the 1280 byte
+/// minimum MTU of IPv6, so fragments are never split up by IP
+pub const MAX_FRAGMENT_PAYLOAD: usize = 1200;
+
+/// Largest datagram a client sends
+pub const MAX_FRAGMENT_LEN: usize = FRAGMENT_HEADER_LEN + MAX_FRAGMENT_PAYLOAD;
+
+/// How long the fragments of an incomplete frame are kept around
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Most incomplete frames kept per sender, older ones are given up on first
+pub const MAX_PARTIAL_FRAMES: usize = 4;
+
+/// Split an encoded frame into datagrams of at most `MAX_FRAGMENT_LEN` bytes.
+///
+/// Each fragment starts with a header (big-endian) made of `FRAGMENT_MAGIC`,
+/// the 4 byte `frame_id`, then the 2 byte index of the fragment and the
+/// 2 byte amount of fragments the frame was split into.
+///
+/// # Examples
+///
+/// ```
+/// use common::fragment::{MAX_FRAGMENT_LEN, Reassembler, fragment};
+/// use std::time::Instant;
+///
+/// let frame = vec![7u8; 3000];
+/// let fragments = fragment(1, &frame);
+/// assert_eq!(fragments.len(), 3);
+/// assert!(fragments.iter().all(|f| f.len() <= MAX_FRAGMENT_LEN));
+///
+/// // fragments may arrive in any order
+/// let mut reassembler = Reassembler::default();
+/// let now = Instant::now();
+/// assert_eq!(reassembler.push(9, &fragments[2], now), Ok(None));
+/// assert_eq!(reassembler.push(9, &fragments[0], now), Ok(None));
+/// assert_eq!(reassembler.push(9, &fragments[1], now), Ok(Some(frame)));
+/// ```
+pub fn fragment(frame_id: u32, data: &[u8]) -> Vec<Vec<u8>> {
+    // an empty frame still needs a fragment to arrive at all
+    let count = data.len().div_ceil(MAX_FRAGMENT_PAYLOAD).max(1);
+
+    (0..count)
+        .map(|index| {
+            let start = index * MAX_FRAGMENT_PAYLOAD;
+            let chunk = &data[start..(start + MAX_FRAGMENT_PAYLOAD).min(data.len())];
+
+            let mut out = Vec::with_capacity(FRAGMENT_HEADER_LEN + chunk.len());
+            out.extend_from_slice(&FRAGMENT_MAGIC);
+            out.extend_from_slice(&frame_id.to_be_bytes());
+            out.extend_from_slice(&(index as u16).to_be_bytes());
+            out.extend_from_slice(&(count as u16).to_be_bytes());
+            out.extend_from_slice(chunk);
+            out
+        })
+        .collect()
+}
+
+/// Frame whose fragments have only partly arrived
+struct Partial {
+    frame_id: u32,
+    /// when the first fragment arrived
+    started: Instant,
+    /// fragment payloads by index, `None` until received
+    parts: Vec<Option<Vec<u8>>>,
+    /// amount of `Some` entries in `parts`
+    received: usize,
+}
+
+/// Puts fragmented frames back together, separately for every sender.
+///
+/// Frames that are still incomplete after `timeout` (a fragment was lost)
+/// are discarded.
+pub struct Reassembler {
+    timeout: Duration,
+    /// incomplete frames of each sender, oldest first
+    partials: HashMap<ParticipantId, Vec<Partial>>,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(REASSEMBLY_TIMEOUT)
+    }
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            partials: HashMap::new(),
+        }
+    }
+
+    /// Add a fragment received from `sender` at `now`, returning the whole
+    /// frame once its last missing fragment arrives
+    pub fn push(
+        &mut self,
+        sender: ParticipantId,
+        datagram: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, DatagramError> {
+        if datagram.len() < FRAGMENT_HEADER_LEN {
+            return Err(DatagramError::TooShort(datagram.len()));
+        }
+        if datagram[0..2] != FRAGMENT_MAGIC {
+            return Err(DatagramError::BadMagic);
+        }
+
+        let frame_id = u32::from_be_bytes([datagram[2], datagram[3], datagram[4], datagram[5]]);
+        let index = u16::from_be_bytes([datagram[6], datagram[7]]) as usize;
+        let count = u16::from_be_bytes([datagram[8], datagram[9]]) as usize;
+        let payload = &datagram[FRAGMENT_HEADER_LEN..];
+        if index >= count {
+            return Err(DatagramError::BadFragment);
+        }
+
+        // common case, nothing to put together
+        if count == 1 {
+            return Ok(Some(payload.to_vec()));
+        }
+
+        self.expire(now);
+        let partials = self.partials.entry(sender).or_default();
+
+        let i = match partials.iter().position(|p| p.frame_id == frame_id) {
+            Some(i) => i,
+            None => {
+                if partials.len() == MAX_PARTIAL_FRAMES {
+                    partials.remove(0);
+                }
+                partials.push(Partial {
+                    frame_id,
+                    started: now,
+                    parts: vec![None; count],
+                    received: 0,
+                });
+                partials.len() - 1
+            }
+        };
+
+        let partial = &mut partials[i];
+        if partial.parts.len() != count {
+            return Err(DatagramError::BadFragment);
+        }
+        if partial.parts[index].is_none() {
+            partial.parts[index] = Some(payload.to_vec());
+            partial.received += 1;
+        }
+
+        if partial.received < count {
+            return Ok(None);
+        }
+
+        let partial = partials.remove(i);
+        Ok(Some(
+            partial.parts.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    /// Discard incomplete frames that have been waiting longer than the timeout
+    pub fn expire(&mut self, now: Instant) {
+        for partials in self.partials.values_mut() {
+            partials.retain(|p| now.duration_since(p.started) < self.timeout);
+        }
+        self.partials.retain(|_, partials| !partials.is_empty());
+    }
+
+    /// Forget the incomplete frames of senders that `keep` rejects
+    pub fn retain_senders(&mut self, mut keep: impl FnMut(&ParticipantId) -> bool) {
+        self.partials.retain(|id, _| keep(id));
+    }
+}
@@ -1,2 +1,7 @@
 pub mod ascii_frame;
-pub mod logger;
\ No newline at end of file
+pub mod compression;
+pub mod datagram;
+pub mod delta;
+pub mod fragment;
+pub mod logger;
+pub mod protocol;
@@ -0,0 +1,777 @@
+use crate::compression::Codec;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Version of the control protocol spoken over TCP. Exchanged in the
+/// `HELLO` / `WELCOME` handshake, peers with a different version are rejected
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// Longest accepted control message (excluding the newline delimiter).
+/// Anything longer is treated as a misbehaving peer
+pub const MAX_MESSAGE_LEN: usize = 1024;
+
+/// Check the version a peer sent in `HELLO` / `WELCOME` against
+/// `PROTOCOL_VERSION`
+///
+/// # Examples
+///
+/// ```
+/// use common::protocol::{ErrorCode, PROTOCOL_VERSION, check_version};
+///
+/// assert!(check_version(PROTOCOL_VERSION).is_ok());
+/// let err = check_version(PROTOCOL_VERSION + 1).unwrap_err();
+/// assert_eq!(err.code, ErrorCode::UnsupportedVersion);
+/// ```
+pub fn check_version(version: u16) -> Result<(), ProtocolError> {
+    if version == PROTOCOL_VERSION {
+        return Ok(());
+    }
+
+    Err(ProtocolError::new(
+        ErrorCode::UnsupportedVersion,
+        format!("expected version {}, got {}", PROTOCOL_VERSION, version),
+    ))
+}
+
+/// Prefix of the UDP datagram a client sends to bind its UDP address
+/// to its control connection, followed by the client's `RegistrationToken`
+pub const PING_PREFIX: &[u8] = b"PING ";
+
+/// Identifies a client within its session, assigned by the SFU on join
+pub type ParticipantId = u32;
+
+/// Size of the header the SFU puts in front of every forwarded datagram
+pub const RELAY_HEADER_LEN: usize = 4;
+
+/// Header the SFU prepends to a forwarded datagram, naming the participant
+/// that sent it
+pub fn encode_relay_header(sender: ParticipantId) -> [u8; RELAY_HEADER_LEN] {
+    sender.to_be_bytes()
+}
+
+/// Split a datagram forwarded by the SFU into its sender and payload
+///
+/// # Examples
+///
+/// ```
+/// use common::protocol::{decode_relayed, encode_relay_header};
+///
+/// let mut datagram = encode_relay_header(7).to_vec();
+/// datagram.extend_from_slice(b"frame");
+/// assert_eq!(decode_relayed(&datagram), Some((7, &b"frame"[..])));
+/// assert_eq!(decode_relayed(b"ab"), None);
+/// ```
+pub fn decode_relayed(datagram: &[u8]) -> Option<(ParticipantId, &[u8])> {
+    if datagram.len() < RELAY_HEADER_LEN {
+        return None;
+    }
+
+    let (header, payload) = datagram.split_at(RELAY_HEADER_LEN);
+    let sender = ParticipantId::from_be_bytes(header.try_into().ok()?);
+    Some((sender, payload))
+}
+
+/// Machine-readable reason attached to an `ERROR` reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Peer speaks a different `PROTOCOL_VERSION`
+    UnsupportedVersion = 100,
+    /// A command was sent before the `HELLO` handshake completed
+    HandshakeRequired = 101,
+    /// Message could not be parsed (missing / invalid arguments, bad UTF-8)
+    Malformed = 102,
+    /// Command is not part of the protocol
+    UnknownCommand = 103,
+    /// Message exceeded `MAX_MESSAGE_LEN`
+    MessageTooLong = 104,
+    /// Command is valid, but not something the receiver accepts
+    UnexpectedMessage = 105,
+    /// Session has no open slots
+    SessionFull = 200,
+    /// Command requires the client to be in a session
+    NotInSession = 201,
+    /// Client tried to join a session while already in one
+    AlreadyInSession = 202,
+    /// No participant with the given ID is in the client's session
+    UnknownParticipant = 203,
+}
+
+impl ErrorCode {
+    /// Numeric value of the code as sent on the wire
+    pub fn as_u16(self) -> u16 {
+        self as u16
+    }
+
+    /// Map a numeric code from the wire back to an `ErrorCode`
+    pub fn from_u16(code: u16) -> Option<Self> {
+        match code {
+            100 => Some(ErrorCode::UnsupportedVersion),
+            101 => Some(ErrorCode::HandshakeRequired),
+            102 => Some(ErrorCode::Malformed),
+            103 => Some(ErrorCode::UnknownCommand),
+            104 => Some(ErrorCode::MessageTooLong),
+            105 => Some(ErrorCode::UnexpectedMessage),
+            200 => Some(ErrorCode::SessionFull),
+            201 => Some(ErrorCode::NotInSession),
+            202 => Some(ErrorCode::AlreadyInSession),
+            203 => Some(ErrorCode::UnknownParticipant),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_u16())
+    }
+}
+
+/// Failure to decode a control message, carrying the `ErrorCode` that
+/// should be reported back to the sender
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "protocol error {}: {}", self.code, self.reason)
+    }
+}
+
+impl Error for ProtocolError {}
+
+/// Random secret handed to a client when it joins a session.
+///
+/// The client echoes the token in its `PING` datagram, which is the only way
+/// the SFU associates a UDP source address with a TCP control connection.
+/// Written on the wire as 16 hexadecimal digits.
+///
+/// # Examples
+///
+/// ```
+/// use common::protocol::RegistrationToken;
+///
+/// let token = RegistrationToken(0xdead_beef);
+/// let ping = token.ping_datagram();
+/// assert_eq!(ping, b"PING 00000000deadbeef");
+/// assert_eq!(RegistrationToken::from_ping(&ping), Some(Ok(token)));
+/// assert_eq!(RegistrationToken::from_ping(b"not a ping"), None);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RegistrationToken(pub u64);
+
+impl RegistrationToken {
+    /// Build the UDP datagram used to register with the SFU
+    pub fn ping_datagram(&self) -> Vec<u8> {
+        let mut out = PING_PREFIX.to_vec();
+        out.extend_from_slice(self.to_string().as_bytes());
+        out
+    }
+
+    /// Extract the token from a `PING` datagram.
+    ///
+    /// Returns `None` if the datagram is not a `PING` at all, and an error
+    /// if it is one but the token is unreadable.
+    pub fn from_ping(datagram: &[u8]) -> Option<Result<Self, ProtocolError>> {
+        let token = datagram.strip_prefix(PING_PREFIX)?;
+
+        Some(
+            std::str::from_utf8(token)
+                .map_err(|_| ProtocolError::new(ErrorCode::Malformed, "token is not UTF-8"))
+                .and_then(|t| t.trim().parse()),
+        )
+    }
+}
+
+impl fmt::Display for RegistrationToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for RegistrationToken {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 16 {
+            return Err(ProtocolError::new(
+                ErrorCode::Malformed,
+                "token must be 16 hex digits",
+            ));
+        }
+
+        u64::from_str_radix(s, 16)
+            .map(RegistrationToken)
+            .map_err(|_| ProtocolError::new(ErrorCode::Malformed, "token must be 16 hex digits"))
+    }
+}
+
+/// Messages exchanged between clients and the SFU over the TCP control channel
+///
+/// Every message is a single line of whitespace-separated UTF-8 text,
+/// starting with an upper-case command word, e.g. `JOIN standup`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// client -> server, first message on every connection, listing the
+    /// frame codecs the client can decode (clients that predate compression
+    /// list none)
+    Hello { version: u16, codecs: Vec<Codec> },
+    /// server -> client, handshake accepted
+    Welcome { version: u16 },
+    /// client -> server, join (or create) a session, optionally under a
+    /// display name shown to the other participants
+    Join {
+        session_id: String,
+        name: Option<String>,
+    },
+    /// server -> client, client is now part of the session and should
+    /// register its UDP address by sending `token` in a `PING` datagram
+    Joined {
+        session_id: String,
+        participant_id: ParticipantId,
+        token: RegistrationToken,
+    },
+    /// server -> client, the client's `PING` was accepted and its UDP
+    /// address is now bound to this control connection
+    Registered,
+    /// client -> server, leave the current session
+    Leave,
+    /// server -> client, client has left its session
+    Left,
+    /// server -> client, a participant is present and streaming in the session
+    PeerJoined {
+        participant_id: ParticipantId,
+        name: String,
+    },
+    /// server -> client, a participant has left the session
+    PeerLeft { participant_id: ParticipantId },
+    /// server -> client, frames sent to the session should be compressed
+    /// with `codec` from now on. Only sent to clients that listed codecs
+    Codec { codec: Codec },
+    /// client -> server, ask the participant with the given ID to send a
+    /// keyframe, because a delta frame could not be applied.
+    /// server -> client, send a keyframe, the participant with the given
+    /// ID asked for it
+    RequestKeyframe { participant_id: ParticipantId },
+    /// client -> server, the participant with the given ID is shown in an
+    /// area of `width` x `height` characters, so should send frames of
+    /// that size.
+    /// server -> client, the participant with the given ID shows this
+    /// client's frames in an area of `width` x `height` characters
+    Viewport {
+        participant_id: ParticipantId,
+        width: u16,
+        height: u16,
+    },
+    /// either direction, request could not be fulfilled
+    Error { code: ErrorCode, reason: String },
+}
+
+impl ControlMessage {
+    /// Build an `ERROR` reply
+    pub fn error(code: ErrorCode, reason: impl Into<String>) -> Self {
+        ControlMessage::Error {
+            code,
+            reason: reason.into(),
+        }
+    }
+
+    /// Check that every argument of the message fits on its line: session
+    /// IDs and names are single arguments, so must be `is_single_argument`,
+    /// and error reasons may contain spaces but no control characters
+    pub fn validate(&self) -> Result<(), ProtocolError> {
+        let invalid = |what: &str, arg: &str| {
+            ProtocolError::new(ErrorCode::Malformed, format!("invalid {}: {:?}", what, arg))
+        };
+
+        match self {
+            ControlMessage::Join { session_id, name } => {
+                if !is_single_argument(session_id) {
+                    return Err(invalid("session ID", session_id));
+                }
+                if let Some(name) = name
+                    && !is_single_argument(name)
+                {
+                    return Err(invalid("name", name));
+                }
+            }
+            ControlMessage::Joined { session_id, .. } if !is_single_argument(session_id) => {
+                return Err(invalid("session ID", session_id));
+            }
+            ControlMessage::PeerJoined { name, .. } if !is_single_argument(name) => {
+                return Err(invalid("name", name));
+            }
+            ControlMessage::Error { reason, .. } if reason.chars().any(char::is_control) => {
+                return Err(invalid("error reason", reason));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Whether `arg` can be sent as a single argument of a `ControlMessage`:
+/// non-empty, without whitespace or control characters
+pub fn is_single_argument(arg: &str) -> bool {
+    !arg.is_empty() && !arg.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+impl From<ProtocolError> for ControlMessage {
+    fn from(e: ProtocolError) -> Self {
+        ControlMessage::Error {
+            code: e.code,
+            reason: e.reason,
+        }
+    }
+}
+
+impl fmt::Display for ControlMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControlMessage::Hello { version, codecs } => {
+                write!(f, "HELLO {}", version)?;
+                if !codecs.is_empty() {
+                    let codecs: Vec<_> = codecs.iter().map(Codec::to_string).collect();
+                    write!(f, " {}", codecs.join(","))?;
+                }
+                Ok(())
+            }
+            ControlMessage::Welcome { version } => write!(f, "WELCOME {}", version),
+            ControlMessage::Join { session_id, name } => match name {
+                Some(name) => write!(f, "JOIN {} {}", session_id, name),
+                None => write!(f, "JOIN {}", session_id),
+            },
+            ControlMessage::Joined {
+                session_id,
+                participant_id,
+                token,
+            } => write!(f, "JOINED {} {} {}", session_id, participant_id, token),
+            ControlMessage::Registered => write!(f, "REGISTERED"),
+            ControlMessage::Leave => write!(f, "LEAVE"),
+            ControlMessage::Left => write!(f, "LEFT"),
+            ControlMessage::PeerJoined {
+                participant_id,
+                name,
+            } => write!(f, "PEER_JOINED {} {}", participant_id, name),
+            ControlMessage::PeerLeft { participant_id } => {
+                write!(f, "PEER_LEFT {}", participant_id)
+            }
+            ControlMessage::Codec { codec } => write!(f, "CODEC {}", codec),
+            ControlMessage::RequestKeyframe { participant_id } => {
+                write!(f, "REQUEST_KEYFRAME {}", participant_id)
+            }
+            ControlMessage::Viewport {
+                participant_id,
+                width,
+                height,
+            } => write!(f, "VIEWPORT {} {} {}", participant_id, width, height),
+            ControlMessage::Error { code, reason } => {
+                if reason.is_empty() {
+                    write!(f, "ERROR {}", code)
+                } else {
+                    write!(f, "ERROR {} {}", code, reason)
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for ControlMessage {
+    type Err = ProtocolError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut parts = line.split_whitespace();
+        let command = parts
+            .next()
+            .ok_or_else(|| ProtocolError::new(ErrorCode::Malformed, "empty message"))?;
+
+        let msg = match command {
+            "HELLO" => ControlMessage::Hello {
+                version: parse_arg(&mut parts, command)?,
+                // codecs added by newer clients are skipped, not rejected
+                codecs: parts
+                    .next()
+                    .map(|list| list.split(',').filter_map(|c| c.parse().ok()).collect())
+                    .unwrap_or_default(),
+            },
+            "WELCOME" => ControlMessage::Welcome {
+                version: parse_arg(&mut parts, command)?,
+            },
+            "JOIN" => ControlMessage::Join {
+                session_id: parse_arg(&mut parts, command)?,
+                name: parts.next().map(str::to_string),
+            },
+            "JOINED" => ControlMessage::Joined {
+                session_id: parse_arg(&mut parts, command)?,
+                participant_id: parse_arg(&mut parts, command)?,
+                token: parse_arg(&mut parts, command)?,
+            },
+            "REGISTERED" => ControlMessage::Registered,
+            "LEAVE" => ControlMessage::Leave,
+            "LEFT" => ControlMessage::Left,
+            "PEER_JOINED" => ControlMessage::PeerJoined {
+                participant_id: parse_arg(&mut parts, command)?,
+                name: parse_arg(&mut parts, command)?,
+            },
+            "PEER_LEFT" => ControlMessage::PeerLeft {
+                participant_id: parse_arg(&mut parts, command)?,
+            },
+            "CODEC" => ControlMessage::Codec {
+                codec: parse_arg(&mut parts, command)?,
+            },
+            "REQUEST_KEYFRAME" => ControlMessage::RequestKeyframe {
+                participant_id: parse_arg(&mut parts, command)?,
+            },
+            "VIEWPORT" => ControlMessage::Viewport {
+                participant_id: parse_arg(&mut parts, command)?,
+                width: parse_arg(&mut parts, command)?,
+                height: parse_arg(&mut parts, command)?,
+            },
+            "ERROR" => {
+                let raw: u16 = parse_arg(&mut parts, command)?;
+                let code = ErrorCode::from_u16(raw).ok_or_else(|| {
+                    ProtocolError::new(ErrorCode::Malformed, format!("unknown error code {}", raw))
+                })?;
+                // the reason is free text, so it consumes the rest of the line
+                let reason = parts.collect::<Vec<_>>().join(" ");
+                return Ok(ControlMessage::Error { code, reason });
+            }
+            other => {
+                return Err(ProtocolError::new(
+                    ErrorCode::UnknownCommand,
+                    format!("unknown command {}", other),
+                ));
+            }
+        };
+
+        if parts.next().is_some() {
+            return Err(ProtocolError::new(
+                ErrorCode::Malformed,
+                format!("too many arguments for {}", command),
+            ));
+        }
+
+        msg.validate()?;
+        Ok(msg)
+    }
+}
+
+/// Pull the next whitespace-separated argument of `command` and parse it
+fn parse_arg<'a, T: FromStr>(
+    parts: &mut impl Iterator<Item = &'a str>,
+    command: &str,
+) -> Result<T, ProtocolError> {
+    let arg = parts.next().ok_or_else(|| {
+        ProtocolError::new(
+            ErrorCode::Malformed,
+            format!("missing argument for {}", command),
+        )
+    })?;
+
+    arg.parse().map_err(|_| {
+        ProtocolError::new(
+            ErrorCode::Malformed,
+            format!("invalid argument for {}: {}", command, arg),
+        )
+    })
+}
+
+/// Newline-delimited framing for `ControlMessage`s.
+///
+/// A single `read` from a TCP stream may contain part of a message, or
+/// several messages at once, so received bytes are buffered until a full
+/// line is available.
+///
+/// # Examples
+///
+/// ```
+/// use common::protocol::{ControlCodec, ControlMessage};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut codec = ControlCodec::new();
+///
+/// // a message split across two reads
+/// codec.extend(b"JOIN stan");
+/// assert_eq!(codec.decode_next()?, None);
+/// codec.extend(b"dup\nLEAVE\n");
+///
+/// assert_eq!(
+///     codec.decode_next()?,
+///     Some(ControlMessage::Join {
+///         session_id: "standup".to_string(),
+///         name: None,
+///     })
+/// );
+/// assert_eq!(codec.decode_next()?, Some(ControlMessage::Leave));
+/// assert_eq!(codec.decode_next()?, None);
+///
+/// assert_eq!(ControlCodec::encode(&ControlMessage::Leave)?, b"LEAVE\n");
+///
+/// // session IDs are a single argument
+/// let join = ControlMessage::Join {
+///     session_id: "my session".to_string(),
+///     name: None,
+/// };
+/// assert!(ControlCodec::encode(&join).is_err());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ControlCodec {
+    /// bytes received that do not yet form a complete line
+    buffer: Vec<u8>,
+    /// set after a line grew past `MAX_MESSAGE_LEN`: its remaining bytes,
+    /// up to and including the next `\n`, are dropped rather than decoded
+    discarding: bool,
+}
+
+impl ControlCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serialize a message into a single newline-terminated line. Messages
+    /// that would not decode to themselves (see `ControlMessage::validate`)
+    /// are refused
+    pub fn encode(msg: &ControlMessage) -> Result<Vec<u8>, ProtocolError> {
+        msg.validate()?;
+        let mut out = msg.to_string().into_bytes();
+        out.push(b'\n');
+        Ok(out)
+    }
+
+    /// Append freshly received bytes to the codec's buffer
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Pop the next complete message from the buffer, if there is one.
+    ///
+    /// Blank lines are skipped. A line that fails to parse is consumed and
+    /// reported as an error, so decoding can continue with the next line.
+    /// A line that is too long is reported once, and the rest of it is
+    /// skipped as it arrives.
+    pub fn decode_next(&mut self) -> Result<Option<ControlMessage>, ProtocolError> {
+        loop {
+            if self.discarding {
+                match self.buffer.iter().position(|&b| b == b'\n') {
+                    Some(end) => {
+                        self.buffer.drain(..=end);
+                        self.discarding = false;
+                    }
+                    None => {
+                        self.buffer.clear();
+                        return Ok(None);
+                    }
+                }
+            }
+
+            let Some(end) = self.buffer.iter().position(|&b| b == b'\n') else {
+                if self.buffer.len() > MAX_MESSAGE_LEN {
+                    self.buffer.clear();
+                    self.discarding = true;
+                    return Err(ProtocolError::new(
+                        ErrorCode::MessageTooLong,
+                        format!("message exceeds {} bytes", MAX_MESSAGE_LEN),
+                    ));
+                }
+                return Ok(None);
+            };
+
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            if line.len() > MAX_MESSAGE_LEN + 1 {
+                return Err(ProtocolError::new(
+                    ErrorCode::MessageTooLong,
+                    format!("message exceeds {} bytes", MAX_MESSAGE_LEN),
+                ));
+            }
+
+            let text = std::str::from_utf8(&line)
+                .map_err(|_| ProtocolError::new(ErrorCode::Malformed, "message is not UTF-8"))?;
+
+            // tolerate CRLF line endings (e.g. from `nc` or telnet)
+            let text = text.trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            return text.parse().map(Some);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One message of every variant, with every optional part both present
+    /// and absent
+    fn every_message() -> Vec<ControlMessage> {
+        vec![
+            ControlMessage::Hello {
+                version: PROTOCOL_VERSION,
+                codecs: vec![],
+            },
+            ControlMessage::Hello {
+                version: PROTOCOL_VERSION,
+                codecs: Codec::ALL.to_vec(),
+            },
+            ControlMessage::Welcome {
+                version: PROTOCOL_VERSION,
+            },
+            ControlMessage::Join {
+                session_id: "standup".to_string(),
+                name: None,
+            },
+            ControlMessage::Join {
+                session_id: "standup".to_string(),
+                name: Some("ada".to_string()),
+            },
+            ControlMessage::Joined {
+                session_id: "standup".to_string(),
+                participant_id: 3,
+                token: RegistrationToken(0xdead_beef),
+            },
+            ControlMessage::Registered,
+            ControlMessage::Leave,
+            ControlMessage::Left,
+            ControlMessage::PeerJoined {
+                participant_id: 4,
+                name: "grace".to_string(),
+            },
+            ControlMessage::PeerLeft { participant_id: 4 },
+            ControlMessage::Codec { codec: Codec::Lz4 },
+            ControlMessage::RequestKeyframe { participant_id: 5 },
+            ControlMessage::Viewport {
+                participant_id: 6,
+                width: 80,
+                height: 24,
+            },
+            ControlMessage::error(ErrorCode::SessionFull, ""),
+            ControlMessage::error(ErrorCode::SessionFull, "session standup is full"),
+        ]
+    }
+
+    /// Error code `line` is rejected with
+    fn rejection(line: &str) -> ErrorCode {
+        line.parse::<ControlMessage>().unwrap_err().code
+    }
+
+    #[test]
+    fn every_message_parses_back_from_its_display() {
+        for msg in every_message() {
+            let line = msg.to_string();
+            assert_eq!(line.parse::<ControlMessage>(), Ok(msg), "{line}");
+        }
+    }
+
+    #[test]
+    fn every_message_decodes_from_its_encoding() {
+        let mut codec = ControlCodec::new();
+        for msg in every_message() {
+            codec.extend(&ControlCodec::encode(&msg).unwrap());
+        }
+        for msg in every_message() {
+            assert_eq!(codec.decode_next().unwrap(), Some(msg));
+        }
+        assert_eq!(codec.decode_next().unwrap(), None);
+    }
+
+    #[test]
+    fn bad_versions_are_rejected() {
+        for line in [
+            "HELLO",
+            "HELLO four",
+            "HELLO -1",
+            "HELLO 65536",
+            "WELCOME 1.0",
+        ] {
+            assert_eq!(rejection(line), ErrorCode::Malformed, "{line}");
+        }
+
+        // well-formed, but not the version spoken here
+        for version in [0, PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let line = ControlMessage::Hello {
+                version,
+                codecs: vec![],
+            }
+            .to_string();
+            let Ok(ControlMessage::Hello { version, .. }) = line.parse() else {
+                panic!("{line} did not parse");
+            };
+            let err = check_version(version).unwrap_err();
+            assert_eq!(err.code, ErrorCode::UnsupportedVersion, "{line}");
+        }
+    }
+
+    #[test]
+    fn extra_arguments_are_rejected() {
+        for line in [
+            "HELLO 4 lz4 rle",
+            "WELCOME 4 4",
+            "JOIN standup ada lovelace",
+            "JOINED standup 3 00000000deadbeef x",
+            "REGISTERED now",
+            "LEAVE standup",
+            "LEFT standup",
+            "PEER_JOINED 4 grace hopper",
+            "PEER_LEFT 4 4",
+            "CODEC lz4 rle",
+            "REQUEST_KEYFRAME 5 5",
+            "VIEWPORT 6 80 24 1",
+        ] {
+            assert_eq!(rejection(line), ErrorCode::Malformed, "{line}");
+        }
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        assert_eq!(rejection("join standup"), ErrorCode::UnknownCommand);
+        assert_eq!(rejection("PING"), ErrorCode::UnknownCommand);
+    }
+
+    #[test]
+    fn overlong_line_is_rejected() {
+        let mut codec = ControlCodec::new();
+        let mut line = format!("JOIN {}", "x".repeat(MAX_MESSAGE_LEN));
+        line.push('\n');
+        codec.extend(line.as_bytes());
+
+        let err = codec.decode_next().unwrap_err();
+        assert_eq!(err.code, ErrorCode::MessageTooLong);
+        assert_eq!(codec.decode_next().unwrap(), None);
+    }
+
+    #[test]
+    fn overlong_line_is_skipped_up_to_its_newline() {
+        let mut codec = ControlCodec::new();
+
+        codec.extend(&[b'x'; MAX_MESSAGE_LEN + 10]);
+        let err = codec.decode_next().unwrap_err();
+        assert_eq!(err.code, ErrorCode::MessageTooLong);
+
+        // the tail of the overlong line must not be decoded as a message
+        codec.extend(b"xxx LEAVE");
+        assert_eq!(codec.decode_next().unwrap(), None);
+        codec.extend(b"\nJOIN standup\n");
+        assert_eq!(
+            codec.decode_next().unwrap(),
+            Some(ControlMessage::Join {
+                session_id: "standup".to_string(),
+                name: None,
+            })
+        );
+        assert_eq!(codec.decode_next().unwrap(), None);
+    }
+}
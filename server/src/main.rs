@@ -30,6 +30,10 @@ struct Args {
     /// Enable verbose output
     #[arg(short, long, action = ArgAction::SetTrue)]
     verbose: bool,
+
+    /// Maximum amount of participants in a single session
+    #[arg(short, long, default_value_t = 8, value_parser = clap::value_parser!(u16).range(2..))]
+    max_participants: u16,
 }
 
 /// Entry point for ASCII video SFU server (codename "Pinhole")
@@ -37,13 +41,20 @@ struct Args {
 /// Launches TCP and UDP listeners, where
 /// - TCP is used for control messages, managing session state and other logic
 /// (e.g. JOIN, LEAVE, etc.)
-/// - UDP is used for forwarding ASCII frames between peers
+/// - UDP is used for forwarding ASCII frames from each peer to every other
+/// peer in its session
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // Parse command line arguments
     let args = Args::parse();
 
-    let server = SFU::new(args.tcp_addr, args.udp_addr, args.log_file, args.verbose);
+    let server = SFU::new(
+        args.tcp_addr,
+        args.udp_addr,
+        args.log_file,
+        args.verbose,
+        args.max_participants as usize,
+    );
 
     server.run().await?;
 
@@ -1,133 +1,142 @@
+use common::compression::Codec;
+use common::protocol::{ControlMessage, ParticipantId, RegistrationToken};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use tokio::sync::{RwLock, mpsc};
 
-pub enum Message {
-    Connect(String),
-    Disconnect,
+/// A single client taking part in a session
+pub struct Participant {
+    /// identifies the participant to the other members of its session
+    pub id: ParticipantId,
+    /// display name shown to the other members of its session
+    pub name: String,
+    /// frame codecs the participant can decode, empty if it predates
+    /// compression
+    pub codecs: Vec<Codec>,
+    /// address of the participant's control connection
+    pub tcp: SocketAddr,
+    /// channel to the participant's control connection handler
+    pub tx: mpsc::UnboundedSender<ControlMessage>,
+    /// address frames are sent from / forwarded to, once registered
+    pub udp: Option<SocketAddr>,
 }
 
-/// session between two peer clients, created by the SFU
+/// session between up to `capacity` peer clients, created by the SFU
 pub struct Session {
     pub id: String,
-    pub client_a: Option<(SocketAddr, mpsc::UnboundedSender<Message>)>,
-    pub client_b: Option<(SocketAddr, mpsc::UnboundedSender<Message>)>,
-    pub udp_a: Option<SocketAddr>,
-    pub udp_b: Option<SocketAddr>,
-    pub connected_notified: bool,
+    /// maximum amount of participants
+    pub capacity: usize,
+    /// current members, in order of joining
+    pub participants: Vec<Participant>,
+    /// compression every member can decode, used for all frames
+    pub codec: Codec,
+    /// ID handed to the next participant that joins
+    next_participant_id: ParticipantId,
 }
 
 impl Session {
-    pub fn new(id: String) -> Self {
+    pub fn new(id: String, capacity: usize) -> Self {
         Self {
             id,
-            client_a: None,
-            client_b: None,
-            udp_a: None,
-            udp_b: None,
-            connected_notified: false,
+            capacity,
+            participants: Vec::with_capacity(capacity),
+            codec: Codec::None,
+            next_participant_id: 1,
         }
     }
 
-    /// Adds client to first available slot
-    pub fn add_client(&mut self, addr: SocketAddr, tx: mpsc::UnboundedSender<Message>) -> bool {
-        match (&self.client_a, &self.client_b) {
-            // client A is not occupied
-            (None, _) => {
-                self.client_a = Some((addr, tx));
-                true
-            }
-            // client b is not occupied
-            (_, None) => {
-                self.client_b = Some((addr, tx));
-                true
-            }
-            // no available slots
-            _ => false,
+    /// Adds client if the session is not full, returning its participant ID.
+    /// Clients that did not pick a name are named after their ID.
+    pub fn add_client(
+        &mut self,
+        addr: SocketAddr,
+        name: Option<String>,
+        codecs: Vec<Codec>,
+        tx: mpsc::UnboundedSender<ControlMessage>,
+    ) -> Option<ParticipantId> {
+        if self.participants.len() >= self.capacity {
+            return None;
         }
+
+        let id = self.next_participant_id;
+        self.next_participant_id += 1;
+        self.participants.push(Participant {
+            id,
+            name: name.unwrap_or_else(|| format!("participant-{}", id)),
+            codecs,
+            tcp: addr,
+            tx,
+            udp: None,
+        });
+
+        Some(id)
     }
 
-    /// Returns peer's message channel for given client
-    pub fn get_peer_tx(&self, addr: &SocketAddr) -> Option<mpsc::UnboundedSender<Message>> {
-        match (&self.client_a, &self.client_b) {
-            (Some((a, _)), Some((_, tx))) if a == addr => Some(tx.clone()),
-            (Some((_, tx)), Some((b, _))) if b == addr => Some(tx.clone()),
-            _ => None,
-        }
+    /// Returns the participant with the given TCP address
+    pub fn participant(&self, addr: &SocketAddr) -> Option<&Participant> {
+        self.participants.iter().find(|p| p.tcp == *addr)
     }
 
-    /// Associates client's TCP address w/ its UDP address
-    pub fn register_udp(&mut self, tcp_addr: SocketAddr, udp_port: SocketAddr) {
-        if self
-            .client_a
-            .as_ref()
-            .map(|(a, _)| *a == tcp_addr)
-            .unwrap_or(false)
-        {
-            self.udp_a = Some(udp_port)
-        } else if self
-            .client_b
-            .as_ref()
-            .map(|(b, _)| *b == tcp_addr)
-            .unwrap_or(false)
-        {
-            self.udp_b = Some(udp_port)
-        }
+    /// Returns the participants other than the given client
+    pub fn peers<'a>(&'a self, addr: &'a SocketAddr) -> impl Iterator<Item = &'a Participant> {
+        self.participants.iter().filter(move |p| p.tcp != *addr)
     }
 
-    pub fn get_peer_udp(&self, tcp_addr: &SocketAddr) -> Option<SocketAddr> {
-        if self
-            .client_a
-            .as_ref()
-            .map(|(a, _)| a == tcp_addr)
-            .unwrap_or(false)
-        {
-            return self.udp_b;
-        } else if self
-            .client_b
-            .as_ref()
-            .map(|(b, _)| b == tcp_addr)
-            .unwrap_or(false)
-        {
-            return self.udp_a;
+    /// Associates client's TCP address w/ its UDP address.
+    /// Returns `true` if the client had no UDP address before.
+    pub fn register_udp(&mut self, tcp_addr: SocketAddr, udp_port: SocketAddr) -> bool {
+        match self.participants.iter_mut().find(|p| p.tcp == tcp_addr) {
+            Some(p) => p.udp.replace(udp_port).is_none(),
+            None => false,
         }
-        None
     }
 
-    pub fn remove_client(&mut self, addr: &SocketAddr) {
-        if self
-            .client_a
-            .as_ref()
-            .map(|(a, _)| a == addr)
-            .unwrap_or(false)
-        {
-            self.client_a = None;
-            self.udp_a = None;
-            self.connected_notified = false;
-        } else if self
-            .client_b
-            .as_ref()
-            .map(|(b, _)| b == addr)
-            .unwrap_or(false)
-        {
-            self.client_b = None;
-            self.udp_b = None;
-            self.connected_notified = false;
-        }
+    /// Returns the UDP addresses of every other registered participant
+    pub fn get_peer_udps(&self, tcp_addr: &SocketAddr) -> Vec<SocketAddr> {
+        self.peers(tcp_addr).filter_map(|p| p.udp).collect()
     }
 
-    pub fn has_open_slot(&self) -> bool {
-        self.client_a.is_none() || self.client_b.is_none()
+    pub fn remove_client(&mut self, addr: &SocketAddr) -> Option<Participant> {
+        let i = self.participants.iter().position(|p| p.tcp == *addr)?;
+        Some(self.participants.remove(i))
     }
 
     pub fn is_empty(&self) -> bool {
-        self.client_a.is_none() && self.client_b.is_none()
+        self.participants.is_empty()
+    }
+
+    /// Picks the most preferred codec all current members can decode.
+    /// Returns `true` if it differs from the codec used so far
+    pub fn negotiate_codec(&mut self) -> bool {
+        let supported: Vec<&[Codec]> = self
+            .participants
+            .iter()
+            .map(|p| p.codecs.as_slice())
+            .collect();
+        let codec = Codec::negotiate(&supported);
+
+        let changed = codec != self.codec;
+        self.codec = codec;
+        changed
+    }
+
+    /// `CODEC` messages announcing the session's codec to the given members,
+    /// skipping those that would not understand them
+    fn announce_codec<'a>(
+        &self,
+        to: impl Iterator<Item = &'a Participant>,
+    ) -> Vec<(mpsc::UnboundedSender<ControlMessage>, ControlMessage)> {
+        to.filter(|p| !p.codecs.is_empty())
+            .map(|p| (p.tx.clone(), ControlMessage::Codec { codec: self.codec }))
+            .collect()
     }
 }
 
 /// Holds all active session & maps clients to their session IDs.
 /// Also tracks UDP-to-TCP associations for UDP forwarding.
 pub struct SessionManager {
+    /// participant cap applied to newly created sessions
+    max_participants: usize,
     inner: RwLock<Inner>,
 }
 
@@ -138,15 +147,38 @@ struct Inner {
     /// reverse map of client addresses -> session ID
     pub client_sessions: HashMap<SocketAddr, String>,
     pub udp_to_tcp: HashMap<SocketAddr, SocketAddr>,
+    /// registration tokens handed out on JOIN -> TCP address of their client
+    pub tokens: HashMap<RegistrationToken, SocketAddr>,
+}
+
+impl Inner {
+    /// Generate a random registration token that is not already in use
+    fn new_token(&self) -> RegistrationToken {
+        loop {
+            let token = RegistrationToken(rand::random());
+            if !self.tokens.contains_key(&token) {
+                return token;
+            }
+        }
+    }
+
+    /// Returns the session the given client is part of
+    fn session_of(&self, tcp: &SocketAddr) -> Option<&Session> {
+        self.client_sessions
+            .get(tcp)
+            .and_then(|id| self.sessions.get(id))
+    }
 }
 
 impl SessionManager {
-    pub fn new() -> Self {
+    pub fn new(max_participants: usize) -> Self {
         Self {
+            max_participants,
             inner: RwLock::new(Inner {
                 sessions: HashMap::new(),
                 client_sessions: HashMap::new(),
                 udp_to_tcp: HashMap::new(),
+                tokens: HashMap::new(),
             }),
         }
     }
@@ -159,202 +191,244 @@ impl SessionManager {
         inner
             .sessions
             .entry(id.to_owned())
-            .or_insert_with(|| Session::new(id.to_owned()));
+            .or_insert_with(|| Session::new(id.to_owned(), self.max_participants));
     }
 
+    /// Adds client to the session, returning its participant ID and the
+    /// token it must use to register its UDP address
+    /// (`None` if the session is full).
+    /// The client is told which codec the session uses, and so is everyone
+    /// else if the client's arrival changes it.
     pub async fn add_client(
         &self,
         session_id: &str,
         tcp_addr: SocketAddr,
-        tx: mpsc::UnboundedSender<Message>,
-    ) -> bool {
-        let mut inner = self.inner.write().await;
+        name: Option<String>,
+        codecs: Vec<Codec>,
+        tx: mpsc::UnboundedSender<ControlMessage>,
+    ) -> Option<(ParticipantId, RegistrationToken)> {
+        let (participant_id, token, announcements) = {
+            let mut inner = self.inner.write().await;
+            let token = inner.new_token();
+
+            let session = inner.sessions.get_mut(session_id)?;
+            let participant_id = session.add_client(tcp_addr, name, codecs, tx)?;
+
+            let announcements = if session.negotiate_codec() {
+                println!(
+                    "[CONTROL] session {} now uses codec {}",
+                    session.id, session.codec
+                );
+                session.announce_codec(session.participants.iter())
+            } else {
+                session.announce_codec(session.participant(&tcp_addr).into_iter())
+            };
 
-        if let Some(s) = inner.sessions.get_mut(session_id) {
-            if s.add_client(tcp_addr, tx) {
-                inner
-                    .client_sessions
-                    .insert(tcp_addr, session_id.to_owned());
-                return true;
-            }
+            inner
+                .client_sessions
+                .insert(tcp_addr, session_id.to_owned());
+            inner.tokens.insert(token, tcp_addr);
+
+            (participant_id, token, announcements)
+        };
+
+        for (tx, msg) in announcements {
+            let _ = tx.send(msg); // no lock held here
         }
 
-        false
+        Some((participant_id, token))
     }
 
-    pub async fn register_udp(&self, tcp: SocketAddr, udp: SocketAddr) {
+    /// Binds the UDP source of a `PING` to the client that was handed
+    /// `token`, returning that client's TCP address and whether this is
+    /// its first registration.
+    /// Unknown tokens are rejected with `None`.
+    pub async fn register_udp(
+        &self,
+        token: RegistrationToken,
+        udp_src: SocketAddr,
+    ) -> Option<(SocketAddr, bool)> {
         let mut inner = self.inner.write().await;
 
-        if let Some(id) = inner.client_sessions.get(&tcp).cloned() {
-            if let Some(s) = inner.sessions.get_mut(&id) {
-                s.register_udp(tcp, udp);
-                inner.udp_to_tcp.insert(udp, tcp);
-            }
-        }
+        let tcp = *inner.tokens.get(&token)?;
+        let id = inner.client_sessions.get(&tcp)?.clone();
+
+        // client may re-register from a new port (e.g. NAT rebinding),
+        // only its latest UDP address stays mapped
+        inner
+            .udp_to_tcp
+            .retain(|udp, mapped_tcp| *mapped_tcp != tcp || *udp == udp_src);
+        inner.udp_to_tcp.insert(udp_src, tcp);
+        let first = inner.sessions.get_mut(&id)?.register_udp(tcp, udp_src);
+
+        Some((tcp, first))
     }
 
-    pub async fn get_peer_udp(&self, udp_src: &SocketAddr) -> Option<SocketAddr> {
+    /// Returns the participant ID of the client that sent from `udp_src`,
+    /// along with the UDP addresses its frames should be forwarded to
+    pub async fn get_peer_udps(
+        &self,
+        udp_src: &SocketAddr,
+    ) -> Option<(ParticipantId, Vec<SocketAddr>)> {
         let inner = self.inner.read().await;
-        let tcp = inner.udp_to_tcp.get(&udp_src)?;
-        let id = inner.client_sessions.get(tcp)?;
+        let tcp = inner.udp_to_tcp.get(udp_src)?;
+        let session = inner.session_of(tcp)?;
 
-        inner.sessions.get(id)?.get_peer_udp(tcp)
+        Some((session.participant(tcp)?.id, session.get_peer_udps(tcp)))
+    }
+
+    /// Returns the participant ID of the given client
+    pub async fn participant_id_for(&self, tcp: &SocketAddr) -> Option<ParticipantId> {
+        let inner = self.inner.read().await;
+        Some(inner.session_of(tcp)?.participant(tcp)?.id)
     }
 
-    pub async fn notify_peer(&self, tcp: &SocketAddr, msg: Message) {
-        let peer_tx = {
+    /// Sends a control message to the given client
+    pub async fn notify_client(&self, tcp: &SocketAddr, msg: ControlMessage) {
+        let tx = {
             let inner = self.inner.read().await;
             inner
-                .client_sessions
-                .get(tcp)
-                .and_then(|id| inner.sessions.get(id))
-                .and_then(|s| s.get_peer_tx(tcp))
+                .session_of(tcp)
+                .and_then(|s| s.participant(tcp))
+                .map(|p| p.tx.clone())
         };
 
-        if let Some(tx) = peer_tx {
+        if let Some(tx) = tx {
             let _ = tx.send(msg); // no lock held here
         }
     }
 
-    pub async fn remove_client(&self, tcp: &SocketAddr) {
-        let mut inner = self.inner.write().await;
-        
-        let session_id = match inner.client_sessions.remove(tcp) {
-            Some(id) => id,
-            None => return,
-        };
-        
-        let session = match inner.sessions.get_mut(&session_id) {
-            Some(session) => session,
-            None => return,
-        };
-        
-        let is_empty_after_remove = {
-            session.remove_client(tcp);
-            session.connected_notified = false;
-            session.is_empty()
+    /// Sends a control message to the participant with the given ID in the
+    /// given client's session. Returns `false` if there is no such participant
+    pub async fn notify_participant(
+        &self,
+        tcp: &SocketAddr,
+        participant_id: ParticipantId,
+        msg: ControlMessage,
+    ) -> bool {
+        let tx = {
+            let inner = self.inner.read().await;
+            inner
+                .session_of(tcp)
+                .and_then(|s| s.participants.iter().find(|p| p.id == participant_id))
+                .map(|p| p.tx.clone())
         };
-        
-        inner.udp_to_tcp.retain(|_, mapped_tcp| {
-            let keep = mapped_tcp != tcp;
-            if !keep {
-                println!("[CONTROL] unregistered UDP mapping for TCP {}", tcp);
-            }
-            keep
-        });
-        
-        if is_empty_after_remove {
-            inner.sessions.remove(&session_id);
-            println!("[CONTROL] removed empty session {}", session_id);
+
+        match tx {
+            Some(tx) => tx.send(msg).is_ok(), // no lock held here
+            None => false,
         }
     }
 
-    /// Return peer's UDP address given your own TCP address
-    /// (both clients are present & peer already registered there UDP port)
-    pub async fn get_peer_udp_from_tcp(&self, tcp: &SocketAddr) -> Option<SocketAddr> {
-        let inner = self.inner.read().await;
-        let id = inner.client_sessions.get(tcp)?;
-        let room = inner.sessions.get(id)?;
-        room.get_peer_udp(tcp)
+    /// Sends a control message to every other participant in the
+    /// given client's session
+    pub async fn notify_peers(&self, tcp: &SocketAddr, msg: ControlMessage) {
+        let peer_txs: Vec<_> = {
+            let inner = self.inner.read().await;
+            inner
+                .session_of(tcp)
+                .map(|s| s.peers(tcp).map(|p| p.tx.clone()).collect())
+                .unwrap_or_default()
+        };
+
+        for tx in peer_txs {
+            let _ = tx.send(msg.clone()); // no lock held here
+        }
     }
 
-    pub async fn session_full(&self, id: &str) -> bool {
-        let inner = self.inner.read().await;
-        inner
-            .sessions
-            .get(id)
-            .map(|s| !s.has_open_slot())
-            .unwrap_or(false)
+    /// Introduces a freshly registered client and the already registered
+    /// members of its session to each other with `PEER_JOINED`
+    pub async fn announce_participant(&self, tcp: &SocketAddr) {
+        let (own, peers): (_, Vec<_>) = {
+            let inner = self.inner.read().await;
+            let Some(session) = inner.session_of(tcp) else {
+                return;
+            };
+            let Some(own) = session.participant(tcp) else {
+                return;
+            };
+
+            (
+                Self::introduce(own),
+                session
+                    .peers(tcp)
+                    .filter(|p| p.udp.is_some())
+                    .map(Self::introduce)
+                    .collect(),
+            )
+        };
+
+        let (own_msg, own_tx) = own;
+        for (peer_msg, peer_tx) in peers {
+            let _ = peer_tx.send(own_msg.clone());
+            let _ = own_tx.send(peer_msg);
+        }
     }
 
-    pub async fn session_id_for(&self, tcp: &SocketAddr) -> Option<String> {
-        let inner = self.inner.read().await;
-        inner.client_sessions.get(tcp).cloned()
+    /// `PEER_JOINED` message introducing a participant, along with the
+    /// participant's own message channel
+    fn introduce(p: &Participant) -> (ControlMessage, mpsc::UnboundedSender<ControlMessage>) {
+        (
+            ControlMessage::PeerJoined {
+                participant_id: p.id,
+                name: p.name.clone(),
+            },
+            p.tx.clone(),
+        )
     }
 
-    /// Register a TCP connection's real UDP (i.e. public IP & UDP port)
-    /// to its public TCP mapping
-    pub async fn map_udp_to_tcp(&self, udp_src: SocketAddr) {
-        let mut inner = self.inner.write().await;
+    /// Removes client from its session, removing the session once empty.
+    /// The remaining members are told if the session's codec changes
+    pub async fn remove_client(&self, tcp: &SocketAddr) {
+        let announcements = {
+            let mut inner = self.inner.write().await;
 
-        // is UDP source mapped? check if its valid
-        if let Some(tcp_addr) = inner.udp_to_tcp.get(&udp_src).copied() {
-            if inner.client_sessions.contains_key(&tcp_addr) {
-                // connection still alive, keep the mapping
-                return;
+            let session_id = match inner.client_sessions.remove(tcp) {
+                Some(id) => id,
+                None => return,
+            };
+
+            let session = match inner.sessions.get_mut(&session_id) {
+                Some(session) => session,
+                None => return,
+            };
+
+            session.remove_client(tcp);
+            let is_empty_after_remove = session.is_empty();
+
+            let announcements = if !is_empty_after_remove && session.negotiate_codec() {
+                println!(
+                    "[CONTROL] session {} now uses codec {}",
+                    session.id, session.codec
+                );
+                session.announce_codec(session.participants.iter())
             } else {
-                // connection is gone, remove mapping
-                inner.udp_to_tcp.remove(&udp_src);
+                Vec::new()
+            };
+
+            inner.udp_to_tcp.retain(|_, mapped_tcp| {
+                let keep = mapped_tcp != tcp;
+                if !keep {
+                    println!("[CONTROL] unregistered UDP mapping for TCP {}", tcp);
+                }
+                keep
+            });
+            inner.tokens.retain(|_, token_tcp| token_tcp != tcp);
+
+            if is_empty_after_remove && let Some(session) = inner.sessions.remove(&session_id) {
+                println!("[CONTROL] removed empty session {}", session.id);
             }
-        }
 
-        // Oh, God!
-        let candidate = inner
-            .client_sessions
-            .keys()
-            .filter(|tcp| tcp.ip() == udp_src.ip())
-            .find(|tcp| {
-                inner
-                    .sessions
-                    .get(inner.client_sessions.get(tcp).unwrap())
-                    .map(|session| {
-                        let unregistered_a = session
-                            .client_a
-                            .as_ref()
-                            .filter(|(a, _)| a == *tcp)
-                            .map(|_| session.udp_a.is_none())
-                            .unwrap_or(false);
-                        let unregistered_b = session
-                            .client_b
-                            .as_ref()
-                            .filter(|(b, _)| b == *tcp)
-                            .map(|_| session.udp_b.is_none())
-                            .unwrap_or(false);
-
-                        unregistered_a || unregistered_b
-                    })
-                    .unwrap_or(false)
-            })
-            .copied();
-
-        if let Some(tcp_addr) = candidate {
-            let s_id = inner.client_sessions.get(&tcp_addr).unwrap().clone();
-            inner
-                .sessions
-                .get_mut(&s_id)
-                .unwrap()
-                .register_udp(tcp_addr, udp_src);
-            inner.udp_to_tcp.insert(udp_src, tcp_addr);
-            println!(
-                "[FORWARD] registered REAL UDP src {} to TCP {}",
-                udp_src, tcp_addr
-            );
-        } else {
-            eprintln!(
-                "[FORWARD] UDP {} could not be matched to any client",
-                udp_src
-            );
-        }
-    }
-
-    pub async fn tcp_for_udp(&self, udp_src: &SocketAddr) -> Option<SocketAddr> {
-        let inner = self.inner.read().await;
-        inner.udp_to_tcp.get(udp_src).copied()
-    }
+            announcements
+        };
 
-    pub async fn mark_connected(&self, id: &str) {
-        let mut inner = self.inner.write().await;
-        if let Some(s) = inner.sessions.get_mut(id) {
-            s.connected_notified = true;
+        for (tx, msg) in announcements {
+            let _ = tx.send(msg); // no lock held here
         }
     }
 
-    pub async fn is_connected(&self, id: &str) -> bool {
+    pub async fn session_id_for(&self, tcp: &SocketAddr) -> Option<String> {
         let inner = self.inner.read().await;
-        inner
-            .sessions
-            .get(id)
-            .map(|s| s.connected_notified)
-            .unwrap_or(false)
+        inner.client_sessions.get(tcp).cloned()
     }
 }
@@ -2,15 +2,22 @@ use std::error::Error;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::{TcpStream, UdpSocket};
 use tokio::sync::mpsc;
 use tokio::{select, task};
 
-use crate::sessions::{Message, SessionManager};
+use crate::sessions::SessionManager;
+use common::fragment::MAX_FRAGMENT_LEN;
 use common::logger::Logger;
+use common::protocol::{
+    ControlCodec, ControlMessage, ErrorCode, PROTOCOL_VERSION, ParticipantId, ProtocolError,
+    RELAY_HEADER_LEN, RegistrationToken, check_version, encode_relay_header,
+};
 
 /// Server acting as a Selective Forwarding Unit for connected clients,
 /// responsible for session control (TCP) and frame forwarding (UDP)
+#[allow(clippy::upper_case_acronyms)]
 pub struct SFU {
     /// Address for sending control messages to clients
     tcp_addr: String,
@@ -20,18 +27,27 @@ pub struct SFU {
     log_file: String,
     /// Option to have a finer level of detail in the log file
     verbose: bool,
+    /// Maximum amount of participants in a single session
+    max_participants: usize,
     /// Thread-safe session manager for client/session tracking
     sessions: Arc<SessionManager>,
 }
 
 impl SFU {
-    pub fn new(tcp_addr: String, udp_addr: String, log_file: String, verbose: bool) -> Self {
+    pub fn new(
+        tcp_addr: String,
+        udp_addr: String,
+        log_file: String,
+        verbose: bool,
+        max_participants: usize,
+    ) -> Self {
         Self {
             tcp_addr,
             udp_addr,
             log_file,
             verbose,
-            sessions: Arc::new(SessionManager::new()),
+            max_participants,
+            sessions: Arc::new(SessionManager::new(max_participants)),
         }
     }
 
@@ -48,6 +64,7 @@ impl SFU {
             println!("\tTCP control address: {}", self.tcp_addr);
             println!("\tUDP data address: {}", self.udp_addr);
             println!("\tLog file: {}", self.log_file);
+            println!("\tMax participants per session: {}", self.max_participants);
         } else {
             println!("SFU server starting...");
         }
@@ -86,20 +103,18 @@ impl SFU {
     ) -> Result<(), Box<dyn Error>> {
         let (mut rd, mut wr) = socket.into_split();
 
-        let (peer_tx, mut peer_rx) = mpsc::unbounded_channel::<Message>();
+        let (peer_tx, mut peer_rx) = mpsc::unbounded_channel::<ControlMessage>();
 
+        let mut codec = ControlCodec::new();
+        let mut handshake_done = false;
+        // frame codecs the client listed in its HELLO
+        let mut client_codecs = Vec::new();
         let mut cmd_buf = vec![0u8; 1024];
-        loop {
+        'conn: loop {
             select! {
                 // session notifications
                 Some(msg) = peer_rx.recv() => {
-                    let line: &str = match msg {
-                        Message::Connect(_) => "CONNECTED\n",
-                        Message::Disconnect => "DISCONNECTED\n",
-                        _ => continue
-                    };
-                    println!("[CONTROL] Sending to {}: {}", addr, line.trim());
-                    wr.write_all(line.as_bytes()).await?;
+                    Self::send(&mut wr, addr, &msg).await?;
                 }
                 result = rd.read(&mut cmd_buf) => {
                     let n = result?;
@@ -107,30 +122,104 @@ impl SFU {
                         // client has closed connection
                         break;
                     }
-                    let line = std::str::from_utf8(&cmd_buf[..n])?.trim();
-                    let mut parts = line.split_whitespace();
-                    match parts.next() {
-                        Some("JOIN") => {
-                            if let Some(id) = parts.next() {
-                                sessions.ensure_session(id).await;
-                                if sessions.add_client(id.clone(), addr, peer_tx.clone()).await {
-                                    println!("[CONTROL] Sending to {}: OK: joined session", addr);
-                                    wr.write_all(b"OK: joined session\n").await?;
+                    codec.extend(&cmd_buf[..n]);
+
+                    loop {
+                        let msg = match codec.decode_next() {
+                            Ok(Some(msg)) => msg,
+                            Ok(None) => break,
+                            Err(e) => {
+                                let fatal = e.code == ErrorCode::MessageTooLong;
+                                Self::send(&mut wr, addr, &e.into()).await?;
+                                if fatal {
+                                    break 'conn;
+                                }
+                                continue;
+                            }
+                        };
+
+                        match msg {
+                            ControlMessage::Hello { version, codecs } => {
+                                if let Err(e) = check_version(version) {
+                                    Self::send(&mut wr, addr, &ControlMessage::from(e)).await?;
+                                    break 'conn;
+                                }
+                                handshake_done = true;
+                                client_codecs = codecs;
+                                let reply = ControlMessage::Welcome { version: PROTOCOL_VERSION };
+                                Self::send(&mut wr, addr, &reply).await?;
+                            }
+                            _ if !handshake_done => {
+                                let reply = ControlMessage::error(
+                                    ErrorCode::HandshakeRequired,
+                                    "send HELLO first",
+                                );
+                                Self::send(&mut wr, addr, &reply).await?;
+                            }
+                            ControlMessage::Join { session_id, name } => {
+                                let reply = if sessions.session_id_for(&addr).await.is_some() {
+                                    ControlMessage::error(
+                                        ErrorCode::AlreadyInSession,
+                                        "already in a session",
+                                    )
+                                } else {
+                                    sessions.ensure_session(&session_id).await;
+                                    match sessions.add_client(&session_id, addr, name, client_codecs.clone(), peer_tx.clone()).await {
+                                        Some((participant_id, token)) => ControlMessage::Joined {
+                                            session_id,
+                                            participant_id,
+                                            token,
+                                        },
+                                        None => ControlMessage::error(ErrorCode::SessionFull, "session full"),
+                                    }
+                                };
+                                Self::send(&mut wr, addr, &reply).await?;
+                            }
+                            ControlMessage::Leave => {
+                                let reply = if sessions.session_id_for(&addr).await.is_some() {
+                                    Self::leave_session(addr, &sessions).await;
+                                    ControlMessage::Left
                                 } else {
-                                    println!("[CONTROL] Sending to {}: ERROR: session full", addr);
-                                    wr.write_all(b"ERROR: session full\n").await?;
+                                    ControlMessage::error(ErrorCode::NotInSession, "not in a session")
+                                };
+                                Self::send(&mut wr, addr, &reply).await?;
+                            }
+                            ControlMessage::RequestKeyframe { participant_id } => {
+                                // the sender is told who asked for the keyframe
+                                let reply = Self::relay(addr, &sessions, participant_id, |requester| {
+                                    ControlMessage::RequestKeyframe {
+                                        participant_id: requester,
+                                    }
+                                })
+                                .await;
+                                if let Some(reply) = reply {
+                                    Self::send(&mut wr, addr, &reply).await?;
                                 }
                             }
-                        }
-                        Some("LEAVE") => {
-                            sessions.notify_peer(&addr, Message::Disconnect).await;
-                            sessions.remove_client(&addr).await;
-                            println!("[CONTROL] Sending to {}: OK: left session", addr);
-                            wr.write_all(b"OK: left session\n").await?;
-                        }
-                        _ => {
-                            // println!("Sending to {}: ERROR: unknown command", addr);
-                            // wr.write_all(b"ERROR: unknown command\n").await?;
+                            ControlMessage::Viewport { participant_id, width, height } => {
+                                // the sender is told who shows it at that size
+                                let reply = Self::relay(addr, &sessions, participant_id, |viewer| {
+                                    ControlMessage::Viewport {
+                                        participant_id: viewer,
+                                        width,
+                                        height,
+                                    }
+                                })
+                                .await;
+                                if let Some(reply) = reply {
+                                    Self::send(&mut wr, addr, &reply).await?;
+                                }
+                            }
+                            ControlMessage::Error { code, reason } => {
+                                eprintln!("[CONTROL] {} reported error {}: {}", addr, code, reason);
+                            }
+                            other => {
+                                let reply = ControlMessage::error(
+                                    ErrorCode::UnexpectedMessage,
+                                    format!("unexpected {}", other),
+                                );
+                                Self::send(&mut wr, addr, &reply).await?;
+                            }
                         }
                     }
                 }
@@ -138,17 +227,107 @@ impl SFU {
         }
 
         println!("[CONTROL] Client {} disconnected, cleaning up", addr);
-        sessions.notify_peer(&addr, Message::Disconnect).await;
+        Self::leave_session(addr, &sessions).await;
+        Ok(())
+    }
+
+    /// Pass a message from the client at `addr` on to the participant with
+    /// the given ID in its session. The message is built from the client's
+    /// own participant ID, so the recipient knows where it came from.
+    /// Returns the error to reply with if it could not be delivered
+    async fn relay(
+        addr: SocketAddr,
+        sessions: &SessionManager,
+        participant_id: ParticipantId,
+        message_from: impl FnOnce(ParticipantId) -> ControlMessage,
+    ) -> Option<ControlMessage> {
+        let Some(from) = sessions.participant_id_for(&addr).await else {
+            return Some(ControlMessage::error(
+                ErrorCode::NotInSession,
+                "not in a session",
+            ));
+        };
+
+        if sessions
+            .notify_participant(&addr, participant_id, message_from(from))
+            .await
+        {
+            None
+        } else {
+            Some(ControlMessage::error(
+                ErrorCode::UnknownParticipant,
+                format!("no participant {}", participant_id),
+            ))
+        }
+    }
+
+    /// Removes a client from its session, telling the remaining
+    /// participants which participant left
+    async fn leave_session(addr: SocketAddr, sessions: &SessionManager) {
+        if let Some(participant_id) = sessions.participant_id_for(&addr).await {
+            sessions
+                .notify_peers(&addr, ControlMessage::PeerLeft { participant_id })
+                .await;
+        }
         sessions.remove_client(&addr).await;
+    }
+
+    /// Encodes and writes a single control message to a client
+    async fn send(
+        wr: &mut OwnedWriteHalf,
+        addr: SocketAddr,
+        msg: &ControlMessage,
+    ) -> Result<(), Box<dyn Error>> {
+        println!("[CONTROL] Sending to {}: {}", addr, msg);
+        wr.write_all(&ControlCodec::encode(msg)?).await?;
         Ok(())
     }
 
-    /// Handles UDP registration and frame forwarding
+    /// Handles a `PING` datagram, binding its source to the client that owns
+    /// the token. The first time a client registers, it and the other
+    /// registered participants of its session are introduced to each other.
+    async fn register_udp(
+        token: Result<RegistrationToken, ProtocolError>,
+        src_udp: SocketAddr,
+        sessions: &SessionManager,
+    ) {
+        let token = match token {
+            Ok(token) => token,
+            Err(e) => {
+                eprintln!("[FORWARD] rejecting PING from UDP {src_udp}: {e}");
+                return;
+            }
+        };
+
+        let Some((src_tcp, first)) = sessions.register_udp(token, src_udp).await else {
+            eprintln!("[FORWARD] rejecting PING from UDP {src_udp}: unknown token");
+            return;
+        };
+        println!(
+            "[FORWARD] registered UDP src {} to TCP {}",
+            src_udp, src_tcp
+        );
+        sessions
+            .notify_client(&src_tcp, ControlMessage::Registered)
+            .await;
+
+        if first {
+            sessions.announce_participant(&src_tcp).await;
+        }
+    }
+
+    /// Handles UDP registration and frame forwarding.
+    ///
+    /// Every frame fragment is fanned out to all other registered participants of
+    /// the sender's session, prefixed with the sender's participant ID.
     pub async fn udp_loop(socket: UdpSocket, sessions: Arc<SessionManager>) {
-        let mut buf = vec![0u8; 65536];
+        // datagrams are received after the space reserved for the relay header,
+        // so they can be forwarded without copying.
+        // Clients fragment their frames, so no datagram is larger than a fragment
+        let mut buf = vec![0u8; RELAY_HEADER_LEN + MAX_FRAGMENT_LEN];
 
         loop {
-            let (n, src_udp) = match socket.recv_from(&mut buf).await {
+            let (n, src_udp) = match socket.recv_from(&mut buf[RELAY_HEADER_LEN..]).await {
                 Ok(v) => v,
                 Err(e) => {
                     eprintln!("[FORWARD] udp recv error: {e}");
@@ -157,33 +336,32 @@ impl SFU {
             };
             //println!("<< got {} bytes from UDP src: {}", n, src_udp);
 
-            sessions.map_udp_to_tcp(src_udp).await;
-            if let Some(dst_udp) = sessions.get_peer_udp(&src_udp).await {
-                if let (Some(src_tcp), Some(dst_tcp)) = (
-                    sessions.tcp_for_udp(&src_udp).await,
-                    sessions.tcp_for_udp(&dst_udp).await,
-                ) {
-                    if let Some(session_id) = sessions.session_id_for(&dst_tcp).await {
-                        if !sessions.is_connected(&session_id).await {
-                            sessions
-                                .notify_peer(&src_tcp, Message::Connect(session_id.clone()))
-                                .await;
-                            sessions
-                                .notify_peer(&dst_tcp, Message::Connect(session_id.clone()))
-                                .await;
-                            sessions.mark_connected(&session_id).await;
-                        }
-                    }
-                }
+            // registration: bind this UDP source to a control connection
+            let datagram = &buf[RELAY_HEADER_LEN..RELAY_HEADER_LEN + n];
+            if let Some(token) = RegistrationToken::from_ping(datagram) {
+                Self::register_udp(token, src_udp, &sessions).await;
+                continue;
+            }
+
+            // only frames from registered sources are forwarded
+            let Some((sender, dst_udps)) = sessions.get_peer_udps(&src_udp).await else {
+                eprintln!("[FORWARD] dropping datagram from unregistered UDP {src_udp}");
+                continue;
+            };
+
+            if dst_udps.is_empty() {
+                eprintln!("[FORWARD] no peer for UDP {src_udp}");
+                continue;
+            }
 
-                match socket.send_to(&buf[..n], &dst_udp).await {
+            buf[..RELAY_HEADER_LEN].copy_from_slice(&encode_relay_header(sender));
+            for dst_udp in dst_udps {
+                match socket.send_to(&buf[..RELAY_HEADER_LEN + n], &dst_udp).await {
                     Ok(_) => {
                         //println!("forwarded {sent} bytes {src_udp} -> {dst_udp}")
                     }
                     Err(e) => eprintln!("udp send error {dst_udp}: {e}"),
                 }
-            } else {
-                eprintln!("[FORWARD] no peer for UDP {src_udp}")
             }
         }
     }